@@ -42,7 +42,10 @@ const img = document.getElementById('file-preview-image');
 const modalCloseButton = document.getElementById('modal-close-button');
 modalCloseButton.addEventListener('click', onPreviewClose);
 
-d3.json('/app/api/inventory').then((data) => {
+// The version being browsed, picked from the list of versions
+const archiveVersion = document.getElementById('graph').dataset.version;
+
+d3.json(`/app/api/inventory?version=${archiveVersion}`).then((data) => {
 
     console.log(data);
 
@@ -242,10 +245,10 @@ function openPreview(data){
   window.filePreviewModal.show();
 
   title.innerHTML = data.name;
-  body.innerHTML = data.file_path;
+  body.innerHTML = `${data.file_path}<br>Rarity: ${data.rarity}`;
 
   let imagePathEncoded = btoa(data.file_path);
-  let url = `${window.location.origin}/app/api/image?path=${imagePathEncoded}`;
+  let url = `${window.location.origin}/app/api/image?path=${imagePathEncoded}&version=${archiveVersion}`;
 
   let options = {
     method: 'GET',
@@ -25,7 +25,12 @@ function onGeneratePreview(){
     method: 'POST',
   }
 
+  // An older version of the archive can be used with '?version=N'
+  let version = new URLSearchParams(window.location.search).get("version");
   let url = `${window.location.origin}/app/api/random`;
+  if (version) {
+    url += `?version=${version}`;
+  }
 
   // Ask to generate a random image
   fetch(url, options)
@@ -77,9 +82,10 @@ function getJobInfo(url){
       console.log("Job result", data);
 
       if (data.status == "FAILED"){
-        console.error("Job has failed.");
+        console.error("Job has failed:", data.error);
         clearInterval(getJobInfoIntervalID);
-        progressDiv.style.visibility = "hidden";
+        progressText.innerText = `Errore: ${data.error}`;
+        generateButton.style.visibility = "visible"
       }
       else if (data.status == "STARTED"){
 
@@ -10,7 +10,18 @@ const finishedSuccessDiv = document.getElementById("upload-finished-success");
 const finishedErrorDiv = document.getElementById("upload-finished-error");
 const uploadErrorMessage = document.getElementById("upload-error-message");
 
-const EXPECTED_MIME_TYPE = 'application/x-gzip';
+// Enough to detect the format of the archive
+const ARCHIVE_HEADER_LEN = 262;
+const INGEST_POLLING_INTERVAL_MS = 1000;
+const UPLOADS_ENDPOINT = "/app/api/uploads";
+const CHUNK_MAX_ATTEMPTS = 5;
+const CHUNK_RETRY_DELAY_MS = 2000;
+const INGEST_STAGES_INFO = {
+  SAVING: "Saving the archive..",
+  EXTRACTING: "Extracting the archive..",
+  SANITIZING: "Sanitizing the names of the files..",
+  VALIDATING: "Validating the structure of the archive..",
+};
 
 // Entry points
 // -----------------------------------------------------------------------------
@@ -72,31 +83,33 @@ function onBeforeUnload(event){
   return message;
 }
 
-function sanityCheck(file){
-  
-  if (!file.name.endsWith(".tar.gz")){
-    let errorMessage = `Il file scelto (${file.name}) non finisce con .tar.gz. Sei sicuro sia un archivio TAR GZ?`; 
-    console.error(file);
-    console.error(errorMessage);
-    progressDiv.style.visibility = "hidden";
-    finishedSuccessDiv.style.visibility = "collapse";
-    finishedErrorDiv.style.visibility = "visible";
-    uploadErrorMessage.innerHTML = errorMessage;
-    return false;
-  }
-
-  if (file.type != (EXPECTED_MIME_TYPE)){
-    let errorMessage = `Il MIME-type del file che hai scelto (${file.name}) non è '${EXPECTED_MIME_TYPE}'. Sei sicuro che sia un archivio TAR GZIP?`; 
-    console.error(file);
-    console.error(errorMessage);  
-    progressDiv.style.visibility = "hidden";
-    finishedSuccessDiv.style.visibility = "collapse";
-    finishedErrorDiv.style.visibility = "visible";
-    uploadErrorMessage.innerHTML = errorMessage;
-    return false;
-  }
+// Same magic bytes checked by the server (see archive_format.rs), whatever the name of the file
+function detectArchiveFormat(header){
+  const startsWith = (magic, offset) => magic.every((byte, i) => header[offset + i] == byte);
+  if (startsWith([0x1f, 0x8b], 0)) return "tar.gz";
+  if (startsWith([0x28, 0xb5, 0x2f, 0xfd], 0)) return "tar.zst";
+  if (startsWith([0x50, 0x4b, 0x03, 0x04], 0)) return "zip";
+  if (startsWith([0x75, 0x73, 0x74, 0x61, 0x72], 257)) return "tar";
+  return null;
+}
 
-  return true;
+function sanityCheck(file){
+  return file.slice(0, ARCHIVE_HEADER_LEN).arrayBuffer().then(buffer => {
+    const format = detectArchiveFormat(new Uint8Array(buffer));
+    if (format == null){
+      let errorMessage = `Il file scelto (${file.name}) non sembra un archivio .zip, .tar.gz, .tar.zst o .tar.`; 
+      console.error(file);
+      console.error(errorMessage);
+      progressDiv.style.visibility = "hidden";
+      finishedSuccessDiv.style.visibility = "collapse";
+      finishedErrorDiv.style.visibility = "visible";
+      uploadErrorMessage.innerHTML = errorMessage;
+      window.removeEventListener('beforeunload', onBeforeUnload);
+      return false;
+    }
+    console.log("Archive format:", format);
+    return true;
+  });
 }
 
 function uploadFiles(event){
@@ -112,65 +125,164 @@ function uploadFiles(event){
   // Calculate total size
   const file = inputElement.files[0];
 
-  // If the archive doesn't look like it's an actual tar.gz archive, tell him!
-  if (!sanityCheck(file)){
-    return;
-  }
-
   let totalBytes = file.size;
   
   if (totalBytes <= 0) {
     console.log("Nothing to upload.")
     return;
   }
-  progressDiv.style.visibility = "visible";
 
   console.log("File:", file);
   console.log("totalBytes:", totalBytes);
 
-  let formData = new FormData();
-  formData.append('content-type', file.type);
-  formData.append(file.name, file);
+  // If the file doesn't look like it's an actual archive, tell him!
+  sanityCheck(file)
+    .then(isArchive => {
+      if (!isArchive){
+        return;
+      }
+      progressDiv.style.visibility = "visible";
+      return uploadArchive(file);
+    });
+}
+
+function uploadArchive(file){
+  return startUpload(file)
+    .then(upload => uploadChunks(file, upload))
+    .then(upload => finalizeUpload(file, upload))
+    .then(ingest => {
+      console.log("Ingest:", ingest);
+      // The archive has been received, but it's usable only once it has been published
+      progressText.innerText = "100% - Processing the archive..";
+      pollIngest(ingest.ingest_id);
+    })
+    .catch(error => showIngestError(error.message));
+}
+
+// Fetch that throws the body of the response on errors, so that it can be shown
+function fetchJson(url, options){
+  return fetch(url, options).then(response => {
+    if (!response.ok){
+      return response.text().then(text => {
+        const error = new Error(text);
+        error.status = response.status;
+        throw error;
+      });
+    }
+    return response.json();
+  });
+}
 
-  let request = new XMLHttpRequest();
+// The same file picked again (EG: after reloading the page) resumes its upload
+function getResumeKey(file){
+  return `upload:${file.name}:${file.size}:${file.lastModified}`;
+}
 
-  function onError(event){
-    console.error("Upload errored: ", event);  
-    progressDiv.style.visibility = "hidden";
-    finishedSuccessDiv.style.visibility = "collapse";
-    finishedErrorDiv.style.visibility = "visible";
-    window.removeEventListener('beforeunload', onBeforeUnload);
+function startUpload(file){
+  const uploadId = localStorage.getItem(getResumeKey(file));
+  if (uploadId != null){
+    return fetchJson(`${UPLOADS_ENDPOINT}/${uploadId}`)
+      .then(upload => {
+        console.log("Resuming upload:", upload);
+        return upload;
+      })
+      .catch(error => {
+        console.warn("Can't resume the upload, starting a new one:", error.message);
+        localStorage.removeItem(getResumeKey(file));
+        return startUpload(file);
+      });
   }
 
-  function onProgress(event){
-    if (event.lengthComputable){
-      let progress = mapRange(event.loaded, 0, event.total, 0, 100);
-      progressBar.ariaValueNow = progress.toString();
-      progressBar.style.width = `${progress.toString()}%`;
-      if (progress == 100){
-        progressText.innerText = `${parseInt(progress).toString()}% - Saving the last few bits.. (this might take a while!)`;
-      } 
-      else {
-        progressText.innerText = `${parseInt(progress).toString()}%, ${event.loaded} of ${event.total} bytes`;
-      }
+  return fetchJson(UPLOADS_ENDPOINT, {
+    method: "POST",
+    headers: {"Content-Type": "application/json"},
+    body: JSON.stringify({file_name: file.name, size_bytes: file.size}),
+  }).then(upload => {
+    localStorage.setItem(getResumeKey(file), upload.upload_id);
+    return upload;
+  });
+}
+
+function showUploadProgress(uploadedBytes, totalBytes){
+  let progress = mapRange(uploadedBytes, 0, totalBytes, 0, 100);
+  progressBar.ariaValueNow = progress.toString();
+  progressBar.style.width = `${progress.toString()}%`;
+  progressText.innerText = `${parseInt(progress).toString()}%, ${uploadedBytes} of ${totalBytes} bytes`;
+}
+
+function uploadChunk(file, upload, index, attempt){
+  const start = index * upload.chunk_size;
+  const chunk = file.slice(start, Math.min(start + upload.chunk_size, file.size));
+  return fetchJson(`${UPLOADS_ENDPOINT}/${upload.upload_id}/chunks/${index}`, {
+    method: "PUT",
+    body: chunk,
+  }).catch(error => {
+    // Errors of the request itself (EG: wrong index) won't go away by retrying
+    if (error.status >= 400 && error.status < 500 || attempt >= CHUNK_MAX_ATTEMPTS){
+      throw error;
     }
-  }
+    console.warn(`Chunk ${index} failed (attempt ${attempt}), retrying:`, error.message);
+    return new Promise(resolve => setTimeout(resolve, CHUNK_RETRY_DELAY_MS * attempt))
+      .then(() => uploadChunk(file, upload, index, attempt + 1));
+  });
+}
+
+// One chunk at a time, only the ones the server doesn't have yet
+async function uploadChunks(file, upload){
+  const missingChunks = upload.missing_chunks;
+  let uploadedBytes = file.size - missingChunks.reduce(
+    (total, index) => total + Math.min(upload.chunk_size, file.size - index * upload.chunk_size), 0);
+  showUploadProgress(uploadedBytes, file.size);
 
-  function onUploadFinished(event){
-    progressDiv.style.visibility = "hidden";
-    finishedSuccessDiv.style.visibility = "visible";
-    window.removeEventListener('beforeunload', onBeforeUnload);
+  for (const index of missingChunks){
+    upload = await uploadChunk(file, upload, index, 1);
+    uploadedBytes = upload.received_ranges.reduce((total, [start, end]) => total + end - start, 0);
+    showUploadProgress(uploadedBytes, file.size);
   }
+  return upload;
+}
 
-  request.upload.addEventListener("error", onError);
-  request.upload.addEventListener("progress", onProgress);
+function finalizeUpload(file, upload){
+  progressText.innerText = "100% - Checking the archive.. (this might take a while!)";
+  return fetchJson(`${UPLOADS_ENDPOINT}/${upload.upload_id}/finalize`, {method: "POST"})
+    .then(ingest => {
+      localStorage.removeItem(getResumeKey(file));
+      return ingest;
+    });
+}
 
-  //request.upload.addEventListener("readystatechange", onReadyStateChange);
-  request.upload.addEventListener("load", onUploadFinished);
-  let endpoint = "/app/api/upload-archive";
-  //let endpoint = "http://localhost:3000/upload";
-  console.log("Uploading files to", endpoint);
-  request.open("POST", endpoint);
-  request.send(formData);
+function showIngestError(errorMessage){
+  console.error(errorMessage);
+  progressDiv.style.visibility = "hidden";
+  finishedSuccessDiv.style.visibility = "collapse";
+  finishedErrorDiv.style.visibility = "visible";
+  // Errors span multiple lines (EG: the ones of the validation)
+  uploadErrorMessage.innerText = errorMessage;
+  window.removeEventListener('beforeunload', onBeforeUnload);
 }
 
+function pollIngest(ingestId){
+  fetch(`/app/api/ingests/${ingestId}`)
+    .then(response => {
+      if (!response.ok){
+        return response.text().then(text => { throw new Error(text); });
+      }
+      return response.json();
+    })
+    .then(ingest => {
+      if (ingest.stage == "PUBLISHED"){
+        progressDiv.style.visibility = "hidden";
+        finishedSuccessDiv.innerText = `Upload completed! The archive is now version ${ingest.archive_version}.`;
+        finishedSuccessDiv.style.visibility = "visible";
+        window.removeEventListener('beforeunload', onBeforeUnload);
+        return;
+      }
+      if (ingest.stage == "FAILED"){
+        showIngestError(ingest.error);
+        return;
+      }
+      progressText.innerText = `100% - ${INGEST_STAGES_INFO[ingest.stage] || ingest.stage}`;
+      setTimeout(() => pollIngest(ingestId), INGEST_POLLING_INTERVAL_MS);
+    })
+    .catch(error => showIngestError(`Failed to get the status of the upload: ${error.message}`));
+}
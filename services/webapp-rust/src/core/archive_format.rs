@@ -0,0 +1,175 @@
+// The formats of the archives that can be uploaded. They are told apart by their magic bytes,
+// never by the name of the file (EG: 7-Zip on Windows makes .zip archives by default).
+//
+//   .tar.gz  -> 1f 8b
+//   .tar.zst -> 28 b5 2f fd
+//   .zip     -> 'PK' 03 04
+//   .tar     -> 'ustar' at offset 257 (the magic of the first header)
+
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use tar::Archive;
+use zip::ZipArchive;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+// How many bytes are needed to detect the format
+pub const HEADER_LEN: usize = 262;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const ZIP_MAGIC: &[u8] = &[0x50, 0x4b, 0x03, 0x04];
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_MAGIC_OFFSET: usize = 257;
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    TarGz,
+    TarZst,
+    Tar,
+    Zip,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarZst => "tar.zst",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+}
+
+// Returns None if the header doesn't belong to any of the supported formats
+pub fn detect_format(header: &[u8]) -> Option<ArchiveFormat> {
+    if header.starts_with(GZIP_MAGIC) {
+        return Some(ArchiveFormat::TarGz);
+    }
+    if header.starts_with(ZSTD_MAGIC) {
+        return Some(ArchiveFormat::TarZst);
+    }
+    if header.starts_with(ZIP_MAGIC) {
+        return Some(ArchiveFormat::Zip);
+    }
+    match header.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()) {
+        Some(magic) if magic == TAR_MAGIC => Some(ArchiveFormat::Tar),
+        _ => None,
+    }
+}
+
+pub fn unsupported_format_message(file_name: &str) -> String {
+    format!(
+        "{} is not a supported archive (.tar.gz, .tar.zst, .tar or .zip)",
+        file_name
+    )
+}
+
+pub fn read_header(archive_path: &Path) -> anyhow::Result<Vec<u8>> {
+    let file = match File::open(archive_path) {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!("Failed to open {}: {}", archive_path.display(), e);
+            anyhow::bail!(message);
+        }
+    };
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    match file.take(HEADER_LEN as u64).read_to_end(&mut header) {
+        Ok(_) => Ok(header),
+        Err(e) => {
+            let message = format!("Failed to read {}: {}", archive_path.display(), e);
+            anyhow::bail!(message);
+        }
+    }
+}
+
+pub fn zstd_decoder<R: Read>(reader: R) -> anyhow::Result<zstd::Decoder<'static, BufReader<R>>> {
+    match zstd::Decoder::new(reader) {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            let message = format!("Failed to read zstd archive: {}", e);
+            anyhow::bail!(message);
+        }
+    }
+}
+
+fn list_tar_entries<R: Read>(reader: R) -> anyhow::Result<Vec<(String, bool)>> {
+    let mut archive = Archive::new(reader);
+    let archive_entries = match archive.entries() {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!("Failed to read tar archive: {}", e);
+            anyhow::bail!(message);
+        }
+    };
+
+    let mut paths = Vec::new();
+    for entry in archive_entries {
+        let entry = match entry {
+            Ok(r) => r,
+            Err(e) => {
+                let message = format!("Failed to read tar archive: {}", e);
+                anyhow::bail!(message);
+            }
+        };
+        let path = match entry.path() {
+            Ok(p) => p.to_string_lossy().replace('\\', "/"),
+            Err(e) => {
+                let message = format!("Invalid path in tar archive: {}", e);
+                anyhow::bail!(message);
+            }
+        };
+        paths.push((path, entry.header().entry_type().is_dir()));
+    }
+    Ok(paths)
+}
+
+fn list_zip_entries(data: &[u8]) -> anyhow::Result<Vec<(String, bool)>> {
+    let mut archive = match ZipArchive::new(Cursor::new(data)) {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!("Failed to read zip archive: {}", e);
+            anyhow::bail!(message);
+        }
+    };
+
+    let mut paths = Vec::new();
+    for index in 0..archive.len() {
+        // Only the central directory is read, nothing is decompressed
+        let entry = match archive.by_index_raw(index) {
+            Ok(r) => r,
+            Err(e) => {
+                let message = format!("Failed to read zip archive: {}", e);
+                anyhow::bail!(message);
+            }
+        };
+        paths.push((entry.name().replace('\\', "/"), entry.is_dir()));
+    }
+    Ok(paths)
+}
+
+// The paths of an archive held in memory (path, is_dir), without extracting it.
+// Returns None if the data isn't an archive.
+pub fn list_entries(data: &[u8]) -> Option<anyhow::Result<Vec<(String, bool)>>> {
+    let format = detect_format(data)?;
+    let result = match format {
+        ArchiveFormat::TarGz => list_tar_entries(GzDecoder::new(Cursor::new(data))),
+        ArchiveFormat::TarZst => zstd_decoder(Cursor::new(data)).and_then(list_tar_entries),
+        ArchiveFormat::Tar => list_tar_entries(Cursor::new(data)),
+        ArchiveFormat::Zip => list_zip_entries(data),
+    };
+    Some(result)
+}
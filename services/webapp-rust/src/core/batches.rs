@@ -0,0 +1,807 @@
+// Generation of whole collections: N unique recipes rendered with a bounded parallelism.
+//
+// Every batch lives in its own directory (BATCHES_ROOT_DIR/<batch_id>):
+//   batch.json    -> status and counters, rewritten on every change
+//   recipes.json  -> the unique recipes, written once when they have been generated
+//   images/       -> the rendered images, numbered sequentially (00001.png, 00002.png, ..)
+//   metadata/     -> the NFT metadata of every rendered image (00001.json, 00002.json, ..)
+// An item is considered done when its image exists, so that a batch can be resumed
+// (even after a restart of the server) without rendering anything twice.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use axum::{extract::Path as UrlPath, http::StatusCode, response::Json};
+use chrono::Utc;
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::composite::render_recipe;
+use crate::core::constants::{
+    BATCHES_ROOT_DIR, BATCH_DEFAULT_PARALLELISM, BATCH_MAX_PARALLELISM, BATCH_MAX_SIZE,
+    BATCH_RECIPE_ATTEMPTS_PER_ITEM,
+};
+use crate::core::metadata::{build_metadata, load_metadata, save_metadata, NftMetadata};
+use crate::core::rarity::load_manifest as load_rarity_manifest;
+use crate::core::recipe::{generate_random_recipe, Recipe};
+use crate::core::repository::{record_asset, AssetKind};
+use crate::core::rules::load_rules;
+use crate::core::uniqueness::claim_recipe;
+use crate::core::{
+    find_entry_point, get_entry_point_path, get_requested_version, read_json, write_json_atomically,
+};
+
+// Serializes the read-modify-write cycles on batch.json
+static BATCHES_LOCK: Mutex<()> = Mutex::new(());
+// Ids of the batches that currently have a runner attached
+static RUNNING_BATCHES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+// NB: the names follow the ones used by JobStatus
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BatchStatus {
+    QUEUED,
+    RUNNING,
+    PAUSED,
+    CANCELLED,
+    COMPLETED,
+    FAILED,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchFailure {
+    pub index: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchRecord {
+    pub batch_id: String,
+    pub status: BatchStatus,
+    pub archive_version: i32,
+    pub size: usize,
+    pub parallelism: usize,
+    pub created_at: String,
+    pub finished_at: Option<String>,
+    pub completed: usize,
+    pub failures: Vec<BatchFailure>,
+    pub error: Option<String>,
+    // Where the images will be reachable once uploaded (EG: 'ipfs://<CID>')
+    #[serde(default)]
+    pub metadata_base_uri: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    pub size: usize,
+    pub version: Option<i32>,
+    pub parallelism: Option<usize>,
+    pub metadata_base_uri: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchMetadataRequest {
+    pub base_uri: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchData {
+    endpoint: String,
+    #[serde(flatten)]
+    record: BatchRecord,
+    pending: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchListData {
+    batches: Vec<BatchData>,
+}
+
+// -----------------------------------------------------------------------------
+// Storage
+// -----------------------------------------------------------------------------
+
+fn now_rfc3339() -> String {
+    Utc::now().to_rfc3339()
+}
+
+fn is_valid_batch_id(batch_id: &str) -> bool {
+    !batch_id.is_empty()
+        && batch_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+pub fn get_batch_dir(batch_id: &str) -> PathBuf {
+    Path::new(BATCHES_ROOT_DIR).join(batch_id)
+}
+
+fn get_images_dir(batch_id: &str) -> PathBuf {
+    get_batch_dir(batch_id).join("images")
+}
+
+fn get_metadata_dir(batch_id: &str) -> PathBuf {
+    get_batch_dir(batch_id).join("metadata")
+}
+
+// The rendered image of the Nth item (starting from 1)
+pub fn get_item_image_path(batch_id: &str, index: usize) -> PathBuf {
+    get_images_dir(batch_id).join(format!("{:05}.png", index))
+}
+
+pub fn get_item_metadata_path(batch_id: &str, index: usize) -> PathBuf {
+    get_metadata_dir(batch_id).join(format!("{:05}.json", index))
+}
+
+// (Re)write the metadata of the Nth item, pointing to the current base URI of the batch
+fn write_item_metadata(batch_id: &str, index: usize, recipe: &Recipe) -> anyhow::Result<()> {
+    let base_uri = match load_batch(batch_id)? {
+        Some(record) => record.metadata_base_uri,
+        None => None,
+    };
+    let image_file_name = format!("{:05}.png", index);
+    let metadata = build_metadata(
+        &format!("#{}", index),
+        recipe,
+        base_uri.as_deref(),
+        &image_file_name,
+    );
+    save_metadata(&get_item_metadata_path(batch_id, index), &metadata)
+}
+
+// The files of a rendered item are already in place, failing to keep track of them
+// doesn't make the item fail
+fn record_item_assets(batch_id: &str, index: usize, archive_version: i32, image_path: &Path) {
+    let assets = [
+        (AssetKind::Image, image_path.to_path_buf()),
+        (AssetKind::Metadata, get_item_metadata_path(batch_id, index)),
+    ];
+    for (kind, path) in &assets {
+        match record_asset(batch_id, Some(index), *kind, Some(archive_version), path) {
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!(
+                    "Failed to record {:?} of item {} of batch {}. {}",
+                    kind, index, batch_id, e
+                );
+            }
+        }
+    }
+}
+
+fn save_batch(record: &BatchRecord) -> anyhow::Result<()> {
+    let batch_dir = get_batch_dir(&record.batch_id);
+    match fs::create_dir_all(&batch_dir) {
+        Ok(_) => {}
+        Err(e) => {
+            let message = format!("Failed to create {}. Error: {}", batch_dir.display(), e);
+            anyhow::bail!(message);
+        }
+    }
+    write_json_atomically(&batch_dir.join("batch.json"), record)
+}
+
+pub fn load_batch(batch_id: &str) -> anyhow::Result<Option<BatchRecord>> {
+    if !is_valid_batch_id(batch_id) {
+        return Ok(None);
+    }
+    let record_path = get_batch_dir(batch_id).join("batch.json");
+    if !record_path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(read_json(&record_path)?))
+}
+
+// Load the batch, apply the changes and save it back, without racing with the other writers
+fn update_batch<F>(batch_id: &str, update: F) -> anyhow::Result<BatchRecord>
+where
+    F: FnOnce(&mut BatchRecord),
+{
+    let _guard = BATCHES_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut record = match load_batch(batch_id)? {
+        Some(r) => r,
+        None => {
+            let message = format!("Batch {} doesn't exist.", batch_id);
+            anyhow::bail!(message);
+        }
+    };
+    update(&mut record);
+    save_batch(&record)?;
+    Ok(record)
+}
+
+pub fn load_batch_recipes(batch_id: &str) -> anyhow::Result<Vec<Recipe>> {
+    let recipes_path = get_batch_dir(batch_id).join("recipes.json");
+    if !recipes_path.exists() {
+        return Ok(vec![]);
+    }
+    read_json(&recipes_path)
+}
+
+fn list_batches() -> anyhow::Result<Vec<BatchRecord>> {
+    let batches_root_dir = Path::new(BATCHES_ROOT_DIR);
+    if !batches_root_dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let entries = match fs::read_dir(batches_root_dir) {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!(
+                "Failed to read directory {}. Error: {}",
+                batches_root_dir.display(),
+                e
+            );
+            anyhow::bail!(message);
+        }
+    };
+
+    let mut batches = Vec::new();
+    for entry in entries.flatten() {
+        let batch_id = entry.file_name().to_string_lossy().to_string();
+        match load_batch(&batch_id) {
+            Ok(Some(record)) => batches.push(record),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Skipping batch {}. {}", batch_id, e);
+            }
+        }
+    }
+
+    // Most recent first
+    batches.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    Ok(batches)
+}
+
+fn get_batch_data(record: BatchRecord) -> BatchData {
+    let pending = record
+        .size
+        .saturating_sub(record.completed + record.failures.len());
+    BatchData {
+        endpoint: format!("api/batches/{}", record.batch_id),
+        record,
+        pending,
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Generation
+// -----------------------------------------------------------------------------
+
+// Generate `size` recipes that are all different from each other,
+// and from every recipe generated before for the same archive version
+fn generate_unique_recipes(
+    archive_version: i32,
+    entry_point: &Path,
+    size: usize,
+) -> anyhow::Result<Vec<Recipe>> {
+    let rarity = load_rarity_manifest(archive_version)?;
+    let rules = load_rules(archive_version)?;
+    let mut recipes = Vec::with_capacity(size);
+
+    let max_attempts = size * BATCH_RECIPE_ATTEMPTS_PER_ITEM;
+    let mut attempts = 0;
+
+    while recipes.len() < size {
+        if attempts >= max_attempts {
+            let message = format!(
+                "Could only generate {} unique recipes out of {} after {} attempts. \
+                The archive might not have enough combinations.",
+                recipes.len(),
+                size,
+                attempts
+            );
+            anyhow::bail!(message);
+        }
+        attempts += 1;
+
+        let recipe = generate_random_recipe(entry_point, &rarity, &rules)?;
+        if claim_recipe(archive_version, &recipe)? {
+            recipes.push(recipe);
+        }
+    }
+
+    Ok(recipes)
+}
+
+fn get_batch_status(batch_id: &str) -> Option<BatchStatus> {
+    match load_batch(batch_id) {
+        Ok(Some(record)) => Some(record.status),
+        Ok(None) => None,
+        Err(e) => {
+            eprintln!("Failed to read status of batch {}. {}", batch_id, e);
+            None
+        }
+    }
+}
+
+fn set_item_outcome(batch_id: &str, index: usize, outcome: Result<(), String>) {
+    let update_result = update_batch(batch_id, |record| match outcome {
+        Ok(_) => {
+            record.completed += 1;
+            record.failures.retain(|f| f.index != index);
+        }
+        Err(error) => {
+            eprintln!(
+                "Item {} of batch {} failed. {}",
+                index, record.batch_id, error
+            );
+            record.failures.retain(|f| f.index != index);
+            record.failures.push(BatchFailure { index, error });
+        }
+    });
+    if let Err(e) = update_result {
+        eprintln!("Failed to update batch {}. {}", batch_id, e);
+    }
+}
+
+fn render_item(
+    batch_id: &str,
+    index: usize,
+    recipe: &Recipe,
+    image_path: &Path,
+    archive_version: i32,
+) {
+    let render_path = image_path.with_extension("png.tmp");
+    let rendered = match render_recipe(recipe, &render_path, |_, _| {}) {
+        // The metadata is written first, since the image marks the item as done
+        Ok(_) => write_item_metadata(batch_id, index, recipe),
+        Err(e) => Err(e.into()),
+    };
+
+    let outcome = match rendered {
+        Ok(_) => match fs::rename(&render_path, image_path) {
+            Ok(_) => {
+                record_item_assets(batch_id, index, archive_version, image_path);
+                Ok(())
+            }
+            Err(e) => Err(format!(
+                "Failed to move image to {}. {}",
+                image_path.display(),
+                e
+            )),
+        },
+        Err(e) => Err(e.to_string()),
+    };
+    set_item_outcome(batch_id, index, outcome);
+}
+
+async fn render_pending_items(
+    batch_id: &str,
+    recipes: Vec<Recipe>,
+    parallelism: usize,
+    archive_version: i32,
+) {
+    let semaphore = Arc::new(Semaphore::new(parallelism));
+    let mut handles = Vec::new();
+
+    for (position, recipe) in recipes.into_iter().enumerate() {
+        let index = position + 1;
+        let image_path = get_item_image_path(batch_id, index);
+        if image_path.exists() {
+            // The server might have stopped right after rendering the image
+            if !get_item_metadata_path(batch_id, index).exists() {
+                if let Err(e) = write_item_metadata(batch_id, index, &recipe) {
+                    eprintln!(
+                        "Failed to write metadata of item {} of batch {}. {}",
+                        index, batch_id, e
+                    );
+                }
+            }
+            continue;
+        }
+
+        // Wait for a free slot, then check whether we've been asked to stop
+        let permit = match semaphore.clone().acquire_owned().await {
+            Ok(p) => p,
+            Err(_) => break,
+        };
+        let status_batch_id = String::from(batch_id);
+        let status = tokio::task::spawn_blocking(move || get_batch_status(&status_batch_id)).await;
+        if !matches!(status, Ok(Some(BatchStatus::RUNNING))) {
+            break;
+        }
+
+        let item_batch_id = String::from(batch_id);
+        let handle = tokio::task::spawn_blocking(move || {
+            render_item(&item_batch_id, index, &recipe, &image_path, archive_version);
+            drop(permit);
+        });
+        handles.push((index, handle));
+    }
+
+    for (index, handle) in handles {
+        if let Err(e) = handle.await {
+            // Counted as a failure, otherwise the item would never be done
+            let error = format!("Render task panicked. {}", e);
+            let failed_batch_id = String::from(batch_id);
+            let failed = tokio::task::spawn_blocking(move || {
+                set_item_outcome(&failed_batch_id, index, Err(error))
+            })
+            .await;
+            if let Err(e) = failed {
+                eprintln!("Failed to update batch {}. {}", batch_id, e);
+            }
+        }
+    }
+}
+
+// Load the batch and its recipes (generating them the first time), and mark it as running.
+// None if there is nothing to render.
+fn prepare_batch(batch_id: &str) -> anyhow::Result<Option<(BatchRecord, Vec<Recipe>)>> {
+    let record = match load_batch(batch_id)? {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+
+    let mut recipes = load_batch_recipes(batch_id)?;
+    if recipes.is_empty() {
+        eprintln!(
+            "Generating {} unique recipes for batch {}",
+            record.size, batch_id
+        );
+        let entry_point = get_entry_point_path(record.archive_version)?;
+        recipes = generate_unique_recipes(record.archive_version, &entry_point, record.size)?;
+
+        let recipes_path = get_batch_dir(batch_id).join("recipes.json");
+        write_json_atomically(&recipes_path, &recipes)?;
+    }
+
+    let started = update_batch(batch_id, |record| {
+        if record.status == BatchStatus::QUEUED {
+            record.status = BatchStatus::RUNNING;
+        }
+    })?;
+    if started.status != BatchStatus::RUNNING {
+        return Ok(None);
+    }
+
+    for dir in [get_images_dir(batch_id), get_metadata_dir(batch_id)] {
+        match fs::create_dir_all(&dir) {
+            Ok(_) => {}
+            Err(e) => {
+                let message = format!("Failed to create {}. Error: {}", dir.display(), e);
+                anyhow::bail!(message);
+            }
+        }
+    }
+    Ok(Some((started, recipes)))
+}
+
+// The images on disk are what counts: the counters might have missed an item
+// (e.g. the server stopped, or batch.json couldn't be written, right after the rename)
+fn finish_pass(batch_id: &str) -> anyhow::Result<()> {
+    update_batch(batch_id, |record| {
+        record.completed = (1..=record.size)
+            .filter(|index| get_item_image_path(batch_id, *index).exists())
+            .count();
+        record
+            .failures
+            .retain(|f| !get_item_image_path(batch_id, f.index).exists());
+
+        let done = record.completed + record.failures.len();
+        if record.status == BatchStatus::RUNNING && done >= record.size {
+            record.status = BatchStatus::COMPLETED;
+            record.finished_at = Some(now_rfc3339());
+        }
+    })?;
+    Ok(())
+}
+
+// Generate the recipes of the batch (if needed) and render everything that is still pending,
+// until the batch is done or somebody asks to stop it
+async fn run_batch_once(batch_id: &str) -> anyhow::Result<()> {
+    // 1. Generate the recipes, if that wasn't done already
+    let prepare_batch_id = String::from(batch_id);
+    let prepared = tokio::task::spawn_blocking(move || prepare_batch(&prepare_batch_id)).await??;
+    let (record, recipes) = match prepared {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+
+    // 2. Render them
+    render_pending_items(
+        batch_id,
+        recipes,
+        record.parallelism,
+        record.archive_version,
+    )
+    .await;
+
+    // 3. Is there anything left to do?
+    let finish_batch_id = String::from(batch_id);
+    tokio::task::spawn_blocking(move || finish_pass(&finish_batch_id)).await??;
+    Ok(())
+}
+
+// Whether the runner of the batch can stop. If so, the batch is released.
+fn release_runner(batch_id: &str) -> bool {
+    // NB: the check is done while holding the lock, so that a batch resumed
+    // while we were stopping can't be left without a runner
+    let mut running = RUNNING_BATCHES.lock().unwrap_or_else(|e| e.into_inner());
+    if get_batch_status(batch_id) == Some(BatchStatus::RUNNING) {
+        return false;
+    }
+    running.retain(|id| id != batch_id);
+    true
+}
+
+async fn run_batch(batch_id: String) {
+    loop {
+        match run_batch_once(&batch_id).await {
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Batch {} has failed. {}", batch_id, e);
+                let failed_batch_id = batch_id.clone();
+                let failed = tokio::task::spawn_blocking(move || {
+                    update_batch(&failed_batch_id, |record| {
+                        record.status = BatchStatus::FAILED;
+                        record.finished_at = Some(now_rfc3339());
+                        record.error = Some(e.to_string());
+                    })
+                })
+                .await;
+                match failed {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => {
+                        eprintln!("Failed to mark batch {} as failed. {}", batch_id, e)
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to mark batch {} as failed. {}", batch_id, e)
+                    }
+                }
+            }
+        }
+
+        let release_batch_id = batch_id.clone();
+        let released = tokio::task::spawn_blocking(move || release_runner(&release_batch_id)).await;
+        match released {
+            Ok(false) => continue,
+            Ok(true) => {}
+            Err(e) => {
+                eprintln!("Failed to check status of batch {}. {}", batch_id, e);
+                let mut running = RUNNING_BATCHES.lock().unwrap_or_else(|e| e.into_inner());
+                running.retain(|id| id != &batch_id);
+            }
+        }
+        eprintln!("Runner of batch {} has stopped.", batch_id);
+        return;
+    }
+}
+
+// Attach a runner to the batch, unless one is running already
+fn spawn_runner(batch_id: &str) {
+    {
+        let mut running = RUNNING_BATCHES.lock().unwrap_or_else(|e| e.into_inner());
+        if running.iter().any(|id| id == batch_id) {
+            return;
+        }
+        running.push(String::from(batch_id));
+    }
+    tokio::spawn(run_batch(String::from(batch_id)));
+}
+
+// Batches that were running when the server went down are picked up where they were left
+pub fn resume_interrupted_batches() -> anyhow::Result<()> {
+    for record in list_batches()? {
+        if matches!(record.status, BatchStatus::QUEUED | BatchStatus::RUNNING) {
+            eprintln!("Resuming batch {}", record.batch_id);
+            spawn_runner(&record.batch_id);
+        }
+    }
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, String) {
+    eprintln!("{}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+fn load_batch_or_404(batch_id: &str) -> Result<BatchRecord, (StatusCode, String)> {
+    match load_batch(batch_id).map_err(internal_error)? {
+        Some(record) => Ok(record),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            format!("Batch {} doesn't exist.", batch_id),
+        )),
+    }
+}
+
+pub async fn create_batch(
+    Json(request): Json<BatchRequest>,
+) -> Result<Json<BatchData>, (StatusCode, String)> {
+    if request.size == 0 || request.size > BATCH_MAX_SIZE {
+        let message = format!(
+            "The size of a batch must be between 1 and {}",
+            BATCH_MAX_SIZE
+        );
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+
+    let parallelism = request
+        .parallelism
+        .unwrap_or(BATCH_DEFAULT_PARALLELISM)
+        .clamp(1, BATCH_MAX_PARALLELISM);
+
+    let archive_version = get_requested_version(request.version).await?;
+    // Fail early if the archive can't be used
+    find_entry_point(archive_version)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let record = BatchRecord {
+        batch_id: Uuid::new_v4().to_string(),
+        status: BatchStatus::QUEUED,
+        archive_version,
+        size: request.size,
+        parallelism,
+        created_at: now_rfc3339(),
+        finished_at: None,
+        completed: 0,
+        failures: vec![],
+        error: None,
+        metadata_base_uri: request.metadata_base_uri,
+    };
+    let saved_record = record.clone();
+    tokio::task::spawn_blocking(move || save_batch(&saved_record))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(internal_error)?;
+    eprintln!("Queued batch {} of {} images", record.batch_id, record.size);
+
+    spawn_runner(&record.batch_id);
+
+    Ok(Json(get_batch_data(record)))
+}
+
+pub async fn get_batches() -> Result<Json<BatchListData>, (StatusCode, String)> {
+    let batches = tokio::task::spawn_blocking(list_batches)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(internal_error)?
+        .into_iter()
+        .map(get_batch_data)
+        .collect();
+    Ok(Json(BatchListData { batches }))
+}
+
+pub async fn get_batch(
+    UrlPath(batch_id): UrlPath<String>,
+) -> Result<Json<BatchData>, (StatusCode, String)> {
+    let record = tokio::task::spawn_blocking(move || load_batch_or_404(&batch_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    Ok(Json(get_batch_data(record)))
+}
+
+async fn change_status(
+    batch_id: &str,
+    allowed_from: &'static [BatchStatus],
+    new_status: BatchStatus,
+) -> Result<BatchRecord, (StatusCode, String)> {
+    let batch_id = String::from(batch_id);
+    tokio::task::spawn_blocking(move || {
+        let current = load_batch_or_404(&batch_id)?;
+        if !allowed_from.contains(&current.status) {
+            let message = format!(
+                "Batch {} is {:?}, it can't become {:?}",
+                batch_id, current.status, new_status
+            );
+            return Err((StatusCode::CONFLICT, message));
+        }
+
+        update_batch(&batch_id, |record| {
+            record.status = new_status;
+            if new_status == BatchStatus::CANCELLED {
+                record.finished_at = Some(now_rfc3339());
+            }
+        })
+        .map_err(internal_error)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+}
+
+pub async fn pause_batch(
+    UrlPath(batch_id): UrlPath<String>,
+) -> Result<Json<BatchData>, (StatusCode, String)> {
+    let record = change_status(
+        &batch_id,
+        &[BatchStatus::QUEUED, BatchStatus::RUNNING],
+        BatchStatus::PAUSED,
+    )
+    .await?;
+    Ok(Json(get_batch_data(record)))
+}
+
+pub async fn resume_batch(
+    UrlPath(batch_id): UrlPath<String>,
+) -> Result<Json<BatchData>, (StatusCode, String)> {
+    let record = change_status(&batch_id, &[BatchStatus::PAUSED], BatchStatus::RUNNING).await?;
+    spawn_runner(&batch_id);
+    Ok(Json(get_batch_data(record)))
+}
+
+pub async fn cancel_batch(
+    UrlPath(batch_id): UrlPath<String>,
+) -> Result<Json<BatchData>, (StatusCode, String)> {
+    let record = change_status(
+        &batch_id,
+        &[
+            BatchStatus::QUEUED,
+            BatchStatus::RUNNING,
+            BatchStatus::PAUSED,
+        ],
+        BatchStatus::CANCELLED,
+    )
+    .await?;
+    Ok(Json(get_batch_data(record)))
+}
+
+// Point the metadata of the batch to where the images have been uploaded,
+// rewriting the metadata of the images rendered so far
+pub async fn set_batch_metadata_base_uri(
+    UrlPath(batch_id): UrlPath<String>,
+    Json(request): Json<BatchMetadataRequest>,
+) -> Result<Json<BatchData>, (StatusCode, String)> {
+    let update_batch_id = batch_id.clone();
+    let record = tokio::task::spawn_blocking(move || {
+        load_batch_or_404(&update_batch_id)?;
+        update_batch(&update_batch_id, |record| {
+            record.metadata_base_uri = request.base_uri;
+        })
+        .map_err(internal_error)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+
+    let rewrite_batch_id = batch_id.clone();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let recipes = load_batch_recipes(&rewrite_batch_id)?;
+        for (position, recipe) in recipes.iter().enumerate() {
+            let index = position + 1;
+            if get_item_image_path(&rewrite_batch_id, index).exists() {
+                write_item_metadata(&rewrite_batch_id, index, recipe)?;
+            }
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(internal_error)?;
+
+    Ok(Json(get_batch_data(record)))
+}
+
+// The metadata of the Nth image of the batch (starting from 1)
+pub async fn get_batch_item_metadata(
+    UrlPath((batch_id, index)): UrlPath<(String, usize)>,
+) -> Result<Json<NftMetadata>, (StatusCode, String)> {
+    let metadata = tokio::task::spawn_blocking(move || {
+        load_batch_or_404(&batch_id)?;
+
+        let metadata_path = get_item_metadata_path(&batch_id, index);
+        if !metadata_path.exists() {
+            let message = format!("Item {} of batch {} hasn't been rendered.", index, batch_id);
+            return Err((StatusCode::NOT_FOUND, message));
+        }
+
+        load_metadata(&metadata_path).map_err(internal_error)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    Ok(Json(metadata))
+}
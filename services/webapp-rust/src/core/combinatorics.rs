@@ -0,0 +1,305 @@
+// How many different recipes can be generated out of an archive.
+// The archive is walked with the same rules used to generate recipes
+// (overlays are combined, variants are alternatives, leaves are alternatives),
+// keeping track of the stream chosen by the body skin, since it limits the skins that can follow.
+// NB: this models the default compatibility rules (see rules.rs), nothing else. When a version
+// has custom rules the count is flagged as approximate: they can forbid combinations
+// as well as allow new ones (EG: without the stream rule).
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use axum::{extract::Query, http::StatusCode, response::Json};
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::SKINS_DIR_NAME;
+use crate::core::recipe::{get_stream, is_overlay, read_dir_sorted};
+use crate::core::rules::uses_default_rules;
+use crate::core::{find_entry_point, get_requested_version};
+
+// Number of combinations, grouped by the stream that is active once they've been chosen
+type CountsByStream = BTreeMap<Option<String>, u128>;
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone)]
+pub struct LayerCombinations {
+    pub name: String,
+    pub combinations: u128,
+}
+
+#[derive(Debug, Clone)]
+pub struct Combinations {
+    // Exact number of recipes, stream constraints included
+    pub total: u128,
+    // What the total would be if every layer could be combined freely with the others
+    pub unconstrained_total: u128,
+    pub layers: Vec<LayerCombinations>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CombinationsQuery {
+    pub version: Option<i32>,
+    // Size of a collection, to know how much of the space it would use
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LayerCombinationsData {
+    name: String,
+    combinations: String,
+}
+
+// NB: big numbers are sent as strings, since javascript can't represent them precisely
+#[derive(Debug, Serialize)]
+pub struct CombinationsData {
+    version: i32,
+    total_combinations: String,
+    // The version has custom rules, which the count doesn't know about
+    approximate: bool,
+    unconstrained_combinations: String,
+    layers: Vec<LayerCombinationsData>,
+    collection_size: Option<u64>,
+    // Percentage of the total combinations used by a collection of the given size
+    collection_coverage: Option<f64>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+// The result of an addition or multiplication of counts, which can't fit in a u128
+// once an archive has enough layers
+fn checked_count(count: Option<u128>) -> anyhow::Result<u128> {
+    match count {
+        Some(c) => Ok(c),
+        None => {
+            let message = format!(
+                "The archive has too many combinations to be counted (more than {})",
+                u128::MAX
+            );
+            anyhow::bail!(message);
+        }
+    }
+}
+
+fn add_count(
+    counts: &mut CountsByStream,
+    stream: Option<String>,
+    count: u128,
+) -> anyhow::Result<()> {
+    if count == 0 {
+        return Ok(());
+    }
+    let total = counts.entry(stream).or_insert(0);
+    *total = checked_count(total.checked_add(count))?;
+    Ok(())
+}
+
+fn sum_counts(counts: &CountsByStream) -> anyhow::Result<u128> {
+    checked_count(
+        counts
+            .values()
+            .try_fold(0u128, |acc, count| acc.checked_add(*count)),
+    )
+}
+
+fn count_in_dir(
+    dir: &Path,
+    dir_name: &str,
+    current_stream: &Option<String>,
+) -> anyhow::Result<CountsByStream> {
+    let (dirs, files) = read_dir_sorted(dir)?;
+    let mut counts = CountsByStream::new();
+
+    // 1. Overlays: every one of them is used, so their combinations multiply
+    let overlays: Vec<&String> = dirs.iter().filter(|d| is_overlay(d)).collect();
+    if !overlays.is_empty() {
+        add_count(&mut counts, current_stream.clone(), 1)?;
+
+        for overlay in overlays {
+            let mut next_counts = CountsByStream::new();
+            for (stream, count) in &counts {
+                let overlay_counts = count_in_dir(&dir.join(overlay), overlay, stream)?;
+                for (next_stream, overlay_count) in overlay_counts {
+                    let combined = checked_count(count.checked_mul(overlay_count))?;
+                    add_count(&mut next_counts, next_stream, combined)?;
+                }
+            }
+            counts = next_counts;
+        }
+        return Ok(counts);
+    }
+
+    // 2. Variants: only one of them is used, so their combinations add up
+    if !dirs.is_empty() {
+        for variant in &dirs {
+            let variant_counts = count_in_dir(&dir.join(variant), variant, current_stream)?;
+            for (stream, count) in variant_counts {
+                add_count(&mut counts, stream, count)?;
+            }
+        }
+        return Ok(counts);
+    }
+
+    // 3. Leaves: one of them is used, as long as it belongs to the current stream
+    let potential_leaves: Vec<&String> = match current_stream {
+        Some(stream) if !stream.is_empty() && dir_name.contains("skins") => files
+            .iter()
+            .filter(|leaf| get_stream(leaf).as_ref() == Some(stream))
+            .collect(),
+        _ => files.iter().collect(),
+    };
+
+    // A directory without leaves is skipped by the generation, it doesn't remove combinations
+    if potential_leaves.is_empty() {
+        add_count(&mut counts, current_stream.clone(), 1)?;
+        return Ok(counts);
+    }
+
+    for leaf in potential_leaves {
+        let next_stream = if dir_name == SKINS_DIR_NAME {
+            get_stream(leaf)
+        } else {
+            current_stream.clone()
+        };
+        add_count(&mut counts, next_stream, 1)?;
+    }
+
+    Ok(counts)
+}
+
+// Exact number of different recipes that can be generated starting from the entry point
+pub fn count_combinations(entry_point: &Path) -> anyhow::Result<u128> {
+    let counts = count_in_dir(entry_point, "", &None)?;
+    sum_counts(&counts)
+}
+
+// Total number of recipes, broken down by the layers at the top of the archive
+// (EG: '01_background', '02_body_skins', ...)
+pub fn compute_combinations(entry_point: &Path) -> anyhow::Result<Combinations> {
+    let total = count_combinations(entry_point)?;
+
+    let (dirs, _) = read_dir_sorted(entry_point)?;
+    let mut layers = Vec::new();
+    for dir_name in dirs.iter().filter(|d| is_overlay(d)) {
+        // Every layer is counted on its own, so that there's no stream to respect
+        let counts = count_in_dir(&entry_point.join(dir_name), dir_name, &None)?;
+        layers.push(LayerCombinations {
+            name: dir_name.clone(),
+            combinations: sum_counts(&counts)?,
+        });
+    }
+
+    let unconstrained_total = if layers.is_empty() {
+        total
+    } else {
+        layers
+            .iter()
+            .fold(1u128, |acc, l| acc.saturating_mul(l.combinations))
+    };
+
+    Ok(Combinations {
+        total,
+        unconstrained_total,
+        layers,
+    })
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+// How many different recipes an archive version can produce.
+// NB: only the default rules are taken into account. When the version has its own rules
+// (see /api/rules) the numbers are the ones of the default rules, flagged as `approximate`.
+pub async fn get_combinations(
+    query: Query<CombinationsQuery>,
+) -> Result<Json<CombinationsData>, (StatusCode, String)> {
+    let version = get_requested_version(query.version).await?;
+
+    let entry_point = find_entry_point(version)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+
+    let (combinations, approximate) =
+        tokio::task::spawn_blocking(move || -> anyhow::Result<(Combinations, bool)> {
+            Ok((
+                compute_combinations(&entry_point)?,
+                !uses_default_rules(version)?,
+            ))
+        })
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let collection_coverage = match query.size {
+        Some(size) if combinations.total > 0 => {
+            Some(size as f64 * 100.0 / combinations.total as f64)
+        }
+        _ => None,
+    };
+
+    Ok(Json(CombinationsData {
+        version,
+        total_combinations: combinations.total.to_string(),
+        approximate,
+        unconstrained_combinations: combinations.unconstrained_total.to_string(),
+        layers: combinations
+            .layers
+            .into_iter()
+            .map(|l| LayerCombinationsData {
+                name: l.name,
+                combinations: l.combinations.to_string(),
+            })
+            .collect(),
+        collection_size: query.size,
+        collection_coverage,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::core::test_utils::{sample_archive, TestDir};
+
+    #[test]
+    fn counts_only_skins_of_the_chosen_stream() {
+        let dir = TestDir::new("combinatorics");
+        sample_archive(&dir);
+
+        let combinations = compute_combinations(&dir.path).unwrap();
+
+        // 3 backgrounds, 2 bodies and the ear skin matching the body
+        assert_eq!(combinations.total, 6);
+        assert_eq!(combinations.unconstrained_total, 12);
+        let layers: Vec<(&str, u128)> = combinations
+            .layers
+            .iter()
+            .map(|l| (l.name.as_str(), l.combinations))
+            .collect();
+        assert_eq!(
+            layers,
+            vec![("01_background", 3), ("02_body_skins", 2), ("03_ears", 2)]
+        );
+    }
+
+    #[test]
+    fn refuses_counts_overflowing() {
+        // 10^40 combinations, more than a u128 can hold
+        let dir = TestDir::new("combinatorics");
+        for layer in 10..50 {
+            for leaf in 0..10 {
+                dir.add_file(&format!("{layer}_layer/Leaf_{leaf}.png"));
+            }
+        }
+
+        let error = count_combinations(&dir.path).unwrap_err().to_string();
+        assert!(error.contains("too many combinations"), "{error}");
+    }
+}
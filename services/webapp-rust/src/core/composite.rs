@@ -0,0 +1,178 @@
+// Rendering of recipes: every layer is alpha-blended on top of the previous ones.
+// This replaces the external image-composite binary.
+
+use std::error::Error;
+use std::fmt;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use image::{ImageFormat, RgbaImage};
+
+use crate::core::recipe::Recipe;
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug)]
+pub enum CompositeError {
+    EmptyRecipe,
+    MissingLayer(PathBuf),
+    DecodeFailed {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    SizeMismatch {
+        path: PathBuf,
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+    WriteFailed {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    EncodeFailed(image::ImageError),
+}
+
+impl fmt::Display for CompositeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompositeError::EmptyRecipe => write!(f, "The recipe has no layers to composite"),
+            CompositeError::MissingLayer(path) => {
+                write!(f, "Layer {} doesn't exist on disk", path.display())
+            }
+            CompositeError::DecodeFailed { path, source } => {
+                write!(
+                    f,
+                    "Failed to decode layer {}. Error: {}",
+                    path.display(),
+                    source
+                )
+            }
+            CompositeError::SizeMismatch {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Layer {} is {}x{}, expected {}x{} like the layers below it",
+                path.display(),
+                found.0,
+                found.1,
+                expected.0,
+                expected.1
+            ),
+            CompositeError::WriteFailed { path, source } => {
+                write!(
+                    f,
+                    "Failed to write image to {}. Error: {}",
+                    path.display(),
+                    source
+                )
+            }
+            CompositeError::EncodeFailed(source) => {
+                write!(f, "Failed to encode image as PNG. Error: {}", source)
+            }
+        }
+    }
+}
+
+impl Error for CompositeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CompositeError::DecodeFailed { source, .. } => Some(source),
+            CompositeError::WriteFailed { source, .. } => Some(source),
+            CompositeError::EncodeFailed(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+fn load_layer(path: &Path) -> Result<RgbaImage, CompositeError> {
+    if !path.is_file() {
+        return Err(CompositeError::MissingLayer(path.to_path_buf()));
+    }
+
+    match image::open(path) {
+        Ok(img) => Ok(img.into_rgba8()),
+        Err(e) => Err(CompositeError::DecodeFailed {
+            path: path.to_path_buf(),
+            source: e,
+        }),
+    }
+}
+
+// Overlay all of the layers of the recipe (bottom to top) and return the final image.
+// `on_progress` is called with (layers done, total layers) after every layer.
+pub fn composite_layers<F>(recipe: &Recipe, mut on_progress: F) -> Result<RgbaImage, CompositeError>
+where
+    F: FnMut(usize, usize),
+{
+    let layer_paths = recipe.layer_paths();
+    let num_layers = layer_paths.len();
+
+    let mut canvas: Option<RgbaImage> = None;
+
+    for (index, layer_path) in layer_paths.iter().enumerate() {
+        let layer = load_layer(layer_path)?;
+
+        match canvas.as_mut() {
+            // The first layer defines the size of the final image
+            None => {
+                canvas = Some(layer);
+            }
+            Some(base) => {
+                if base.dimensions() != layer.dimensions() {
+                    return Err(CompositeError::SizeMismatch {
+                        path: layer_path.clone(),
+                        expected: base.dimensions(),
+                        found: layer.dimensions(),
+                    });
+                }
+                image::imageops::overlay(base, &layer, 0, 0);
+            }
+        }
+
+        on_progress(index + 1, num_layers);
+    }
+
+    canvas.ok_or(CompositeError::EmptyRecipe)
+}
+
+// Composite the recipe and save it to disk as a PNG
+pub fn render_recipe<F>(
+    recipe: &Recipe,
+    output_path: &Path,
+    on_progress: F,
+) -> Result<(), CompositeError>
+where
+    F: FnMut(usize, usize),
+{
+    let final_image = composite_layers(recipe, on_progress)?;
+
+    match final_image.save_with_format(output_path, ImageFormat::Png) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(CompositeError::WriteFailed {
+            path: output_path.to_path_buf(),
+            source: e,
+        }),
+    }
+}
+
+// Composite the recipe and return the encoded PNG, without touching the disk
+pub fn render_recipe_to_png<F>(recipe: &Recipe, on_progress: F) -> Result<Vec<u8>, CompositeError>
+where
+    F: FnMut(usize, usize),
+{
+    let final_image = composite_layers(recipe, on_progress)?;
+
+    let mut png_bytes = Cursor::new(Vec::new());
+    match final_image.write_to(&mut png_bytes, ImageFormat::Png) {
+        Ok(_) => Ok(png_bytes.into_inner()),
+        Err(e) => Err(CompositeError::EncodeFailed(e)),
+    }
+}
@@ -1,10 +1,60 @@
 pub const PORT_NUM: u16 = 3000;
-pub const APP_VERSION: &'static str = "v0.1.0";
-pub const ENTRY_POINT_DIR_NAME: &'static str = "programm"; // arbitrary name given by vmassimi
+pub const APP_VERSION: &str = "v0.1.0";
+pub const ENTRY_POINT_DIR_NAME: &str = "programm"; // arbitrary name given by vmassimi
+pub const SANITIZED_ENTRY_POINT_DIR_NAME: &str = "program"; // what the sanitization renames it to
+pub const SKINS_DIR_NAME: &str = "02_body_skins"; // where the 'stream' of a cat is chosen
+pub const IGNORED_FILE_NAMES: [&str; 1] = [".DS_Store"];
 
-pub const ARCHIVES_ROOT_DIR: &'static str = "/app/data/archives";
-pub const JOBS_ROOT_DIR: &'static str = "/app/jobs";
-pub const ARCHIVES_TMP_DIR: &'static str = "/app/data/archives/tmp";
-pub const VERSIONS_PATH: &'static str = "/app/data/versions.json";
+pub const ARCHIVES_ROOT_DIR: &str = "/app/data/archives";
+pub const JOBS_ROOT_DIR: &str = "/app/data/jobs";
+pub const INGESTS_ROOT_DIR: &str = "/app/data/ingests";
+pub const UPLOADS_ROOT_DIR: &str = "/app/data/uploads";
+pub const ARCHIVES_TMP_DIR: &str = "/app/data/archives/tmp";
+// Where the archives are extracted and checked before becoming a version
+pub const ARCHIVES_STAGING_DIR: &str = "/app/data/archives/staging";
+pub const BATCHES_ROOT_DIR: &str = "/app/data/batches";
+pub const RECIPES_ROOT_DIR: &str = "/app/data/recipes";
+pub const RARITY_ROOT_DIR: &str = "/app/data/rarity";
+pub const RULES_ROOT_DIR: &str = "/app/data/rules";
+pub const SANITIZE_ROOT_DIR: &str = "/app/data/sanitize";
+// Versions, ingests, jobs, recipes and generated assets
+pub const DATABASE_PATH: &str = "/app/data/webapp.sqlite";
+// Files used before the database existed, imported into it once
+pub const VERSIONS_PATH: &str = "/app/data/versions.json";
 
 pub const ZFILL_PADDING: usize = 3;
+// Trailing numbers of sanitized names (EG: 'Ear 1' -> 'ear_01')
+pub const SANITIZE_NUM_PADDING: usize = 2;
+
+// Batches of images
+pub const BATCH_MAX_SIZE: usize = 20_000;
+pub const BATCH_DEFAULT_PARALLELISM: usize = 4;
+pub const BATCH_MAX_PARALLELISM: usize = 16;
+// How many random recipes we try per image before giving up on finding unique ones
+pub const BATCH_RECIPE_ATTEMPTS_PER_ITEM: usize = 50;
+
+// How many times a recipe is generated again when it breaks the compatibility rules
+pub const RECIPE_RULES_MAX_ATTEMPTS: usize = 100;
+// Recipes already generated for an archive version are never generated again
+pub const UNIQUE_RECIPE_MAX_ATTEMPTS: usize = 1_000;
+
+// NFT metadata
+pub const METADATA_COLLECTION_NAME: &str = "Sphynx";
+pub const METADATA_DESCRIPTION: &str = "A unique, generated Sphynx cat.";
+// Used as the base URI of the images until the real one is known
+pub const METADATA_IMAGE_BASE_URI_PLACEHOLDER: &str = "ipfs://<CID>";
+
+// Exports of batches: size of the chunks sent to the client, and how many can be waiting
+pub const EXPORT_CHUNK_SIZE: usize = 256 * 1024;
+pub const EXPORT_CHANNEL_CAPACITY: usize = 8;
+
+// Chunked uploads: size of every chunk (but the last one) and of the whole archive
+// NB: the maximum follows client_max_body_size in nginx.conf
+pub const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+pub const UPLOAD_MAX_SIZE: usize = 1500 * 1024 * 1024;
+// Limits of an archive once extracted. PNGs barely compress, so a legit archive
+// is not much bigger than what was uploaded
+pub const EXTRACT_MAX_SIZE: u64 = 8 * 1024 * 1024 * 1024;
+pub const EXTRACT_MAX_ENTRIES: usize = 100_000;
+// Diffs of versions: how many versions keep the checksums of their images in memory
+pub const DIFF_CACHED_VERSIONS: usize = 4;
@@ -0,0 +1,484 @@
+// Differences between two versions of the archive, to know what changed before regenerating anything.
+//
+// The images are compared by their path relative to the entry point (like the layers of the recipes)
+// and by the md5 of their content:
+//
+//   - added / removed: the path exists in only one of the versions
+//   - renamed: a removed and an added image with the same content
+//   - changed: same path, different content
+//
+// The recipes generated for the older version that use any of the removed, renamed or changed
+// images can't be reproduced with the newer one.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Path as UrlPath, Query},
+    http::StatusCode,
+    response::Json,
+};
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::{DIFF_CACHED_VERSIONS, ZFILL_PADDING};
+use crate::core::recipe::read_dir_sorted;
+use crate::core::repository::run_blocking;
+use crate::core::uniqueness::{load_registered_recipes, RegisteredRecipe};
+use crate::core::versions::get_previous_version;
+use crate::core::{get_entry_point_path, get_requested_version};
+
+// Path of every image (relative to the entry point) -> md5 of its content
+type Checksums = BTreeMap<String, String>;
+
+// Published versions never change, so their checksums are computed only once.
+// Only the last DIFF_CACHED_VERSIONS versions that were used are kept, most recent last.
+static CHECKSUMS: Mutex<Vec<(i32, Arc<Checksums>)>> = Mutex::new(Vec::new());
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerChangeKind {
+    Removed,
+    Renamed,
+    Changed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RenamedLayer {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LayerChange {
+    pub layer: String,
+    pub change: LayerChangeKind,
+    // Only for the renamed layers
+    pub renamed_to: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BrokenRecipe {
+    pub checksum: String,
+    pub created_at: String,
+    // Only the layers that can't be used as they are any more
+    pub changes: Vec<LayerChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionDiff {
+    pub from_version: i32,
+    pub to_version: i32,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub renamed: Vec<RenamedLayer>,
+    pub changed: Vec<String>,
+    pub unchanged: usize,
+    // How many of the recipes of the older version were checked
+    pub recipes_checked: usize,
+    pub broken_recipes: Vec<BrokenRecipe>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    // The previous version by default
+    pub from: Option<i32>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+fn compute_file_checksum(path: &Path) -> anyhow::Result<String> {
+    let mut file = match fs::File::open(path) {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!("Failed to open {}. Error: {}", path.display(), e);
+            anyhow::bail!(message);
+        }
+    };
+    let mut context = md5::Context::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => context.consume(&buffer[..n]),
+            Err(e) => {
+                let message = format!("Failed to read {}. Error: {}", path.display(), e);
+                anyhow::bail!(message);
+            }
+        }
+    }
+    Ok(format!("{:x}", context.compute()))
+}
+
+// Relative path -> md5, for every file under `dir`
+fn collect_checksums(
+    dir: &Path,
+    relative_dir: &str,
+    checksums: &mut BTreeMap<String, String>,
+) -> anyhow::Result<()> {
+    let (dirs, files) = read_dir_sorted(dir)?;
+    let join = |name: &str| {
+        if relative_dir.is_empty() {
+            String::from(name)
+        } else {
+            format!("{}/{}", relative_dir, name)
+        }
+    };
+
+    for file in &files {
+        let checksum = compute_file_checksum(&dir.join(file))?;
+        checksums.insert(join(file), checksum);
+    }
+    for sub_dir in &dirs {
+        collect_checksums(&dir.join(sub_dir), &join(sub_dir), checksums)?;
+    }
+    Ok(())
+}
+
+fn get_version_checksums(version: i32) -> anyhow::Result<Arc<Checksums>> {
+    {
+        let mut cache = CHECKSUMS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(position) = cache.iter().position(|(v, _)| *v == version) {
+            let cached = cache.remove(position);
+            let checksums = cached.1.clone();
+            cache.push(cached);
+            return Ok(checksums);
+        }
+    }
+
+    // NB: computed without holding the lock, two requests might end up doing it twice
+    let entry_point = get_entry_point_path(version)?;
+    let mut checksums = BTreeMap::new();
+    collect_checksums(&entry_point, "", &mut checksums)?;
+    let checksums = Arc::new(checksums);
+
+    let mut cache = CHECKSUMS.lock().unwrap_or_else(|e| e.into_inner());
+    cache.retain(|(v, _)| *v != version);
+    cache.push((version, checksums.clone()));
+    if cache.len() > DIFF_CACHED_VERSIONS {
+        let evicted = cache.len() - DIFF_CACHED_VERSIONS;
+        cache.drain(..evicted);
+    }
+    Ok(checksums)
+}
+
+// Pair the removed and added images that have the same content.
+// If the same content was removed or added more than once, the paths are paired in order.
+fn find_renames(
+    removed: &BTreeSet<String>,
+    added: &BTreeSet<String>,
+    from: &BTreeMap<String, String>,
+    to: &BTreeMap<String, String>,
+) -> Vec<RenamedLayer> {
+    let mut added_by_checksum: BTreeMap<&str, Vec<&String>> = BTreeMap::new();
+    for path in added {
+        added_by_checksum
+            .entry(to[path].as_str())
+            .or_default()
+            .push(path);
+    }
+    // Consumed from the front, so that the pairing follows the order of the paths
+    for candidates in added_by_checksum.values_mut() {
+        candidates.reverse();
+    }
+
+    let mut renames = Vec::new();
+    for path in removed {
+        if let Some(candidates) = added_by_checksum.get_mut(from[path].as_str()) {
+            if let Some(new_path) = candidates.pop() {
+                renames.push(RenamedLayer {
+                    from: path.clone(),
+                    to: new_path.clone(),
+                });
+            }
+        }
+    }
+    renames
+}
+
+// Blocking: every image of both versions is read (once)
+pub fn diff_versions(from_version: i32, to_version: i32) -> anyhow::Result<VersionDiff> {
+    let from = get_version_checksums(from_version)?;
+    let to = get_version_checksums(to_version)?;
+    let recipes = load_registered_recipes(from_version)?;
+    Ok(compare_versions(
+        from_version,
+        to_version,
+        &from,
+        &to,
+        recipes,
+    ))
+}
+
+// The recipes are the ones of the older version
+fn compare_versions(
+    from_version: i32,
+    to_version: i32,
+    from: &Checksums,
+    to: &Checksums,
+    recipes: Vec<RegisteredRecipe>,
+) -> VersionDiff {
+    let mut removed: BTreeSet<String> = from
+        .keys()
+        .filter(|p| !to.contains_key(*p))
+        .cloned()
+        .collect();
+    let mut added: BTreeSet<String> = to
+        .keys()
+        .filter(|p| !from.contains_key(*p))
+        .cloned()
+        .collect();
+    let changed: Vec<String> = from
+        .iter()
+        .filter(|(path, checksum)| matches!(to.get(*path), Some(c) if c != *checksum))
+        .map(|(path, _)| path.clone())
+        .collect();
+    let unchanged = from
+        .iter()
+        .filter(|(path, checksum)| to.get(*path) == Some(*checksum))
+        .count();
+
+    let renamed = find_renames(&removed, &added, from, to);
+    for rename in &renamed {
+        removed.remove(&rename.from);
+        added.remove(&rename.to);
+    }
+
+    // What happened to every layer that can't be used as it is any more
+    let mut layer_changes: BTreeMap<&str, LayerChange> = BTreeMap::new();
+    for path in &removed {
+        layer_changes.insert(
+            path,
+            LayerChange {
+                layer: path.clone(),
+                change: LayerChangeKind::Removed,
+                renamed_to: None,
+            },
+        );
+    }
+    for rename in &renamed {
+        layer_changes.insert(
+            &rename.from,
+            LayerChange {
+                layer: rename.from.clone(),
+                change: LayerChangeKind::Renamed,
+                renamed_to: Some(rename.to.clone()),
+            },
+        );
+    }
+    for path in &changed {
+        layer_changes.insert(
+            path,
+            LayerChange {
+                layer: path.clone(),
+                change: LayerChangeKind::Changed,
+                renamed_to: None,
+            },
+        );
+    }
+
+    let recipes_checked = recipes.len();
+    let broken_recipes = recipes
+        .into_iter()
+        .filter_map(|recipe| {
+            let changes: Vec<LayerChange> = recipe
+                .layers
+                .iter()
+                .filter_map(|layer| layer_changes.get(layer.as_str()).cloned())
+                .collect();
+            if changes.is_empty() {
+                return None;
+            }
+            Some(BrokenRecipe {
+                checksum: recipe.checksum,
+                created_at: recipe.created_at,
+                changes,
+            })
+        })
+        .collect();
+
+    VersionDiff {
+        from_version,
+        to_version,
+        added: added.into_iter().collect(),
+        removed: removed.into_iter().collect(),
+        renamed,
+        changed,
+        unchanged,
+        recipes_checked,
+        broken_recipes,
+    }
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+// What changed in a version since an older one
+pub async fn get_version_diff(
+    UrlPath(version): UrlPath<i32>,
+    query: Query<DiffQuery>,
+) -> Result<Json<VersionDiff>, (StatusCode, String)> {
+    let to_version = get_requested_version(Some(version)).await?;
+    let previous_version = match query.from {
+        Some(v) => Some(v),
+        None => run_blocking(move || get_previous_version(to_version))
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+    };
+    let from_version = match previous_version {
+        Some(v) => get_requested_version(Some(v)).await?,
+        None => {
+            let message = format!(
+                "Version {:0ZFILL_PADDING$} has no previous version to compare with",
+                to_version
+            );
+            return Err((StatusCode::BAD_REQUEST, message));
+        }
+    };
+    if from_version == to_version {
+        let message = format!(
+            "Can't compare version {:0ZFILL_PADDING$} with itself",
+            to_version
+        );
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+
+    let diff = tokio::task::spawn_blocking(move || diff_versions(from_version, to_version))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?;
+
+    Ok(Json(diff))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::core::test_utils::TestDir;
+
+    // Relative path -> content
+    fn checksums_of(files: &[(&str, &str)]) -> Checksums {
+        let dir = TestDir::new("diff");
+        for (relative, content) in files {
+            fs::write(dir.add_file(relative), content).unwrap();
+        }
+        let mut checksums = BTreeMap::new();
+        collect_checksums(&dir.path, "", &mut checksums).unwrap();
+        checksums
+    }
+
+    fn recipe(checksum: &str, layers: &[&str]) -> RegisteredRecipe {
+        RegisteredRecipe {
+            checksum: String::from(checksum),
+            layers: layers.iter().map(|l| String::from(*l)).collect(),
+            created_at: String::from("2022-11-02T10:00:00+00:00"),
+        }
+    }
+
+    #[test]
+    fn lists_the_recipes_a_new_version_breaks() {
+        let from = checksums_of(&[
+            ("01_background/Background_C_01.png", "blue"),
+            ("01_background/Background_C_02.png", "green"),
+            ("02_body_skins/Body_Skin_Standard_pink.png", "pink"),
+            ("02_body_skins/Body_Skin_Tiger_zebra.png", "zebra"),
+        ]);
+        let to = checksums_of(&[
+            ("01_background/Background_C_01.png", "blue"),
+            ("01_background/Background_C_03.png", "green"),
+            ("02_body_skins/Body_Skin_Standard_pink.png", "pinker"),
+            ("02_body_skins/Body_Skin_Sphynx_grey.png", "grey"),
+        ]);
+        let recipes = vec![
+            recipe("aaa", &["01_background/Background_C_01.png"]),
+            recipe(
+                "bbb",
+                &[
+                    "01_background/Background_C_02.png",
+                    "02_body_skins/Body_Skin_Tiger_zebra.png",
+                ],
+            ),
+            recipe(
+                "ccc",
+                &[
+                    "01_background/Background_C_01.png",
+                    "02_body_skins/Body_Skin_Standard_pink.png",
+                ],
+            ),
+        ];
+
+        let diff = compare_versions(1, 2, &from, &to, recipes);
+        assert_eq!(diff.added, vec!["02_body_skins/Body_Skin_Sphynx_grey.png"]);
+        assert_eq!(
+            diff.removed,
+            vec!["02_body_skins/Body_Skin_Tiger_zebra.png"]
+        );
+        assert_eq!(diff.renamed.len(), 1);
+        assert_eq!(diff.renamed[0].from, "01_background/Background_C_02.png");
+        assert_eq!(diff.renamed[0].to, "01_background/Background_C_03.png");
+        assert_eq!(
+            diff.changed,
+            vec!["02_body_skins/Body_Skin_Standard_pink.png"]
+        );
+        assert_eq!(diff.unchanged, 1);
+
+        assert_eq!(diff.recipes_checked, 3);
+        let broken: Vec<(&str, Vec<LayerChangeKind>)> = diff
+            .broken_recipes
+            .iter()
+            .map(|r| {
+                let changes = r.changes.iter().map(|c| c.change).collect();
+                (r.checksum.as_str(), changes)
+            })
+            .collect();
+        assert_eq!(
+            broken,
+            vec![
+                (
+                    "bbb",
+                    vec![LayerChangeKind::Renamed, LayerChangeKind::Removed]
+                ),
+                ("ccc", vec![LayerChangeKind::Changed]),
+            ]
+        );
+        assert_eq!(
+            diff.broken_recipes[0].changes[0].renamed_to.as_deref(),
+            Some("01_background/Background_C_03.png")
+        );
+    }
+
+    #[test]
+    fn pairs_the_renames_in_order() {
+        let from = checksums_of(&[("a/1.png", "same"), ("a/2.png", "same")]);
+        let to = checksums_of(&[
+            ("b/1.png", "same"),
+            ("b/2.png", "same"),
+            ("b/3.png", "same"),
+        ]);
+
+        let diff = compare_versions(1, 2, &from, &to, vec![]);
+        let renamed: Vec<(&str, &str)> = diff
+            .renamed
+            .iter()
+            .map(|r| (r.from.as_str(), r.to.as_str()))
+            .collect();
+        assert_eq!(
+            renamed,
+            vec![("a/1.png", "b/1.png"), ("a/2.png", "b/2.png")]
+        );
+        assert_eq!(diff.added, vec!["b/3.png"]);
+        assert!(diff.removed.is_empty());
+    }
+}
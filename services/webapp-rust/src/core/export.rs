@@ -0,0 +1,184 @@
+// Export of a finished batch as a self-contained .tar.gz:
+//
+//   sphynx_<batch_id>/
+//     batch.json           -> the record of the batch
+//     images/00001.png     -> the rendered images
+//     recipes/00001.json   -> the layers every image is made of
+//     metadata/00001.json  -> the NFT metadata of every image
+//
+// Items are numbered sequentially in the archive, skipping the ones that failed to render.
+// The tarball is built on a blocking thread and sent to the client chunk by chunk through
+// a bounded channel, so that only a few chunks are ever held in memory.
+
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::Path as UrlPath,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tar::{Builder, Header};
+use tokio::sync::mpsc;
+
+use crate::core::batches::{get_item_image_path, load_batch, load_batch_recipes, BatchStatus};
+use crate::core::constants::{EXPORT_CHANNEL_CAPACITY, EXPORT_CHUNK_SIZE};
+use crate::core::metadata::build_metadata;
+use crate::core::repository::run_blocking;
+
+type ExportChunk = Result<Bytes, io::Error>;
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+// Sends everything written to it down a channel
+struct ChannelWriter {
+    sender: mpsc::Sender<ExportChunk>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.sender.blocking_send(Ok(Bytes::copy_from_slice(buf))) {
+            Ok(_) => Ok(buf.len()),
+            // The client went away, there's no point in going on
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The client has disconnected",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+fn append_bytes<W: Write>(builder: &mut Builder<W>, path: &str, data: &[u8]) -> io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)
+}
+
+fn to_io_error<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+fn write_export<W: Write>(batch_id: &str, writer: W) -> io::Result<W> {
+    let record = match load_batch(batch_id).map_err(to_io_error)? {
+        Some(r) => r,
+        None => {
+            let message = format!("Batch {} doesn't exist.", batch_id);
+            return Err(io::Error::new(io::ErrorKind::NotFound, message));
+        }
+    };
+    let recipes = load_batch_recipes(batch_id).map_err(to_io_error)?;
+
+    let root = format!("sphynx_{}", batch_id);
+    let mut builder = Builder::new(GzEncoder::new(writer, Compression::default()));
+
+    let batch_json = serde_json::to_vec_pretty(&record).map_err(to_io_error)?;
+    append_bytes(&mut builder, &format!("{}/batch.json", root), &batch_json)?;
+
+    let mut number = 0;
+    for (position, recipe) in recipes.iter().enumerate() {
+        let image_path = get_item_image_path(batch_id, position + 1);
+        if !image_path.exists() {
+            continue;
+        }
+        number += 1;
+
+        let image_file_name = format!("{:05}.png", number);
+        builder.append_path_with_name(
+            &image_path,
+            Path::new(&root).join("images").join(&image_file_name),
+        )?;
+
+        let recipe_json = serde_json::to_vec_pretty(&recipe.layers).map_err(to_io_error)?;
+        append_bytes(
+            &mut builder,
+            &format!("{}/recipes/{:05}.json", root, number),
+            &recipe_json,
+        )?;
+
+        // Written again, since the numbering might differ from the one of the batch
+        let metadata = build_metadata(
+            &format!("#{}", number),
+            recipe,
+            record.metadata_base_uri.as_deref(),
+            &image_file_name,
+        );
+        let metadata_json = serde_json::to_vec_pretty(&metadata).map_err(to_io_error)?;
+        append_bytes(
+            &mut builder,
+            &format!("{}/metadata/{:05}.json", root, number),
+            &metadata_json,
+        )?;
+    }
+
+    builder.into_inner()?.finish()
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+pub async fn export_batch(
+    UrlPath(batch_id): UrlPath<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let load_batch_id = batch_id.clone();
+    let record = match run_blocking(move || load_batch(&load_batch_id)).await {
+        Ok(Some(r)) => r,
+        Ok(None) => {
+            let message = format!("Batch {} doesn't exist.", batch_id);
+            return Err((StatusCode::NOT_FOUND, message));
+        }
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+    if record.status != BatchStatus::COMPLETED {
+        let message = format!(
+            "Batch {} is {:?}, only completed batches can be exported",
+            batch_id, record.status
+        );
+        return Err((StatusCode::CONFLICT, message));
+    }
+
+    let (sender, mut receiver) = mpsc::channel::<ExportChunk>(EXPORT_CHANNEL_CAPACITY);
+
+    let export_batch_id = batch_id.clone();
+    tokio::task::spawn_blocking(move || {
+        let error_sender = sender.clone();
+        let writer = BufWriter::with_capacity(EXPORT_CHUNK_SIZE, ChannelWriter { sender });
+        let result = write_export(&export_batch_id, writer).and_then(|mut w| w.flush());
+        if let Err(e) = result {
+            eprintln!("Export of batch {} failed. {}", export_batch_id, e);
+            // Make the download fail, instead of leaving a truncated file that looks complete
+            let _ = error_sender.blocking_send(Err(e));
+        }
+    });
+
+    let stream = futures::stream::poll_fn(move |cx| receiver.poll_recv(cx));
+
+    let file_name = format!("sphynx_{}.tar.gz", batch_id);
+    Ok((
+        [
+            (header::CONTENT_TYPE, String::from("application/gzip")),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file_name),
+            ),
+        ],
+        StreamBody::new(stream),
+    )
+        .into_response())
+}
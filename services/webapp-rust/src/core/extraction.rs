@@ -0,0 +1,714 @@
+// Extraction of the uploaded archives, which can't be trusted.
+// Every entry is checked before anything is written, and the whole archive is rejected
+// (and what was already extracted removed by the ingest) if any of them:
+//
+//   - has an absolute path or a '..' component
+//   - is a symlink or hardlink pointing outside of the directory of the version
+//   - is a device file, a FIFO or a socket
+//   - makes the archive exceed EXTRACT_MAX_ENTRIES or EXTRACT_MAX_SIZE once uncompressed
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use tar::Archive;
+use zip::ZipArchive;
+
+use crate::core::archive_format::{
+    detect_format, read_header, unsupported_format_message, zstd_decoder, ArchiveFormat,
+};
+use crate::core::constants::{EXTRACT_MAX_ENTRIES, EXTRACT_MAX_SIZE};
+
+// File types, from the unix mode of the zip entries
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+// Symlinks are never longer than this
+const ZIP_SYMLINK_MAX_LEN: u64 = 4096;
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+struct Extraction {
+    // Canonical, so that the resolved links can be compared with it
+    root: PathBuf,
+    entries: usize,
+    size: u64,
+    // EXTRACT_MAX_ENTRIES and EXTRACT_MAX_SIZE, unless overridden
+    max_entries: usize,
+    max_size: u64,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+// The path of an entry relative to the root, without '.' components.
+// Absolute paths and '..' components are refused.
+fn check_entry_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                let message = format!("{} has a '..' component", path.display());
+                anyhow::bail!(message);
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                let message = format!("{} is an absolute path", path.display());
+                anyhow::bail!(message);
+            }
+        }
+    }
+    Ok(relative)
+}
+
+// Whether `target`, relative to `base` (itself relative to the root), stays inside of the root.
+// NB: this is only lexical, links are resolved once everything has been extracted
+fn is_link_inside(base: &Path, target: &Path) -> bool {
+    let mut depth: usize = 0;
+    for component in base.components().chain(target.components()) {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if depth == 0 {
+                    return false;
+                }
+                depth -= 1;
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+fn check_link(entry_path: &Path, target: &Path, is_symlink: bool) -> anyhow::Result<()> {
+    // Symlinks are relative to their directory, hardlinks to the root of the archive
+    let base = match (is_symlink, entry_path.parent()) {
+        (true, Some(parent)) => parent,
+        _ => Path::new(""),
+    };
+    if !is_link_inside(base, target) {
+        let message = format!(
+            "{} links to {}, which is outside of the archive",
+            entry_path.display(),
+            target.display()
+        );
+        anyhow::bail!(message);
+    }
+    Ok(())
+}
+
+impl Extraction {
+    fn new(extraction_path: &Path) -> anyhow::Result<Extraction> {
+        Extraction::with_limits(extraction_path, EXTRACT_MAX_ENTRIES, EXTRACT_MAX_SIZE)
+    }
+
+    fn with_limits(
+        extraction_path: &Path,
+        max_entries: usize,
+        max_size: u64,
+    ) -> anyhow::Result<Extraction> {
+        match fs::create_dir_all(extraction_path) {
+            Ok(_) => {}
+            Err(e) => {
+                let message = format!(
+                    "Failed to create {}. Error: {}",
+                    extraction_path.display(),
+                    e
+                );
+                anyhow::bail!(message);
+            }
+        }
+        let root = match extraction_path.canonicalize() {
+            Ok(r) => r,
+            Err(e) => {
+                let message = format!(
+                    "Failed to resolve {}. Error: {}",
+                    extraction_path.display(),
+                    e
+                );
+                anyhow::bail!(message);
+            }
+        };
+        Ok(Extraction {
+            root,
+            entries: 0,
+            size: 0,
+            max_entries,
+            max_size,
+        })
+    }
+
+    fn count_entry(&mut self) -> anyhow::Result<()> {
+        self.entries += 1;
+        if self.entries > self.max_entries {
+            let message = format!(
+                "it has more than {} files and directories",
+                self.max_entries
+            );
+            anyhow::bail!(message);
+        }
+        Ok(())
+    }
+
+    fn count_size(&mut self, path: &Path, size: u64) -> anyhow::Result<()> {
+        self.size += size;
+        if self.size > self.max_size {
+            let message = format!(
+                "it's bigger than {} bytes once uncompressed (reached at {})",
+                self.max_size,
+                path.display()
+            );
+            anyhow::bail!(message);
+        }
+        Ok(())
+    }
+
+    // Where the entry goes, with its parent resolved: links already extracted are followed,
+    // and none of them can lead outside
+    fn resolve_destination(&self, relative: &Path) -> anyhow::Result<PathBuf> {
+        let destination = self.root.join(relative);
+        let (parent, file_name) = match (destination.parent(), destination.file_name()) {
+            (Some(p), Some(f)) => (p, f),
+            _ => {
+                let message = format!("{} is not a valid path", relative.display());
+                anyhow::bail!(message);
+            }
+        };
+        match fs::create_dir_all(parent) {
+            Ok(_) => {}
+            Err(e) => {
+                let message = format!("Failed to create {}. Error: {}", parent.display(), e);
+                anyhow::bail!(message);
+            }
+        }
+        match parent.canonicalize() {
+            Ok(p) if p.starts_with(&self.root) => Ok(p.join(file_name)),
+            _ => {
+                let message = format!("{} is written through a link", relative.display());
+                anyhow::bail!(message);
+            }
+        }
+    }
+
+    // Same, but the entry can't replace anything (EG: a link planted by a previous entry)
+    fn prepare_destination(&self, relative: &Path) -> anyhow::Result<PathBuf> {
+        let destination = self.resolve_destination(relative)?;
+        if fs::symlink_metadata(&destination).is_ok() {
+            let message = format!("{} is in the archive more than once", relative.display());
+            anyhow::bail!(message);
+        }
+        Ok(destination)
+    }
+
+    // Links are relative to where they really are, not to the path of their entry
+    fn check_resolved_link(&self, destination: &Path, target: &Path) -> anyhow::Result<()> {
+        let relative = destination.strip_prefix(&self.root).unwrap_or(destination);
+        check_link(relative, target, true)
+    }
+
+    // Links are checked one by one while extracting, but a chain of links can still
+    // resolve to somewhere else: check where they end up once they all exist
+    fn check_extracted_links(&self, dir: &Path) -> anyhow::Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(r) => r,
+            Err(e) => {
+                let message = format!("Failed to read directory {}. Error: {}", dir.display(), e);
+                anyhow::bail!(message);
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            if file_type.is_symlink() {
+                let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+                match path.canonicalize() {
+                    Ok(p) if p.starts_with(&self.root) => {}
+                    Ok(_) => {
+                        let message = format!(
+                            "{} resolves to a path outside of the archive",
+                            relative.display()
+                        );
+                        anyhow::bail!(message);
+                    }
+                    Err(_) => {
+                        let message = format!(
+                            "{} links to something that isn't in the archive",
+                            relative.display()
+                        );
+                        anyhow::bail!(message);
+                    }
+                }
+            } else if file_type.is_dir() {
+                self.check_extracted_links(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn unpack_tar<R: Read>(&mut self, reader: R) -> anyhow::Result<()> {
+        let mut archive = Archive::new(reader);
+        let archive_entries = match archive.entries() {
+            Ok(r) => r,
+            Err(e) => {
+                let message = format!("Failed to read tar archive: {}", e);
+                anyhow::bail!(message);
+            }
+        };
+
+        for entry in archive_entries {
+            let mut entry = match entry {
+                Ok(r) => r,
+                Err(e) => {
+                    let message = format!("Failed to read tar archive: {}", e);
+                    anyhow::bail!(message);
+                }
+            };
+            self.count_entry()?;
+
+            let path = match entry.path() {
+                Ok(p) => p.into_owned(),
+                Err(e) => {
+                    let message = format!("Invalid path in tar archive: {}", e);
+                    anyhow::bail!(message);
+                }
+            };
+            let relative = check_entry_path(&path)?;
+
+            let entry_type = entry.header().entry_type();
+            if entry_type.is_symlink() || entry_type.is_hard_link() {
+                let target = match entry.link_name() {
+                    Ok(Some(t)) => t.into_owned(),
+                    _ => {
+                        let message = format!("{} is a link without a target", path.display());
+                        anyhow::bail!(message);
+                    }
+                };
+                check_link(&relative, &target, entry_type.is_symlink())?;
+                if entry_type.is_symlink() {
+                    let destination = self.resolve_destination(&relative)?;
+                    self.check_resolved_link(&destination, &target)?;
+                }
+            } else if entry_type.is_file() || entry_type.is_contiguous() {
+                self.count_size(&path, entry.header().size().unwrap_or(0))?;
+            } else if entry_type.is_character_special()
+                || entry_type.is_block_special()
+                || entry_type.is_fifo()
+            {
+                let message = format!("{} is a device file or a FIFO", path.display());
+                anyhow::bail!(message);
+            } else if !entry_type.is_dir() {
+                // EG: global pax headers, sparse files
+                eprintln!("Skipping {} ({:?})", path.display(), entry_type);
+                continue;
+            }
+
+            // The 'tar' crate runs its own checks too, and resolves the hardlinks
+            match entry.unpack_in(&self.root) {
+                Ok(true) => {}
+                Ok(false) => {
+                    let message = format!("{} can't be extracted safely", path.display());
+                    anyhow::bail!(message);
+                }
+                Err(e) => {
+                    let message = format!("Failed to extract {}: {}", path.display(), e);
+                    anyhow::bail!(message);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unpack_zip(&mut self, file: File) -> anyhow::Result<()> {
+        let mut archive = match ZipArchive::new(BufReader::new(file)) {
+            Ok(r) => r,
+            Err(e) => {
+                let message = format!("Failed to read zip archive: {}", e);
+                anyhow::bail!(message);
+            }
+        };
+
+        for index in 0..archive.len() {
+            let mut entry = match archive.by_index(index) {
+                Ok(r) => r,
+                Err(e) => {
+                    let message = format!("Failed to read zip archive: {}", e);
+                    anyhow::bail!(message);
+                }
+            };
+            self.count_entry()?;
+
+            // Archives made on Windows can use backslashes
+            let name = entry.name().replace('\\', "/");
+            let path = PathBuf::from(&name);
+            let relative = check_entry_path(&path)?;
+            if relative.as_os_str().is_empty() {
+                continue;
+            }
+
+            let file_type = match entry.unix_mode() {
+                Some(mode) if mode & S_IFMT != 0 => mode & S_IFMT,
+                // Made on Windows, or without the type in the mode: only directories and files
+                _ if entry.is_dir() => S_IFDIR,
+                _ => S_IFREG,
+            };
+            let is_dir = entry.is_dir() || file_type == S_IFDIR;
+            if is_dir {
+                let destination = self.resolve_destination(&relative)?;
+                match fs::symlink_metadata(&destination) {
+                    Ok(metadata) if !metadata.is_dir() => {
+                        let message = format!("{} is in the archive more than once", name);
+                        anyhow::bail!(message);
+                    }
+                    _ => {}
+                }
+                match fs::create_dir_all(&destination) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        let message = format!("Failed to extract {}: {}", name, e);
+                        anyhow::bail!(message);
+                    }
+                }
+                continue;
+            }
+
+            if file_type == S_IFLNK {
+                let mut target = String::new();
+                match (&mut entry)
+                    .take(ZIP_SYMLINK_MAX_LEN)
+                    .read_to_string(&mut target)
+                {
+                    Ok(_) => {}
+                    Err(e) => {
+                        let message = format!("Failed to read the link {}: {}", name, e);
+                        anyhow::bail!(message);
+                    }
+                }
+                check_link(&relative, Path::new(&target), true)?;
+                let destination = self.prepare_destination(&relative)?;
+                self.check_resolved_link(&destination, Path::new(&target))?;
+                match std::os::unix::fs::symlink(&target, &destination) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        let message = format!("Failed to extract {}: {}", name, e);
+                        anyhow::bail!(message);
+                    }
+                }
+                continue;
+            }
+
+            if file_type != S_IFREG {
+                let message = format!("{} is a device file, a FIFO or a socket", name);
+                anyhow::bail!(message);
+            }
+
+            // The size declared by the archive can't be trusted: count what is written
+            // NB: never through a link, even if one got there in the meantime
+            let destination = self.prepare_destination(&relative)?;
+            let output = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&destination);
+            let mut output = match output {
+                Ok(r) => r,
+                Err(e) => {
+                    let message = format!("Failed to extract {}: {}", name, e);
+                    anyhow::bail!(message);
+                }
+            };
+            let remaining = self.max_size - self.size;
+            let written = match io::copy(&mut (&mut entry).take(remaining + 1), &mut output) {
+                Ok(r) => r,
+                Err(e) => {
+                    let message = format!("Failed to extract {}: {}", name, e);
+                    anyhow::bail!(message);
+                }
+            };
+            self.count_size(&path, written)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Blocking: none of the crates support async
+pub fn unpack(archive_path: &Path, extraction_path: &Path) -> anyhow::Result<ArchiveFormat> {
+    let header = read_header(archive_path)?;
+    let format = match detect_format(&header) {
+        Some(r) => r,
+        None => {
+            let message = unsupported_format_message(&archive_path.display().to_string());
+            anyhow::bail!(message);
+        }
+    };
+    let file = match File::open(archive_path) {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!("Failed to open {}: {}", archive_path.display(), e);
+            anyhow::bail!(message);
+        }
+    };
+
+    let mut extraction = Extraction::new(extraction_path)?;
+    let result = match format {
+        ArchiveFormat::TarGz => extraction.unpack_tar(GzDecoder::new(file)),
+        ArchiveFormat::TarZst => extraction.unpack_tar(zstd_decoder(file)?),
+        ArchiveFormat::Tar => extraction.unpack_tar(file),
+        ArchiveFormat::Zip => extraction.unpack_zip(file),
+    };
+    let root = extraction.root.clone();
+    let result = result.and_then(|_| extraction.check_extracted_links(&root));
+    if let Err(e) = result {
+        let message = format!("The archive was rejected: {}", e);
+        anyhow::bail!(message);
+    }
+
+    eprintln!(
+        "Extracted {} entries ({} bytes)",
+        extraction.entries, extraction.size
+    );
+    Ok(format)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use tar::{Builder, EntryType, Header};
+    use zip::write::{FileOptions, ZipWriter};
+
+    use super::*;
+    use crate::core::test_utils::TestDir;
+
+    // NB: the name is written as is, since the 'tar' crate refuses to write unsafe paths
+    fn header(name: &str, entry_type: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_cksum();
+        header
+    }
+
+    fn file(name: &str, data: &[u8]) -> (Header, Vec<u8>) {
+        (
+            header(name, EntryType::Regular, data.len() as u64),
+            data.to_vec(),
+        )
+    }
+
+    fn link(name: &str, target: &str, entry_type: EntryType) -> (Header, Vec<u8>) {
+        let mut header = header(name, entry_type, 0);
+        header.set_link_name(target).unwrap();
+        header.set_cksum();
+        (header, vec![])
+    }
+
+    fn build_tar(entries: Vec<(Header, Vec<u8>)>) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for (header, data) in entries {
+            builder.append(&header, data.as_slice()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn extract_tar(
+        entries: Vec<(Header, Vec<u8>)>,
+        max_entries: usize,
+        max_size: u64,
+    ) -> anyhow::Result<()> {
+        let dir = TestDir::new("extraction");
+        let mut extraction = Extraction::with_limits(&dir.path, max_entries, max_size)?;
+        extraction.unpack_tar(Cursor::new(build_tar(entries)))?;
+        let root = extraction.root.clone();
+        extraction.check_extracted_links(&root)
+    }
+
+    fn extract_zip(build: impl FnOnce(&mut ZipWriter<File>)) -> anyhow::Result<()> {
+        extract_zip_in(&TestDir::new("extraction"), build)
+    }
+
+    // The archive is extracted in <dir>/extracted
+    fn extract_zip_in(
+        dir: &TestDir,
+        build: impl FnOnce(&mut ZipWriter<File>),
+    ) -> anyhow::Result<()> {
+        fs::create_dir_all(&dir.path)?;
+        let zip_path = dir.path.join("archive.zip");
+        let mut writer = ZipWriter::new(File::create(&zip_path)?);
+        build(&mut writer);
+        writer.finish()?;
+
+        let mut extraction = Extraction::new(&dir.path.join("extracted"))?;
+        extraction.unpack_zip(File::open(&zip_path)?)?;
+        let root = extraction.root.clone();
+        extraction.check_extracted_links(&root)
+    }
+
+    fn assert_rejected(result: anyhow::Result<()>, reason: &str) {
+        match result {
+            Ok(_) => panic!("The archive should have been rejected ({})", reason),
+            Err(e) => assert!(
+                e.to_string().contains(reason),
+                "'{}' doesn't contain '{}'",
+                e,
+                reason
+            ),
+        }
+    }
+
+    #[test]
+    fn entry_paths() {
+        assert_eq!(
+            check_entry_path(Path::new("./a/./b.png")).unwrap(),
+            PathBuf::from("a/b.png")
+        );
+        assert!(check_entry_path(Path::new("a/../../b")).is_err());
+        assert!(check_entry_path(Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn links_inside() {
+        assert!(is_link_inside(Path::new("a"), Path::new("../b")));
+        assert!(is_link_inside(Path::new("a/b"), Path::new("../c/../../d")));
+        assert!(!is_link_inside(Path::new("a"), Path::new("../../b")));
+        assert!(!is_link_inside(Path::new(""), Path::new("..")));
+        assert!(!is_link_inside(Path::new("a"), Path::new("/etc/passwd")));
+    }
+
+    #[test]
+    fn extracts_a_sound_archive() {
+        let entries = vec![
+            file("program/01_background/blue.png", b"blue"),
+            link(
+                "program/latest.png",
+                "01_background/blue.png",
+                EntryType::Symlink,
+            ),
+            link(
+                "program/copy.png",
+                "program/01_background/blue.png",
+                EntryType::Link,
+            ),
+        ];
+        extract_tar(entries, 10, 100).unwrap();
+    }
+
+    #[test]
+    fn rejects_unsafe_paths() {
+        let entries = vec![file("../evil.png", b"evil")];
+        assert_rejected(extract_tar(entries, 10, 100), "'..'");
+
+        let entries = vec![file("/tmp/evil.png", b"evil")];
+        assert_rejected(extract_tar(entries, 10, 100), "absolute path");
+    }
+
+    #[test]
+    fn rejects_links_leading_outside() {
+        let entries = vec![link("a/passwd", "../../etc/passwd", EntryType::Symlink)];
+        assert_rejected(extract_tar(entries, 10, 100), "outside of the archive");
+
+        let entries = vec![link("passwd", "/etc/passwd", EntryType::Symlink)];
+        assert_rejected(extract_tar(entries, 10, 100), "outside of the archive");
+
+        let entries = vec![link("a/passwd", "../etc/passwd", EntryType::Link)];
+        assert_rejected(extract_tar(entries, 10, 100), "outside of the archive");
+    }
+
+    #[test]
+    fn rejects_chains_of_links_leading_outside() {
+        // Both are inside on their own, but 'up' resolves to the root
+        let entries = vec![
+            link("a/up", "..", EntryType::Symlink),
+            link("escape", "a/up/..", EntryType::Symlink),
+        ];
+        assert_rejected(extract_tar(entries, 10, 100), "resolves to a path outside");
+    }
+
+    #[test]
+    fn rejects_special_files() {
+        for entry_type in [EntryType::Fifo, EntryType::Char, EntryType::Block] {
+            let entries = vec![(header("device", entry_type, 0), vec![])];
+            assert_rejected(extract_tar(entries, 10, 100), "device file or a FIFO");
+        }
+    }
+
+    #[test]
+    fn enforces_the_limits() {
+        let entries = vec![file("a", b"a"), file("b", b"b"), file("c", b"c")];
+        assert_rejected(extract_tar(entries, 2, 100), "more than 2 files");
+
+        let entries = vec![file("a", b"12345"), file("b", b"678901")];
+        assert_rejected(extract_tar(entries, 10, 10), "bigger than 10 bytes");
+    }
+
+    #[test]
+    fn rejects_unsafe_zip_entries() {
+        let result = extract_zip(|writer| {
+            writer
+                .start_file("../evil.png", FileOptions::default())
+                .unwrap();
+            writer.write_all(b"evil").unwrap();
+        });
+        assert_rejected(result, "'..'");
+
+        let result = extract_zip(|writer| {
+            writer
+                .add_symlink("a/passwd", "../../etc/passwd", FileOptions::default())
+                .unwrap();
+        });
+        assert_rejected(result, "outside of the archive");
+    }
+
+    #[test]
+    fn rejects_zip_files_written_through_links() {
+        let dir = TestDir::new("extraction");
+        let result = extract_zip_in(&dir, |writer| {
+            writer
+                .add_symlink("d", ".", FileOptions::default())
+                .unwrap();
+            writer
+                .add_symlink("d/l", "../pwned", FileOptions::default())
+                .unwrap();
+            writer.start_file("l", FileOptions::default()).unwrap();
+            writer.write_all(b"pwned").unwrap();
+        });
+        assert_rejected(result, "outside of the archive");
+        assert!(!dir.path.join("pwned").exists());
+    }
+
+    #[test]
+    fn rejects_tar_links_resolving_outside_through_links() {
+        let entries = vec![
+            link("d", ".", EntryType::Symlink),
+            link("d/l", "../pwned", EntryType::Symlink),
+        ];
+        assert_rejected(extract_tar(entries, 10, 100), "outside of the archive");
+    }
+
+    #[test]
+    fn rejects_zip_entries_replacing_others() {
+        let result = extract_zip(|writer| {
+            writer
+                .add_symlink("a.png", "b.png", FileOptions::default())
+                .unwrap();
+            writer.start_file("a.png", FileOptions::default()).unwrap();
+            writer.write_all(b"a").unwrap();
+        });
+        assert_rejected(result, "more than once");
+    }
+}
@@ -0,0 +1,358 @@
+// Tracking of the uploaded archives, from the moment they are received to the moment
+// they become a version that can be used (or fail trying):
+//
+//   SAVING -> EXTRACTING -> SANITIZING -> VALIDATING -> PUBLISHED
+//                                                    \-> FAILED (at any stage)
+//
+// Every ingest is stored in the repository, like the jobs.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use axum::{extract::Path as UrlPath, http::StatusCode, response::Json};
+use chrono::Utc;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::ZFILL_PADDING;
+use crate::core::repository::{repository, run_blocking};
+use crate::core::validation::{self, ValidationReport};
+use crate::core::{
+    extract_archive, get_archive_path, get_staging_path, load_versions_data, release_saved_version,
+    remove_extracted_archive, remove_tmp_archive, sanitize, versions, SavedArchive,
+};
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+// NB: the names follow the ones used by JobStatus
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum IngestStage {
+    SAVING,
+    EXTRACTING,
+    SANITIZING,
+    VALIDATING,
+    PUBLISHED,
+    FAILED,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestRecord {
+    pub ingest_id: String,
+    pub file_name: String,
+    // Known once the archive has been saved
+    pub size_bytes: Option<usize>,
+    // md5 of the archive, computed while it was being received
+    pub checksum: Option<String>,
+    pub stage: IngestStage,
+    pub created_at: String,
+    pub updated_at: String,
+    pub finished_at: Option<String>,
+    // The version the archive is saved as, usable only once published
+    pub archive_version: Option<i32>,
+    // How many files and directories were renamed or removed by the sanitization
+    pub sanitize_operations: Option<usize>,
+    pub validation: Option<ValidationReport>,
+    pub error: Option<String>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+fn now_rfc3339() -> String {
+    Utc::now().to_rfc3339()
+}
+
+// Ingest ids are uuids, don't let anybody read arbitrary paths
+fn is_valid_ingest_id(ingest_id: &str) -> bool {
+    !ingest_id.is_empty()
+        && ingest_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn save_ingest(record: &IngestRecord) -> anyhow::Result<()> {
+    repository()?.save_ingest(record)
+}
+
+// Returns None if no ingest with the given id was ever created
+pub fn load_ingest(ingest_id: &str) -> anyhow::Result<Option<IngestRecord>> {
+    if !is_valid_ingest_id(ingest_id) {
+        return Ok(None);
+    }
+    repository()?.load_ingest(ingest_id)
+}
+
+pub async fn create_ingest(ingest_id: &str, file_name: &str) -> anyhow::Result<IngestRecord> {
+    let now = now_rfc3339();
+    let record = IngestRecord {
+        ingest_id: String::from(ingest_id),
+        file_name: String::from(file_name),
+        size_bytes: None,
+        checksum: None,
+        stage: IngestStage::SAVING,
+        created_at: now.clone(),
+        updated_at: now,
+        finished_at: None,
+        archive_version: None,
+        sanitize_operations: None,
+        validation: None,
+        error: None,
+    };
+    run_blocking(move || {
+        save_ingest(&record)?;
+        Ok(record)
+    })
+    .await
+}
+
+// Load the ingest, apply the changes and save it back
+fn update_ingest<F>(ingest_id: &str, update: F) -> anyhow::Result<IngestRecord>
+where
+    F: FnOnce(&mut IngestRecord),
+{
+    let mut record = match load_ingest(ingest_id)? {
+        Some(r) => r,
+        None => {
+            let message = format!("Ingest {} doesn't exist.", ingest_id);
+            anyhow::bail!(message);
+        }
+    };
+    update(&mut record);
+    record.updated_at = now_rfc3339();
+    if record.stage == IngestStage::PUBLISHED || record.stage == IngestStage::FAILED {
+        record.finished_at = Some(record.updated_at.clone());
+    }
+    save_ingest(&record)?;
+    Ok(record)
+}
+
+// Failing to keep track of the progress shouldn't stop the ingest
+async fn set_ingest<F>(ingest_id: &str, update: F)
+where
+    F: FnOnce(&mut IngestRecord) + Send + 'static,
+{
+    let update_ingest_id = String::from(ingest_id);
+    match run_blocking(move || update_ingest(&update_ingest_id, update)).await {
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to update ingest {}. {}", ingest_id, e);
+        }
+    }
+}
+
+async fn set_stage(ingest_id: &str, stage: IngestStage) {
+    eprintln!("Ingest {} is now {:?}", ingest_id, stage);
+    set_ingest(ingest_id, move |record| record.stage = stage).await;
+}
+
+pub async fn set_ingest_saved(
+    ingest_id: &str,
+    archive: &SavedArchive,
+) -> anyhow::Result<IngestRecord> {
+    let version: i32 = archive.version.parse()?;
+    let size_bytes = archive.size_bytes;
+    let checksum = archive.checksum.clone();
+    let update_ingest_id = String::from(ingest_id);
+    run_blocking(move || {
+        update_ingest(&update_ingest_id, |record| {
+            record.stage = IngestStage::EXTRACTING;
+            record.archive_version = Some(version);
+            record.size_bytes = Some(size_bytes);
+            record.checksum = Some(checksum);
+        })
+    })
+    .await
+}
+
+pub async fn fail_ingest(ingest_id: &str, error: &str) {
+    eprintln!("Ingest {} failed. {}", ingest_id, error);
+    let error = String::from(error);
+    set_ingest(ingest_id, |record| {
+        record.stage = IngestStage::FAILED;
+        record.error = Some(error);
+    })
+    .await;
+}
+
+// Everything after the archive has been saved
+async fn process_ingest(ingest_id: &str, archive: &SavedArchive) -> anyhow::Result<()> {
+    // From here on, the archive must not be left around if something goes wrong
+    let result = publish_archive(ingest_id, &archive.path, &archive.version).await;
+    if result.is_err() {
+        remove_extracted_archive(&archive.version).await;
+    }
+    result
+}
+
+async fn publish_archive(
+    ingest_id: &str,
+    archive_path: &Path,
+    archive_version: &str,
+) -> anyhow::Result<()> {
+    extract_archive(archive_path, archive_version).await?;
+
+    set_stage(ingest_id, IngestStage::SANITIZING).await;
+    let extraction_path = get_staging_path(archive_version);
+    let sanitize_path = extraction_path.clone();
+    let sanitize_version = String::from(archive_version);
+    let manifest = tokio::task::spawn_blocking(move || {
+        sanitize::sanitize_directory(&sanitize_path, &sanitize_version)
+    })
+    .await??;
+    let sanitize_operations = manifest.operations.len();
+    set_ingest(ingest_id, move |record| {
+        record.sanitize_operations = Some(sanitize_operations)
+    })
+    .await;
+
+    // Only archives that are usable become a version
+    set_stage(ingest_id, IngestStage::VALIDATING).await;
+    let report =
+        tokio::task::spawn_blocking(move || validation::validate_archive(&extraction_path))
+            .await??;
+    let is_valid = report.valid;
+    let errors = validation::format_issues(&report.errors);
+    if !report.warnings.is_empty() {
+        eprintln!(
+            "Warnings about the archive:\n{}",
+            validation::format_issues(&report.warnings)
+        );
+    }
+    set_ingest(ingest_id, |record| record.validation = Some(report)).await;
+    if !is_valid {
+        let message = format!("The archive is not valid:\n{}", errors);
+        anyhow::bail!(message);
+    }
+
+    // Only now it leaves the staging directory and becomes the current version
+    let version = String::from(archive_version);
+    tokio::task::spawn_blocking(move || versions::publish_staged_version(&version)).await??;
+    Ok(())
+}
+
+// Runs in the background, the progress can be followed through the record of the ingest
+pub async fn run_ingest(ingest_id: String, archive: SavedArchive) {
+    match process_ingest(&ingest_id, &archive).await {
+        Ok(_) => {
+            eprintln!("Ingest {} published version {}", ingest_id, archive.version);
+            set_stage(&ingest_id, IngestStage::PUBLISHED).await;
+        }
+        Err(e) => fail_ingest(&ingest_id, &e.to_string()).await,
+    }
+
+    // Published or failed, either way the version isn't reserved any more
+    release_saved_version(&archive.version);
+    match remove_tmp_archive(&archive.path).await {
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to clean up tmp dir. {}", e);
+        }
+    }
+}
+
+// The archive was saved, but it will never be ingested: nothing must be left around
+pub async fn abandon_saved_archive(ingest_id: &str, archive: &SavedArchive, error: &str) {
+    fail_ingest(ingest_id, error).await;
+    release_saved_version(&archive.version);
+    match remove_tmp_archive(&archive.path).await {
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to clean up tmp dir. {}", e);
+        }
+    }
+}
+
+fn get_published_version() -> anyhow::Result<i32> {
+    Ok(load_versions_data()?.last_version)
+}
+
+fn remove_staged_archive(version: i32) {
+    let staging_path = get_staging_path(&format!("{:0ZFILL_PADDING$}", version));
+    if !staging_path.exists() {
+        return;
+    }
+    match std::fs::remove_dir_all(&staging_path) {
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to remove {}. Error: {}", staging_path.display(), e);
+        }
+    }
+}
+
+fn remove_unpublished_archive(version: i32) {
+    let archive_path = get_archive_path(version);
+    if !archive_path.exists() {
+        return;
+    }
+    match std::fs::remove_dir_all(&archive_path) {
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to remove {}. Error: {}", archive_path.display(), e);
+        }
+    }
+}
+
+// The ingests that became a version, by version
+pub fn load_published_ingests() -> anyhow::Result<BTreeMap<i32, IngestRecord>> {
+    let mut ingests = BTreeMap::new();
+    for record in repository()?.list_ingests()? {
+        if record.stage != IngestStage::PUBLISHED {
+            continue;
+        }
+        if let Some(version) = record.archive_version {
+            ingests.insert(version, record);
+        }
+    }
+    Ok(ingests)
+}
+
+// Ingests that were running when the server went down will never complete:
+// mark them as failed, so that the upload page doesn't wait for them forever
+pub fn fail_interrupted_ingests() -> anyhow::Result<()> {
+    for record in repository()?.list_ingests()? {
+        if record.stage == IngestStage::PUBLISHED || record.stage == IngestStage::FAILED {
+            continue;
+        }
+        eprintln!(
+            "Ingest {} was interrupted, marking it as failed.",
+            record.ingest_id
+        );
+        // The archive was extracted, but never became a version
+        if let Some(version) = record.archive_version {
+            remove_staged_archive(version);
+            // Moved in place right before the versions could be updated
+            if version > get_published_version()? {
+                remove_unpublished_archive(version);
+            }
+        }
+        update_ingest(&record.ingest_id, |record| {
+            record.stage = IngestStage::FAILED;
+            record.error = Some(String::from("Interrupted by a restart of the server"));
+        })?;
+    }
+
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+pub async fn get_ingest(
+    UrlPath(ingest_id): UrlPath<String>,
+) -> Result<Json<IngestRecord>, (StatusCode, String)> {
+    let load_ingest_id = ingest_id.clone();
+    match run_blocking(move || load_ingest(&load_ingest_id)).await {
+        Ok(Some(record)) => Ok(Json(record)),
+        Ok(None) => {
+            let message = format!("Ingest {} doesn't exist.", ingest_id);
+            Err((StatusCode::NOT_FOUND, message))
+        }
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
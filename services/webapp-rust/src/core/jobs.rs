@@ -0,0 +1,161 @@
+// Registry of the image generation jobs.
+// Every job is stored in the repository, so that the state of a job survives restarts
+// of the server. The rendered image and its metadata are files in JOBS_ROOT_DIR.
+
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::Utc;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::JOBS_ROOT_DIR;
+use crate::core::recipe::Recipe;
+use crate::core::repository::{record_asset, repository, AssetKind};
+use crate::core::JobStatus;
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub job_id: String,
+    pub status: JobStatus,
+    pub created_at: String,
+    pub finished_at: Option<String>,
+    pub archive_version: Option<i32>,
+    pub recipe: Option<Recipe>,
+    // EG: 'PROGRESS: 40%; Compositing layer 2 of 5'
+    pub progress: Option<String>,
+    pub error: Option<String>,
+    pub output_path: Option<PathBuf>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+fn now_rfc3339() -> String {
+    Utc::now().to_rfc3339()
+}
+
+fn get_jobs_root_dir() -> anyhow::Result<PathBuf> {
+    let jobs_root_dir = PathBuf::from(JOBS_ROOT_DIR);
+    if !jobs_root_dir.exists() {
+        match fs::create_dir_all(&jobs_root_dir) {
+            Ok(_) => {}
+            Err(e) => {
+                let message = format!(
+                    "Failed to create jobs dir {}. Error: {}",
+                    jobs_root_dir.display(),
+                    e
+                );
+                anyhow::bail!(message);
+            }
+        }
+    }
+    Ok(jobs_root_dir)
+}
+
+// Where the rendered image of a job lives
+pub fn get_job_image_path(job_id: &str) -> anyhow::Result<PathBuf> {
+    Ok(get_jobs_root_dir()?.join(format!("{}.png", job_id)))
+}
+
+// Where the NFT metadata of the rendered image lives
+pub fn get_job_metadata_path(job_id: &str) -> anyhow::Result<PathBuf> {
+    Ok(get_jobs_root_dir()?.join(format!("{}.metadata.json", job_id)))
+}
+
+pub fn save_job(record: &JobRecord) -> anyhow::Result<()> {
+    repository()?.save_job(record)
+}
+
+// Returns None if no job with the given id was ever created
+pub fn load_job(job_id: &str) -> anyhow::Result<Option<JobRecord>> {
+    repository()?.load_job(job_id)
+}
+
+pub fn create_job(job_id: &str) -> anyhow::Result<JobRecord> {
+    let record = JobRecord {
+        job_id: String::from(job_id),
+        status: JobStatus::STARTED,
+        created_at: now_rfc3339(),
+        finished_at: None,
+        archive_version: None,
+        recipe: None,
+        progress: Some(String::from("PROGRESS: 00%; Queued")),
+        error: None,
+        output_path: None,
+    };
+    save_job(&record)?;
+    Ok(record)
+}
+
+// Load the job, apply the changes and save it back
+pub fn update_job<F>(job_id: &str, update: F) -> anyhow::Result<JobRecord>
+where
+    F: FnOnce(&mut JobRecord),
+{
+    let mut record = match load_job(job_id)? {
+        Some(r) => r,
+        None => {
+            let message = format!("Job {} doesn't exist.", job_id);
+            anyhow::bail!(message);
+        }
+    };
+    update(&mut record);
+    save_job(&record)?;
+    Ok(record)
+}
+
+pub fn set_job_progress(job_id: &str, percentage: usize, info: &str) {
+    let progress = format!("PROGRESS: {:02}%; {}", percentage, info);
+    match update_job(job_id, |record| record.progress = Some(progress)) {
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to update progress of job {}. {}", job_id, e);
+        }
+    }
+}
+
+pub fn complete_job(job_id: &str, output_path: PathBuf) -> anyhow::Result<JobRecord> {
+    let record = update_job(job_id, |record| {
+        record.status = JobStatus::COMPLETED;
+        record.finished_at = Some(now_rfc3339());
+        record.progress = Some(String::from("PROGRESS: 100%; Completed"));
+        record.output_path = Some(output_path.clone());
+    })?;
+    record_asset(
+        job_id,
+        None,
+        AssetKind::Image,
+        record.archive_version,
+        &output_path,
+    )?;
+    Ok(record)
+}
+
+pub fn fail_job(job_id: &str, error: &str) -> anyhow::Result<JobRecord> {
+    update_job(job_id, |record| {
+        record.status = JobStatus::FAILED;
+        record.finished_at = Some(now_rfc3339());
+        record.error = Some(String::from(error));
+    })
+}
+
+// Jobs that were running when the server went down will never complete:
+// mark them as failed, so that callers don't wait for them forever
+pub fn fail_interrupted_jobs() -> anyhow::Result<()> {
+    for record in repository()?.list_jobs_with_status(JobStatus::STARTED)? {
+        eprintln!(
+            "Job {} was interrupted, marking it as failed.",
+            record.job_id
+        );
+        fail_job(&record.job_id, "Interrupted by a restart of the server")?;
+    }
+
+    Ok(())
+}
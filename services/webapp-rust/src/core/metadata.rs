@@ -0,0 +1,200 @@
+// Metadata of the rendered images, in the format expected by ERC-721 marketplaces like OpenSea:
+//
+//   {
+//     "name": "Sphynx #1",
+//     "description": "...",
+//     "image": "ipfs://<CID>/00001.png",
+//     "attributes": [{"trait_type": "Background", "value": "Background C 01"}, ...]
+//   }
+//
+// The final address of the images is usually known only once they've been uploaded,
+// so until a base URI is set the image points to a placeholder.
+
+use std::path::Path;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::{
+    METADATA_COLLECTION_NAME, METADATA_DESCRIPTION, METADATA_IMAGE_BASE_URI_PLACEHOLDER,
+};
+use crate::core::recipe::{is_overlay, Recipe};
+use crate::core::{read_json, write_json_atomically};
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NftAttribute {
+    pub trait_type: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NftMetadata {
+    pub name: String,
+    pub description: String,
+    pub image: String,
+    pub attributes: Vec<NftAttribute>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+// EG: '02_body_skins' -> 'Body Skins', 'Body_Skin_Tiger_zebra.png' -> 'Body Skin Tiger Zebra'
+fn humanize(name: &str) -> String {
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _)) => stem,
+        None => name,
+    };
+    let stem = if is_overlay(stem) {
+        stem.trim_start_matches(|c: char| c.is_ascii_digit())
+    } else {
+        stem
+    };
+
+    stem.split('_')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().collect::<String>() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// One attribute per layer: the type is the overlay directory the image belongs to,
+// the value is the image itself
+// EG: '01_background/02_uncommon_background/Background_U_01.png' -> Background: Background U 01
+pub fn get_attributes(recipe: &Recipe) -> Vec<NftAttribute> {
+    recipe
+        .layers
+        .iter()
+        .filter_map(|layer| {
+            let components: Vec<&str> = layer.split('/').collect();
+            let (leaf, dirs) = match components.split_last() {
+                Some(r) => r,
+                None => return None,
+            };
+            let trait_dir = dirs
+                .iter()
+                .rev()
+                .find(|d| is_overlay(d))
+                .or_else(|| dirs.first())
+                .copied()
+                .unwrap_or("");
+
+            Some(NftAttribute {
+                trait_type: humanize(trait_dir),
+                value: humanize(leaf),
+            })
+        })
+        .collect()
+}
+
+pub fn get_image_uri(base_uri: Option<&str>, image_file_name: &str) -> String {
+    let base_uri = base_uri.unwrap_or(METADATA_IMAGE_BASE_URI_PLACEHOLDER);
+    format!("{}/{}", base_uri.trim_end_matches('/'), image_file_name)
+}
+
+pub fn build_metadata(
+    name: &str,
+    recipe: &Recipe,
+    base_uri: Option<&str>,
+    image_file_name: &str,
+) -> NftMetadata {
+    NftMetadata {
+        name: format!("{} {}", METADATA_COLLECTION_NAME, name),
+        description: String::from(METADATA_DESCRIPTION),
+        image: get_image_uri(base_uri, image_file_name),
+        attributes: get_attributes(recipe),
+    }
+}
+
+pub fn save_metadata(path: &Path, metadata: &NftMetadata) -> anyhow::Result<()> {
+    write_json_atomically(path, metadata)
+}
+
+pub fn load_metadata(path: &Path) -> anyhow::Result<NftMetadata> {
+    read_json(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::core::test_utils::TestDir;
+
+    fn sample_recipe() -> Recipe {
+        Recipe {
+            root_dir: PathBuf::from("/archives/001/entry"),
+            layers: vec![
+                String::from("01_background/common_background/Background_C_01.png"),
+                String::from("02_body_skins/Body_Skin_Tiger_zebra.png"),
+                String::from("03_ears/ear_1/02_ear_1_lines/Ear_1_Line.png"),
+                String::from("eyes/Eyes_blue.png"),
+            ],
+        }
+    }
+
+    #[test]
+    fn names_the_attributes_after_their_overlay() {
+        let attributes: Vec<(String, String)> = get_attributes(&sample_recipe())
+            .into_iter()
+            .map(|a| (a.trait_type, a.value))
+            .collect();
+
+        let expected = [
+            ("Background", "Background C 01"),
+            ("Body Skins", "Body Skin Tiger Zebra"),
+            // The deepest overlay, not the top level one
+            ("Ear 1 Lines", "Ear 1 Line"),
+            // No overlay at all: the top level directory
+            ("Eyes", "Eyes Blue"),
+        ];
+        let expected: Vec<(String, String)> = expected
+            .iter()
+            .map(|(t, v)| (String::from(*t), String::from(*v)))
+            .collect();
+        assert_eq!(attributes, expected);
+    }
+
+    #[test]
+    fn points_the_image_to_the_base_uri() {
+        let metadata = build_metadata("#7", &sample_recipe(), None, "00007.png");
+        assert_eq!(metadata.name, "Sphynx #7");
+        assert_eq!(metadata.image, "ipfs://<CID>/00007.png");
+
+        let metadata = build_metadata(
+            "#7",
+            &sample_recipe(),
+            Some("ipfs://bafybeigdyrzt/"),
+            "00007.png",
+        );
+        assert_eq!(metadata.image, "ipfs://bafybeigdyrzt/00007.png");
+    }
+
+    #[test]
+    fn saves_and_loads_metadata() {
+        let dir = TestDir::new("metadata");
+        fs::create_dir_all(&dir.path).unwrap();
+        let path = dir.path.join("00007.json");
+
+        let metadata = build_metadata("#7", &sample_recipe(), None, "00007.png");
+        save_metadata(&path, &metadata).unwrap();
+
+        let loaded = load_metadata(&path).unwrap();
+        assert_eq!(loaded.name, metadata.name);
+        assert_eq!(loaded.image, metadata.image);
+        assert_eq!(loaded.attributes.len(), 4);
+        assert_eq!(loaded.attributes[1].value, "Body Skin Tiger Zebra");
+    }
+}
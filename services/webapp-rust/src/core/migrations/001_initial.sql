@@ -0,0 +1,77 @@
+-- Archive versions: which one is in use, and every time that changed
+CREATE TABLE version_state (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    last_version INTEGER NOT NULL,
+    current_version INTEGER,
+    last_modified TEXT NOT NULL
+);
+
+CREATE TABLE version_switches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    version INTEGER NOT NULL,
+    previous_version INTEGER,
+    reason TEXT NOT NULL,
+    switched_at TEXT NOT NULL
+);
+
+-- Uploaded archives, from the moment they are received to the moment they are published
+CREATE TABLE ingests (
+    ingest_id TEXT PRIMARY KEY,
+    file_name TEXT NOT NULL,
+    size_bytes INTEGER,
+    checksum TEXT,
+    stage TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    finished_at TEXT,
+    archive_version INTEGER,
+    sanitize_operations INTEGER,
+    -- JSON
+    validation TEXT,
+    error TEXT
+);
+
+CREATE INDEX ingests_by_stage ON ingests (stage);
+
+-- Image generation jobs
+CREATE TABLE jobs (
+    job_id TEXT PRIMARY KEY,
+    status TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    finished_at TEXT,
+    archive_version INTEGER,
+    -- JSON
+    recipe TEXT,
+    progress TEXT,
+    error TEXT,
+    output_path TEXT
+);
+
+CREATE INDEX jobs_by_status ON jobs (status);
+
+-- Every recipe generated for a version, the same cat is never generated twice
+CREATE TABLE recipes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    archive_version INTEGER NOT NULL,
+    checksum TEXT NOT NULL,
+    -- JSON
+    layers TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    UNIQUE (archive_version, checksum)
+);
+
+-- The files rendered by the jobs and the batches
+CREATE TABLE assets (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- The job or the batch
+    owner_id TEXT NOT NULL,
+    -- The item of a batch, starting from 1
+    item_index INTEGER,
+    kind TEXT NOT NULL,
+    archive_version INTEGER,
+    path TEXT NOT NULL UNIQUE,
+    size_bytes INTEGER NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX assets_by_owner ON assets (owner_id);
@@ -1,19 +1,17 @@
 // Templates and web server
-use axum::{extract::Multipart, extract::Query, http::StatusCode, response::Json};
+use axum::{
+    body::Bytes,
+    extract::{multipart::Field, Multipart, Query},
+    http::StatusCode,
+    response::Json,
+};
 
 // Filesystem operations
 use chrono::{DateTime, Utc};
-use flate2::read::GzDecoder;
-use std::convert::TryInto;
 use std::fs;
-use std::io::{self, BufRead};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::process::Stdio;
 use std::time::SystemTime;
-use sysinfo::{Disk, DiskExt, System, SystemExt};
-use tar::Archive;
-use tokio::fs::File;
+use sysinfo::{DiskExt, System, SystemExt};
 use tokio::io::AsyncWriteExt;
 
 // JSON
@@ -21,14 +19,43 @@ use serde::{Deserialize, Serialize};
 
 use uuid::Uuid;
 
-// Errors
-use anyhow;
-
+pub mod archive_format;
+pub mod batches;
+pub mod combinatorics;
+pub mod composite;
 pub mod constants;
+pub mod diff;
+pub mod export;
+pub mod extraction;
+pub mod ingest;
+pub mod jobs;
+pub mod metadata;
+pub mod rarity;
+pub mod recipe;
+pub mod reports;
+pub mod repository;
+pub mod rules;
+pub mod sanitize;
+pub mod sqlite;
+#[cfg(test)]
+mod test_utils;
+pub mod uniqueness;
+pub mod uploads;
+pub mod validation;
+pub mod versions;
+use crate::core::archive_format::ArchiveFormat;
+use crate::core::composite::{render_recipe, render_recipe_to_png};
 use crate::core::constants::{
-    ARCHIVES_ROOT_DIR, ARCHIVES_TMP_DIR, ENTRY_POINT_DIR_NAME, JOBS_ROOT_DIR, VERSIONS_PATH,
-    ZFILL_PADDING,
+    ARCHIVES_ROOT_DIR, ARCHIVES_STAGING_DIR, ARCHIVES_TMP_DIR, ENTRY_POINT_DIR_NAME,
+    SANITIZED_ENTRY_POINT_DIR_NAME, ZFILL_PADDING,
 };
+use crate::core::ingest::IngestRecord;
+use crate::core::jobs::JobRecord;
+use crate::core::metadata::NftMetadata;
+use crate::core::rarity::RarityManifest;
+use crate::core::recipe::{find_invalid_layers, parse_recipe};
+use crate::core::repository::AssetKind;
+use crate::core::uniqueness::generate_unique_recipe;
 
 // -----------------------------------------------------------------------------
 // Data structures
@@ -61,7 +88,9 @@ pub struct ImageData {
     b64: String,
 }
 
-#[derive(Debug, Serialize)]
+// NB: the names are what the frontend expects
+#[allow(non_camel_case_types, clippy::upper_case_acronyms, dead_code)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum JobStatus {
     NOT_FOUND,
     STARTED,
@@ -77,6 +106,18 @@ pub struct JobData {
     status: JobStatus,
     progress: Option<String>,
     image: Option<String>,
+    error: Option<String>,
+    created_at: Option<String>,
+    finished_at: Option<String>,
+    layers: Option<Vec<String>>,
+    metadata: Option<NftMetadata>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GeneratedImageData {
+    version: i32,
+    layers: Vec<String>,
+    image: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -87,12 +128,32 @@ pub struct JobQuery {
 #[derive(Debug, Deserialize)]
 pub struct ImageQuery {
     pub path: String,
+    // If given, the image must belong to this version of the archive
+    pub version: Option<i32>,
+}
+
+// An uploaded archive, once it's on disk
+#[derive(Debug, Clone)]
+pub struct SavedArchive {
+    pub path: PathBuf,
+    // Zero-padded (EG: '002')
+    pub version: String,
+    pub size_bytes: usize,
+    // md5 of the content
+    pub checksum: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VersionsData {
+    // The highest version ever published, the next upload becomes the one after it
     last_version: i32,
     last_modified: String,
+    // The version used when none is asked for, lower than last_version after a rollback.
+    // Missing in the versions.json written before rollbacks existed.
+    #[serde(default)]
+    current_version: Option<i32>,
+    #[serde(default)]
+    history: Vec<versions::VersionSwitch>,
 }
 
 #[derive(Debug, Serialize)]
@@ -101,25 +162,34 @@ pub struct InventoryNodeData {
     children: Vec<InventoryNodeData>,
     is_file: bool,
     file_path: String,
-}
-
-pub struct ArchiveInfo {
-    pub name: String,
-    pub version: i32,
+    // Effective rarity tier, EG: 'common'
+    rarity: String,
 }
 
 #[derive(Debug, Serialize)]
 pub struct InventoryData {
     root: String,
+    version: i32,
     children: Vec<InventoryNodeData>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct InventoryQuery {
+    pub version: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GenerationQuery {
+    pub version: Option<i32>,
+}
+
 // -----------------------------------------------------------------------------
 // Functions
 // -----------------------------------------------------------------------------
+#[allow(clippy::needless_late_init, clippy::single_match)]
 fn find_entry_point_dir(path: &PathBuf) -> Option<PathBuf> {
     let entries;
-    match fs::read_dir(&path) {
+    match fs::read_dir(path) {
         Ok(r) => {
             entries = r;
         }
@@ -138,7 +208,7 @@ fn find_entry_point_dir(path: &PathBuf) -> Option<PathBuf> {
                 entry_path = entry.path().clone();
                 match entry_path.file_name() {
                     Some(r) => {
-                        entry_name = r.clone();
+                        entry_name = r;
                     }
                     None => {
                         continue;
@@ -155,7 +225,10 @@ fn find_entry_point_dir(path: &PathBuf) -> Option<PathBuf> {
 
         if entry_path.is_dir() {
             // Exit condition
-            if file_name_string.contains(ENTRY_POINT_DIR_NAME) {
+            // Archives that went through the sanitization have it renamed
+            if file_name_string.contains(ENTRY_POINT_DIR_NAME)
+                || file_name_string == SANITIZED_ENTRY_POINT_DIR_NAME
+            {
                 eprintln!("Found entry point of archive: {file_name_string}");
                 return Some(entry_path.canonicalize().unwrap());
             }
@@ -175,11 +248,17 @@ fn find_entry_point_dir(path: &PathBuf) -> Option<PathBuf> {
     None
 }
 
-fn collect_data_from_directory(path: &PathBuf) -> Vec<InventoryNodeData> {
+// `relative_path` is the path of the directory relative to the entry point, used to look up rarities
+#[allow(clippy::needless_late_init)]
+fn collect_data_from_directory(
+    path: &PathBuf,
+    relative_path: &str,
+    rarity: &RarityManifest,
+) -> Vec<InventoryNodeData> {
     let mut nodes_data = Vec::<InventoryNodeData>::new();
 
     let entries;
-    match fs::read_dir(&path) {
+    match fs::read_dir(path) {
         Ok(r) => {
             entries = r;
         }
@@ -198,7 +277,7 @@ fn collect_data_from_directory(path: &PathBuf) -> Vec<InventoryNodeData> {
                 entry_path = entry.path().clone();
                 match entry_path.file_name() {
                     Some(r) => {
-                        entry_name = r.clone();
+                        entry_name = r;
                     }
                     None => {
                         continue;
@@ -212,6 +291,8 @@ fn collect_data_from_directory(path: &PathBuf) -> Vec<InventoryNodeData> {
 
         let file_name = entry_name.to_str().unwrap_or("unknown_name");
         let file_name_string = String::from(file_name);
+        let entry_relative_path = rarity::join_relative(relative_path, file_name);
+        let entry_rarity = String::from(rarity.get_tier(&entry_relative_path));
 
         let file_path = entry_path
             .canonicalize()
@@ -225,16 +306,18 @@ fn collect_data_from_directory(path: &PathBuf) -> Vec<InventoryNodeData> {
                 children: vec![],
                 is_file: true,
                 file_path,
+                rarity: entry_rarity,
             });
         }
         // Recurse
         else if entry_path.is_dir() {
-            let children = collect_data_from_directory(&entry_path);
+            let children = collect_data_from_directory(&entry_path, &entry_relative_path, rarity);
             nodes_data.push(InventoryNodeData {
-                name: String::from(file_name_string),
+                name: file_name_string,
                 children,
                 is_file: false,
                 file_path,
+                rarity: entry_rarity,
             });
         }
     }
@@ -246,39 +329,124 @@ fn collect_data_from_directory(path: &PathBuf) -> Vec<InventoryNodeData> {
 // Various utility functions
 // -----------------------------------------------------------------------------
 
+// The version asked for by a caller, or the current one if none was given
+async fn get_requested_version(version: Option<i32>) -> Result<i32, (StatusCode, String)> {
+    let data = repository::run_blocking(load_versions_data)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    match version {
+        Some(v) => {
+            if !versions::is_published(v, data.last_version) {
+                let message = format!("Archive version {:0ZFILL_PADDING$} doesn't exist", v);
+                return Err((StatusCode::NOT_FOUND, message));
+            }
+            Ok(v)
+        }
+        None => Ok(data.current_version()),
+    }
+}
+
+// Write to a temporary file first and then move it in place,
+// so that readers never see a half written file
+fn write_json_atomically<T: Serialize>(path: &Path, data: &T) -> anyhow::Result<()> {
+    let serialized = match serde_json::to_string_pretty(data) {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!("Failed to serialize {}. Error: {}", path.display(), e);
+            anyhow::bail!(message);
+        }
+    };
+
+    // Every writer has its own temporary file (in the same directory, so that the rename is atomic),
+    // otherwise two concurrent writers could move each other's half written file in place
+    let file_name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(r) => r,
+        None => {
+            let message = format!("Invalid path {}", path.display());
+            anyhow::bail!(message);
+        }
+    };
+    let tmp_path = path.with_file_name(format!("{}.{}.tmp", file_name, Uuid::new_v4()));
+    match fs::write(&tmp_path, serialized) {
+        Ok(_) => {}
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            let message = format!("Failed to write {}. Error: {}", tmp_path.display(), e);
+            anyhow::bail!(message);
+        }
+    }
+    match fs::rename(&tmp_path, path) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            let message = format!("Failed to write {}. Error: {}", path.display(), e);
+            anyhow::bail!(message);
+        }
+    }
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> anyhow::Result<T> {
+    let file_contents = match fs::read_to_string(path) {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!("Failed to read {}. Error: {}", path.display(), e);
+            anyhow::bail!(message);
+        }
+    };
+    match serde_json::from_str(&file_contents) {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            let message = format!("Failed to deserialize {}. Error: {}", path.display(), e);
+            anyhow::bail!(message);
+        }
+    }
+}
+
 fn bytes_to_human_readable(num_bytes: f64) -> String {
     // Convert bytes to human-readable values
     // This function might not be perfect and very optimized, but at least I wrote it myself!
 
     // Since this is for humans, I'm not using bi-bytes (which use 1024 as base)
     let base: f64 = 1000.0;
-    const UNITS: [&'static str; 9] = ["B", "KB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
+    const UNITS: [&str; 9] = ["B", "KB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
 
     // Understand what unit to use
     let mut exponent = num_bytes.log(base).floor() as i64;
     // Just in case the number is horribly big, clamp it down to a minimum
-    exponent = std::cmp::min(exponent, (UNITS.len() - 1) as i64);
+    // (and less than 1 byte is still bytes)
+    exponent = exponent.clamp(0, (UNITS.len() - 1) as i64);
     let unit_to_use = UNITS[exponent as usize];
 
     let file_size_in_unit = num_bytes / base.powf(exponent as f64);
     let file_size_human_readable = file_size_in_unit.to_string();
     // Use only the first 3 digit to represent the number, it will be enough
-    let result = format!("~{} {}", &file_size_human_readable[0..4], unit_to_use);
+    let digits: String = file_size_human_readable.chars().take(4).collect();
+    let result = format!("~{} {}", digits, unit_to_use);
 
     result
 }
 
-async fn save_archive(data: axum::body::Bytes) -> anyhow::Result<(PathBuf, String)> {
+// Where an uploaded archive is saved before being extracted, and the version it will become.
+// The version is reserved: it must be released (see `versions::release_version`) once the
+// archive is published or has failed.
+async fn get_archive_save_path(format: ArchiveFormat) -> anyhow::Result<(PathBuf, String)> {
     // Ask the DB which version of the file this is
-    let last_version = get_archive_version().await?;
+    let version = repository::run_blocking(versions::reserve_version).await?;
 
-    // Understand where to save
-    let version_padded = format!("{:0ZFILL_PADDING$}", last_version + 1);
+    // Understand where to save, every upload has its own file
+    let version_padded = format!("{:0ZFILL_PADDING$}", version);
     let base_dir = Path::new(ARCHIVES_TMP_DIR);
-    let save_path = base_dir.join(format!("{}.tar.gz", version_padded));
+    let file_name = format!(
+        "{}-{}.{}",
+        version_padded,
+        Uuid::new_v4(),
+        format.extension()
+    );
+    let save_path = base_dir.join(file_name);
     match tokio::fs::create_dir_all(base_dir).await {
         Ok(_) => {}
         Err(e) => {
+            versions::release_version(version);
             let message = format!(
                 "Failed to create dir: {}. Error: {}",
                 save_path.display(),
@@ -288,206 +456,201 @@ async fn save_archive(data: axum::body::Bytes) -> anyhow::Result<(PathBuf, Strin
         }
     }
 
-    eprintln!("Saving file to disk to {}", save_path.display());
+    Ok((save_path, version_padded))
+}
 
-    // TODO: Generate a unique checksum?
+// Stream the archive being uploaded to disk, one chunk at a time.
+// `first_chunk` is what was already read from the field (EG: to check the file type).
+async fn save_archive(
+    first_chunk: Bytes,
+    field: &mut Field<'_>,
+    format: ArchiveFormat,
+) -> anyhow::Result<SavedArchive> {
+    let (save_path, version_padded) = get_archive_save_path(format).await?;
+
+    eprintln!("Saving file to disk to {}", save_path.display());
 
     // Keep track of elapsed time, for benchmarking reasons
     let current_time = SystemTime::now();
 
     let mut file;
-    match File::create(&save_path).await {
+    match tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&save_path)
+        .await
+    {
         Ok(f) => {
             file = f;
         }
         Err(e) => {
+            release_saved_version(&version_padded);
             let message = format!("Failed to create file to disk. Error: {}", e);
             anyhow::bail!(message);
         }
     }
-    match file.write_all(&data).await {
-        Ok(_) => {
-            eprintln!("{} written to disk!", save_path.display());
-        }
-        Err(e) => {
-            let message = format!("Failed writing file to disk. Error: {}", e);
-            anyhow::bail!(message);
-        }
-    }
-    match current_time.elapsed() {
-        Ok(elapsed) => {
-            eprintln!("Saving file to disk took {} seconds", elapsed.as_secs());
-        }
-        Err(e) => {
-            eprintln!("Failed to get elapsed time. Error: {}", e);
-        }
-    }
-    Ok((save_path, version_padded))
-}
-
-pub async fn get_archive_version() -> anyhow::Result<i32> {
-    // TODO: Have a proper DB, for now a JSON file on disk is enough
-    let versions_file_path = Path::new(&VERSIONS_PATH);
-
-    // If we don't have any, write the initial JSON to disk
-    if !versions_file_path.exists() {
-        let serialized;
 
-        let now: DateTime<Utc> = SystemTime::now().into();
-        let last_modified = now.to_rfc3339();
-
-        let initial_data = &VersionsData {
-            last_version: 1,
-            last_modified,
-        };
-        match serde_json::to_string_pretty(&initial_data) {
-            Ok(r) => {
-                serialized = r;
-            }
-            Err(e) => {
-                eprintln!("Failed to serialize {:#?}. Error: {}", initial_data, e);
-                return Ok(1);
+    let mut size_bytes = 0;
+    let mut checksum = md5::Context::new();
+    let mut chunk = Some(first_chunk);
+    let result: anyhow::Result<()> = async {
+        while let Some(data) = chunk {
+            checksum.consume(&data);
+            size_bytes += data.len();
+            match file.write_all(&data).await {
+                Ok(_) => {}
+                Err(e) => {
+                    let message = format!("Failed writing file to disk. Error: {}", e);
+                    anyhow::bail!(message);
+                }
             }
+            chunk = match field.chunk().await {
+                Ok(r) => r,
+                Err(e) => {
+                    let message = format!("Failed to receive the archive. Error: {}", e);
+                    anyhow::bail!(message);
+                }
+            };
         }
-
-        match tokio::fs::write(&versions_file_path, serialized).await {
-            Ok(_) => {}
+        match file.flush().await {
+            Ok(_) => Ok(()),
             Err(e) => {
-                eprintln!("Failed to create JSON file. Error: {}", e);
+                let message = format!("Failed writing file to disk. Error: {}", e);
+                anyhow::bail!(message);
             }
         }
     }
+    .await;
 
-    //eprintln!("Reading {}", versions_file_path.display());
-    let file_contents;
-    match tokio::fs::read_to_string(versions_file_path).await {
-        Ok(f) => {
-            file_contents = f;
-        }
-        Err(e) => {
-            let message = format!(
-                "Failed to read {}, error: {}",
-                versions_file_path.display(),
-                e
-            );
-            anyhow::bail!(message);
-        }
+    // Don't leave half written archives around
+    if let Err(e) = result {
+        let _ = tokio::fs::remove_file(&save_path).await;
+        release_saved_version(&version_padded);
+        return Err(e);
     }
+    eprintln!(
+        "{} written to disk! ({})",
+        save_path.display(),
+        bytes_to_human_readable(size_bytes as f64)
+    );
 
-    let data: VersionsData;
-    match serde_json::from_str(&file_contents) {
-        Ok(r) => {
-            data = r;
-            return Ok(data.last_version);
+    match current_time.elapsed() {
+        Ok(elapsed) => {
+            eprintln!("Saving file to disk took {} seconds", elapsed.as_secs());
         }
         Err(e) => {
-            let message = format!("Failed to deserialize {:?}, error: {}", file_contents, e);
-            anyhow::bail!(message);
+            eprintln!("Failed to get elapsed time. Error: {}", e);
         }
     }
-}
 
-async fn extract_archive(archive_path: &Path, version: &str) -> anyhow::Result<()> {
-    eprintln!("Started decompressing and untaring of archive");
-    let tar;
+    Ok(SavedArchive {
+        path: save_path,
+        version: version_padded,
+        size_bytes,
+        checksum: format!("{:x}", checksum.compute()),
+    })
+}
 
-    // Sadly, the 'tar' crate doesn't support async
-    match std::fs::File::open(archive_path) {
-        Ok(tar_gz) => {
-            tar = GzDecoder::new(tar_gz);
-        }
-        Err(e) => {
-            let message = format!("Failed to open tar archive: {}", e);
-            anyhow::bail!(message);
-        }
+impl VersionsData {
+    // The version in use, what every endpoint reads when no version is asked for
+    fn current_version(&self) -> i32 {
+        self.current_version.unwrap_or(self.last_version)
     }
+}
 
-    let mut archive = Archive::new(tar);
-    let extraction_path = Path::new(ARCHIVES_ROOT_DIR).join(&version);
-    match archive.unpack(&extraction_path) {
-        Ok(()) => {
-            println!(
-                "Successfully unpacked archive to {}",
-                extraction_path.display()
-            );
-        }
-        Err(e) => {
-            let message = format!("Failed to extract tar archive: {}", e);
-            anyhow::bail!(message);
+fn load_versions_data() -> anyhow::Result<VersionsData> {
+    let repository = repository::repository()?;
+    match repository.load_versions()? {
+        Some(r) => Ok(r),
+        // If we don't have any, store the initial ones
+        None => {
+            let now: DateTime<Utc> = SystemTime::now().into();
+            let initial_data = VersionsData {
+                last_version: 1,
+                last_modified: now.to_rfc3339(),
+                current_version: None,
+                history: vec![],
+            };
+            match repository.save_versions(&initial_data, None) {
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("Failed to store the initial versions. {}", e);
+                }
+            }
+            Ok(initial_data)
         }
     }
+}
 
-    // Sanitize the names of the directories and files
-    // TODO: Check exit code (to catch python tracebacks)
-    eprintln!("Running sanitization process..");
-    let output = Command::new("/app/scripts/sanitize_directories.py")
-        .args([&extraction_path])
-        .output()
-        .unwrap();
-    eprintln!("Exit code of sanitization process: {:?}", output.status);
-    eprintln!(
-        "STDOUT of sanitization process: {:?}",
-        std::str::from_utf8(&output.stdout)
-    );
-    eprintln!(
-        "STERR of sanitization process: {:?}",
-        std::str::from_utf8(&output.stderr)
+async fn extract_archive(archive_path: &Path, version: &str) -> anyhow::Result<()> {
+    // Sadly, neither the 'tar' nor the 'zip' crate support async
+    let archive_path = archive_path.to_path_buf();
+    let version = String::from(version);
+    tokio::task::spawn_blocking(move || unpack_archive(&archive_path, &version)).await?
+}
+
+fn unpack_archive(archive_path: &Path, version: &str) -> anyhow::Result<()> {
+    eprintln!("Started extracting archive {}", archive_path.display());
+    let extraction_path = get_staging_path(version);
+    let format = extraction::unpack(archive_path, &extraction_path)?;
+    println!(
+        "Successfully unpacked {:?} archive to {}",
+        format,
+        extraction_path.display()
     );
 
     Ok(())
 }
 
-async fn update_latest_version() -> anyhow::Result<()> {
-    eprintln!("Updating versions file to correct the last version..");
-    let last_version = get_archive_version().await?;
-    let new_version = last_version + 1;
-    let versions_file_path = Path::new(&VERSIONS_PATH);
-
-    let serialized_data;
-    let now: DateTime<Utc> = SystemTime::now().into();
-
-    // Write time of last upload
-    let last_modified = now.to_rfc3339();
-    let new_data = &VersionsData {
-        last_version: new_version,
-        last_modified,
-    };
-    match serde_json::to_string_pretty(&new_data) {
-        Ok(r) => {
-            serialized_data = r;
-        }
+async fn remove_extracted_archive(version: &str) {
+    let extraction_path = get_staging_path(version);
+    if !extraction_path.exists() {
+        return;
+    }
+    match tokio::fs::remove_dir_all(&extraction_path).await {
+        Ok(_) => {}
         Err(e) => {
-            let message = format!("Failed to serialize {:#?}. Error: {}", new_data, e);
-            anyhow::bail!(message);
+            eprintln!(
+                "Failed to remove {}. Error: {}",
+                extraction_path.display(),
+                e
+            );
         }
     }
+}
 
-    match tokio::fs::write(&versions_file_path, serialized_data).await {
-        Ok(_) => {
-            eprintln!("Last version is now {}", new_version);
-        }
+// The version of an archive that won't be published (any more)
+fn release_saved_version(version: &str) {
+    match version.parse::<i32>() {
+        Ok(v) => versions::release_version(v),
         Err(e) => {
-            let message = format!("Failed to create JSON file. Error: {}", e);
-            anyhow::bail!(message);
+            eprintln!("Invalid archive version {}. Error: {}", version, e);
         }
     }
-
-    Ok(())
 }
 
-async fn clean_up_tmp() -> anyhow::Result<()> {
-    match std::fs::remove_dir_all(ARCHIVES_TMP_DIR) {
+// Only the archive of this upload, others might still be using the tmp directory
+async fn remove_tmp_archive(archive_path: &Path) -> anyhow::Result<()> {
+    if !archive_path.exists() {
+        return Ok(());
+    }
+    match tokio::fs::remove_file(archive_path).await {
         Ok(_) => {
-            eprintln!("Successfully cleaned up tmp directory.");
+            eprintln!("Successfully removed {}.", archive_path.display());
             Ok(())
         }
         Err(e) => {
-            let message = format!("Failed to clean up tmp directory. Error: {}", e);
+            let message = format!("Failed to remove {}. Error: {}", archive_path.display(), e);
             anyhow::bail!(message);
         }
     }
 }
 
+// Where an archive is extracted, sanitized and validated before being published
+fn get_staging_path(version: &str) -> PathBuf {
+    Path::new(ARCHIVES_STAGING_DIR).join(version)
+}
+
 fn get_archive_path(version: i32) -> PathBuf {
     let version_padded = format!("{:0ZFILL_PADDING$}", version);
     let archive_path = Path::new(ARCHIVES_ROOT_DIR).join(version_padded);
@@ -495,6 +658,28 @@ fn get_archive_path(version: i32) -> PathBuf {
     archive_path
 }
 
+// The directory from where the recipes start (EG: /app/data/archives/002/sphynx_program/programm)
+fn get_entry_point_path(version: i32) -> anyhow::Result<PathBuf> {
+    let archive_path = get_archive_path(version);
+    match find_entry_point_dir(&archive_path) {
+        Some(entry_point) => Ok(entry_point),
+        None => {
+            let message = format!(
+                "Failed to find the entry point ({}) of archive {}",
+                ENTRY_POINT_DIR_NAME,
+                archive_path.display()
+            );
+            anyhow::bail!(message);
+        }
+    }
+}
+
+// Same as get_entry_point_path, from async code
+async fn find_entry_point(version: i32) -> anyhow::Result<PathBuf> {
+    repository::run_blocking(move || get_entry_point_path(version)).await
+}
+
+#[allow(clippy::needless_late_init)]
 pub fn get_base64_for_path(path: &Path) -> anyhow::Result<String> {
     // TODO: cache all of this
 
@@ -530,7 +715,7 @@ pub fn get_pages_lists_for_current_page(active_page: &str) -> Vec<Page> {
 
     for page_name in all_pages {
         let is_active = page_name == active_page;
-        let page_url = String::from(format!("/app/{}", page_name.to_lowercase()));
+        let page_url = format!("/app/{}", page_name.to_lowercase());
         let current_page = Page {
             name: String::from(page_name),
             active: is_active,
@@ -566,7 +751,7 @@ pub async fn status() -> Json<StatusData> {
     let mut disk_info = Vec::new();
     let mut disks_names_already_added = Vec::new();
     for disk in sys.disks() {
-        let disk_name = format!("{}", disk.name().to_str().unwrap_or("unknown"));
+        let disk_name = disk.name().to_str().unwrap_or("unknown").to_string();
         let total_space = bytes_to_human_readable(disk.total_space() as f64);
         let available_space = bytes_to_human_readable(disk.available_space() as f64);
 
@@ -644,6 +829,23 @@ pub async fn image_preview(query: Query<ImageQuery>) -> Json<ImageData> {
         }
     }
 
+    // Don't serve images of a version that isn't the requested one
+    if let Some(version) = query.version {
+        let archive_path = get_archive_path(version).canonicalize();
+        let canonical_image_path = image_path.canonicalize();
+        match (archive_path, canonical_image_path) {
+            (Ok(a), Ok(i)) if i.starts_with(&a) => {}
+            _ => {
+                eprintln!(
+                    "{} is not part of archive version {}",
+                    image_path.display(),
+                    version
+                );
+                return Json(ImageData { b64: image_as_b64 });
+            }
+        }
+    }
+
     match get_base64_for_path(&image_path) {
         Ok(result) => {
             image_as_b64 = result;
@@ -656,31 +858,18 @@ pub async fn image_preview(query: Query<ImageQuery>) -> Json<ImageData> {
     Json(ImageData { b64: image_as_b64 })
 }
 
-// List the current images we have on disk
-pub async fn list_inventory() -> Json<InventoryData> {
-    let latest_version;
-    match get_archive_version().await {
-        Ok(version) => {
-            latest_version = version;
-        }
-        Err(error) => {
-            eprintln!("Failed to get archive version. Error: {}", error);
-            let default_data = InventoryData {
-                root: String::from("root"),
-                children: vec![],
-            };
-            return Json(default_data);
-        }
-    }
-
+// Walk the archive of a version to collect information for all of its images
+#[allow(clippy::single_match)]
+fn collect_inventory(version: i32) -> InventoryData {
     // Look on disk and collect information for all files
-    let archive_path = get_archive_path(latest_version);
+    let archive_path = get_archive_path(version);
     if !archive_path.as_path().exists() {
         let inventory_data = InventoryData {
             root: String::from("root"),
+            version,
             children: vec![],
         };
-        return Json(inventory_data);
+        return inventory_data;
     }
 
     // Find the directory that actually contains the root of the archive
@@ -698,397 +887,404 @@ pub async fn list_inventory() -> Json<InventoryData> {
     if !input_dir.as_path().exists() {
         let inventory_data = InventoryData {
             root: String::from("root"),
+            version,
             children: vec![],
         };
-        return Json(inventory_data);
+        return inventory_data;
     }
 
-    let root_children = collect_data_from_directory(&input_dir);
-    let inventory_data = InventoryData {
+    let rarity = match rarity::load_manifest(version) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!(
+                "Failed to load the rarity manifest, using the default one. Error: {}",
+                e
+            );
+            RarityManifest::default()
+        }
+    };
+
+    let root_children = collect_data_from_directory(&input_dir, "", &rarity);
+    InventoryData {
         root: String::from("root"),
+        version,
         children: root_children,
-    };
+    }
+}
+
+// List the images of a version of the archive (the current one by default)
+pub async fn list_inventory(
+    query: Query<InventoryQuery>,
+) -> Result<Json<InventoryData>, (StatusCode, String)> {
+    let version = get_requested_version(query.version).await?;
 
-    Json(inventory_data)
+    let inventory_data = tokio::task::spawn_blocking(move || collect_inventory(version))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(inventory_data))
 }
 
-pub async fn queue_generation_of_random_image() -> Json<JobData> {
+pub async fn queue_generation_of_random_image(
+    query: Query<GenerationQuery>,
+) -> Result<Json<JobData>, (StatusCode, String)> {
+    let version = get_requested_version(query.version).await?;
+
     // Generate a random ID
     let job_id = Uuid::new_v4();
     let job_id_str = job_id.to_string();
 
     eprintln!("Generated new Job, id: {}", job_id_str);
-    let url = String::from("api/jobs");
-
-    let job_data = JobData {
-        endpoint: url,
-        job_id: String::from(&job_id_str),
-        status: JobStatus::STARTED,
-        image: None,
-        progress: None,
-    };
+
+    // Register the job before answering, so that it can be queried straight away
+    let create_job_id = job_id_str.clone();
+    let record = repository::run_blocking(move || jobs::create_job(&create_job_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let job_data = get_job_data_from_record(record, None, None);
 
     // In the background, start the generation of the image
     tokio::spawn(async move {
         eprintln!("Started image processing..");
-        match generate_random_image(&job_id_str).await {
+        match generate_random_image(&job_id_str, version).await {
             Ok(_) => {
                 eprintln!("Finished image processing.");
             }
             Err(e) => {
                 eprintln!("Failed to render image. {}", e);
+                let fail_job_id = job_id_str.clone();
+                let error = e.to_string();
+                match repository::run_blocking(move || jobs::fail_job(&fail_job_id, &error)).await {
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("Failed to mark job {} as failed. {}", job_id_str, e);
+                    }
+                }
             }
         }
     });
 
-    Json(job_data)
+    Ok(Json(job_data))
 }
 
-fn get_job_path(job_id_str: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
-    let jobs_root_dir = PathBuf::from(JOBS_ROOT_DIR);
-    if !jobs_root_dir.exists() {
-        match fs::create_dir(&jobs_root_dir) {
-            Ok(_) => {}
-            Err(e) => {
-                anyhow::bail!(e);
-            }
-        }
+fn get_job_data_from_record(
+    record: JobRecord,
+    image: Option<String>,
+    metadata: Option<NftMetadata>,
+) -> JobData {
+    JobData {
+        endpoint: String::from("api/jobs"),
+        job_id: record.job_id,
+        status: record.status,
+        progress: record.progress,
+        image,
+        error: record.error,
+        created_at: Some(record.created_at),
+        finished_at: record.finished_at,
+        layers: record.recipe.map(|recipe| recipe.layers),
+        metadata,
     }
-
-    let job_path = PathBuf::from(jobs_root_dir.join(&job_id_str));
-
-    let progress_file_name = format!("{}.progress", job_id_str);
-    let job_progress_path = PathBuf::from(jobs_root_dir.join(&progress_file_name));
-
-    Ok((job_path, job_progress_path))
 }
 
-// TODO: Proper error handling and return codes
-pub async fn get_job(query: Query<JobQuery>) -> Json<JobData> {
+pub async fn get_job(query: Query<JobQuery>) -> Result<Json<JobData>, (StatusCode, String)> {
     let job_id = &query.job_id;
     eprintln!("Checking for job_id={}", job_id);
 
-    // Save a job file with the .progress extension,
-    // We can check if /path/to/job_id exists, and return it,
-    // and if it doesn't, then /path/to/job_id.progress will contain the progress %
-
-    let mut job_data = JobData {
-        endpoint: String::from("api/jobs"),
-        job_id: String::from(job_id),
-        status: JobStatus::NOT_FOUND,
-        progress: None,
-        image: None,
-    };
-
-    // TODO: Distinguish between failed jobs and jobs that don't exist at all
-    let default_data = JobData {
-        endpoint: String::from("api/jobs"),
-        job_id: String::from(job_id),
-        status: JobStatus::NOT_FOUND,
-        progress: None,
-        image: None,
-    };
-
-    // Check if the job has finished
-    // If so, retrieve the related image
-    // If not, give a meaningful reply to the caller
-    let job_path;
-    let job_progress_path;
-
-    match get_job_path(job_id) {
-        Ok(r) => {
-            (job_path, job_progress_path) = r;
+    let load_job_id = job_id.clone();
+    let record = match repository::run_blocking(move || jobs::load_job(&load_job_id)).await {
+        Ok(Some(r)) => r,
+        Ok(None) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                format!("Job {} doesn't exist.", job_id),
+            ));
         }
         Err(e) => {
             eprintln!("{e}");
-            return Json(default_data);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
         }
-    }
-
-    if !job_path.exists() {
-        eprintln!(
-            "{} doesn't exist, checking if it's in progress..",
-            job_path.display()
-        );
-    }
-
-    if !job_progress_path.exists() {
-        eprintln!(
-            "{} doesn't exist and is not in progress.",
-            job_path.display()
-        );
-        return Json(default_data);
-    }
-
-    // If file size is small, it's definitely not a rendered image
-    let file_size = &job_path.metadata().unwrap().len();
-    eprintln!("file_size: {}", file_size);
-    if file_size < &20 {
-        // Extract the progress
-        let progress_file;
+    };
 
-        match fs::File::open(&job_progress_path) {
-            Ok(f) => {
-                progress_file = f;
-            }
-            Err(e) => {
-                eprintln!("{e}");
-                return Json(default_data);
+    // If the job has finished, retrieve the related image and its metadata
+    let mut image = None;
+    let mut metadata = None;
+    if record.status == JobStatus::COMPLETED {
+        let assets_job_id = job_id.clone();
+        let assets =
+            repository::run_blocking(move || repository::repository()?.list_assets(&assets_job_id))
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let metadata_asset = assets.iter().find(|a| a.kind == AssetKind::Metadata);
+        if let Some(metadata_asset) = metadata_asset {
+            match metadata::load_metadata(&metadata_asset.path) {
+                Ok(r) => {
+                    metadata = Some(r);
+                }
+                Err(e) => {
+                    eprintln!("Error while reading metadata: {e}");
+                }
             }
         }
 
-        let lines: Vec<String> = io::BufReader::new(progress_file)
-            .lines()
-            .map(|s| s.unwrap().to_owned())
-            .collect();
-
-        let num_lines = lines.len();
-        match lines.get(num_lines - 1) {
-            Some(l) => {
-                eprintln!("Job progress: {}", l);
-                job_data.progress = Some(String::from(l));
-                job_data.status = JobStatus::STARTED;
-                return Json(job_data);
-            }
-            None => {
-                return Json(default_data);
+        if let Some(output_path) = &record.output_path {
+            match get_base64_for_path(output_path) {
+                Ok(base64_str) => {
+                    image = Some(base64_str);
+                }
+                Err(e) => {
+                    eprintln!("Error while reading image content: {e}");
+                    return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+                }
             }
         }
     }
 
-    // If we are here, the image has finished rendering, ideally
-    eprintln!("Getting base64 from image..");
-    match get_base64_for_path(&job_path) {
-        Ok(base64_str) => {
-            eprintln!("Image has finished rendering");
-            job_data.image = Some(base64_str);
-            job_data.progress = Some(String::from("completed"));
-            job_data.status = JobStatus::COMPLETED;
-        }
-        Err(e) => {
-            eprintln!("Error while reading image content: {e}");
-            return Json(default_data);
-        }
-    }
-
-    Json(job_data)
+    Ok(Json(get_job_data_from_record(record, image, metadata)))
 }
 
-pub async fn generate_random_image(job_id_str: &str) -> anyhow::Result<()> {
-    // Write the job file on disk so that we know this request has started
-    let (job_path, job_progress_path) = get_job_path(job_id_str)?;
-
-    // Update progress
-    match fs::write(&job_path, "progress: 0%") {
-        Ok(_) => {}
-        Err(e) => {
-            let message = format!(
-                "Failed to write jobs file ({}) on disk. {}",
-                job_path.display(),
-                e
-            );
-            anyhow::bail!(message);
-        }
-    }
+pub async fn generate_random_image(job_id_str: &str, archive_version: i32) -> anyhow::Result<()> {
+    let progress_job_id = String::from(job_id_str);
+    repository::run_blocking(move || {
+        jobs::set_job_progress(&progress_job_id, 0, "Generating recipe");
+        Ok(())
+    })
+    .await?;
 
     // First, generate a random recipe
-    let latest_archive_version = get_archive_version().await?;
+    let entry_point_path = find_entry_point(archive_version).await?;
 
-    // Look on disk and collect information for all files
-    let archive_path = get_archive_path(latest_archive_version);
-    let entry_point_path = archive_path
-        .join("sphynx_program")
-        .join(ENTRY_POINT_DIR_NAME);
-
-    // TODO: Check exit code (to catch python tracebacks)
-
-    // $ generate_permutation.py /app/data/archives/002 > my_recipe_file
-    // $ cat my_recipe_file | ./image-composite/target/release/image-composite --image-name my_name
     eprintln!(
         "Generating permutation starting from {}",
         entry_point_path.display()
     );
+    let recipe = tokio::task::spawn_blocking(move || {
+        generate_unique_recipe(archive_version, &entry_point_path)
+    })
+    .await??;
+    eprintln!("Generated recipe:\n{}", recipe);
+
+    let recorded_recipe = recipe.clone();
+    let metadata_recipe = recipe.clone();
+    let update_job_id = String::from(job_id_str);
+    repository::run_blocking(move || {
+        jobs::update_job(&update_job_id, |record| {
+            record.archive_version = Some(archive_version);
+            record.recipe = Some(recorded_recipe);
+        })
+    })
+    .await?;
+
+    // Then, render it
+    // NB: the image is written to a temporary file and moved in place only once it's complete,
+    // so that nobody can read a half written image
+    let image_job_id = String::from(job_id_str);
+    let image_path =
+        repository::run_blocking(move || jobs::get_job_image_path(&image_job_id)).await?;
+    let render_path = image_path.with_extension("png.tmp");
+
+    let render_output_path = render_path.clone();
+    let render_job_id = String::from(job_id_str);
+    let render_result = tokio::task::spawn_blocking(move || {
+        render_recipe(&recipe, &render_output_path, |done, total| {
+            // Leave some room for the recipe generation and the final save
+            let percentage = 10 + (done * 80) / total;
+            let info = format!("Compositing layer {} of {}", done, total);
+            jobs::set_job_progress(&render_job_id, percentage, &info);
+        })
+    })
+    .await?;
 
-    let mut generate = tokio::process::Command::new("/app/scripts/generate_permutation.py")
-        .args([&entry_point_path])
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Failed to spawn generate_permutation.py");
-
-    // Update progress
-    match fs::write(&job_path, "progress: 20%") {
+    match render_result {
         Ok(_) => {}
+        Err(e) => {
+            let message = format!("Failed to render image. {}", e);
+            anyhow::bail!(message);
+        }
+    }
+
+    match tokio::fs::rename(&render_path, &image_path).await {
+        Ok(()) => {}
         Err(e) => {
             let message = format!(
-                "Failed to write jobs file ({}) on disk : {}",
-                job_path.display(),
+                "Failed to move image from {} to {}. {}",
+                render_path.display(),
+                image_path.display(),
                 e
             );
             anyhow::bail!(message);
         }
     }
 
-    let render_stdin: Stdio = generate
-        .stdout
-        .take()
-        .unwrap()
-        .try_into()
-        .expect("Failed to convert stdout to Stdio");
-
-    let stderr_file = fs::File::create(&job_progress_path).unwrap();
-    let render_stderr = Stdio::from(stderr_file);
-
-    eprintln!("Progress will be saved to {}", job_progress_path.display());
-
-    let image_name = format!("{}", job_id_str);
-    let render = tokio::process::Command::new("/app/image-composite-linux")
-        .args(["--image-name", &image_name])
-        .stdin(render_stdin)
-        .stdout(Stdio::piped())
-        .stderr(render_stderr)
-        .spawn()
-        .expect("Failed to spawn image-composite");
-
-    let (generate_result, render_output) = tokio::join!(generate.wait(), render.wait_with_output());
-
-    let generation_has_succeeded = generate_result.unwrap().success();
-    eprintln!("Generation has succeded? {}", generation_has_succeeded);
-
-    if !generation_has_succeeded {
-        match fs::remove_file(&job_path) {
-            Ok(_) => {}
-            Err(e) => {
-                let message = format!(
-                    "Failed to remove jobs file ({}) on disk. {}",
-                    job_path.display(),
-                    e
-                );
-                anyhow::bail!(message);
-            }
-        }
-        let message = format!("Image generation has failed.");
-        anyhow::bail!(message);
-    }
+    // The final address of the image isn't known yet, so it points to a placeholder
+    let image_file_name = format!("{}.png", job_id_str);
+    let job_metadata = metadata::build_metadata(
+        &format!("#{}", &metadata_recipe.checksum()[..8]),
+        &metadata_recipe,
+        None,
+        &image_file_name,
+    );
+    let complete_job_id = String::from(job_id_str);
+    repository::run_blocking(move || {
+        let metadata_path = jobs::get_job_metadata_path(&complete_job_id)?;
+        metadata::save_metadata(&metadata_path, &job_metadata)?;
+        repository::record_asset(
+            &complete_job_id,
+            None,
+            AssetKind::Metadata,
+            Some(archive_version),
+            &metadata_path,
+        )?;
+        jobs::complete_job(&complete_job_id, image_path)
+    })
+    .await?;
 
-    let render_stdout = &render_output.unwrap().stdout;
-    let image_path_str = std::str::from_utf8(render_stdout)
-        .unwrap_or("")
-        .strip_suffix("\n")
-        .unwrap_or("");
+    Ok(())
+}
 
-    eprintln!("image_path_str: {}", image_path_str);
+// Render the recipe provided in the body of the request against a version of the archive
+// (the current one by default), and return the image as base64
+pub async fn generate_image_from_recipe(
+    query: Query<GenerationQuery>,
+    body: String,
+) -> Result<Json<GeneratedImageData>, (StatusCode, String)> {
+    let version = get_requested_version(query.version).await?;
+    let entry_point_path = find_entry_point(version)
+        .await
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e.to_string()))?;
 
-    let image_path = PathBuf::from(image_path_str);
+    let recipe = parse_recipe(&body, &entry_point_path)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
 
-    if image_path_str.is_empty() {
-        anyhow::bail!("No STDOUT generated from render process");
-    }
-    if !image_path.exists() {
-        let message = format!("Image at path {} doesn't exist.", image_path.display());
-        anyhow::bail!(message);
+    let checked_recipe = recipe.clone();
+    let invalid_layers = tokio::task::spawn_blocking(move || find_invalid_layers(&checked_recipe))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !invalid_layers.is_empty() {
+        let message = format!(
+            "The recipe doesn't match archive version {:0ZFILL_PADDING$}:\n{}",
+            version,
+            invalid_layers.join("\n")
+        );
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, message));
     }
 
-    match fs::rename(&image_path, &job_path) {
-        Ok(()) => {}
+    eprintln!("Rendering recipe:\n{}", recipe);
+    let layers = recipe.layers.clone();
+    let render_result =
+        tokio::task::spawn_blocking(move || render_recipe_to_png(&recipe, |_, _| {}))
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    match render_result {
+        Ok(png_bytes) => Ok(Json(GeneratedImageData {
+            version,
+            layers,
+            image: base64::encode(png_bytes),
+        })),
         Err(e) => {
-            let message = format!(
-                "Failed to move image from {} to {}. {}",
-                image_path.display(),
-                job_path.display(),
-                e
-            );
-            anyhow::bail!(message);
+            eprintln!("Failed to render recipe. {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
         }
     }
+}
 
-    Ok(())
+// Read from the field until there's enough to detect the format of the archive
+async fn read_archive_header(field: &mut Field<'_>) -> Result<Bytes, (StatusCode, String)> {
+    let mut header = Vec::new();
+    while header.len() < archive_format::HEADER_LEN {
+        match field.chunk().await {
+            Ok(Some(chunk)) => header.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(e) => return Err((StatusCode::BAD_REQUEST, e.to_string())),
+        }
+    }
+    Ok(Bytes::from(header))
 }
+
 // TODO: implement Content-length limit via RequestBodyLimitLayer
 // https://docs.rs/axum/latest/axum/extract/struct.ContentLengthLimit.html
 // https://github.com/tokio-rs/axum/blob/0.5.x/examples/multipart-form/src/main.rs
-pub async fn upload_archive(mut multipart: Multipart) -> Result<(), (StatusCode, String)> {
-    while let Some(field) = multipart
+// Returns as soon as the archive has been received: the rest of the work is tracked by an ingest
+// (see ingest.rs). Only the first archive of the upload is considered.
+pub async fn upload_archive(
+    mut multipart: Multipart,
+) -> Result<Json<IngestRecord>, (StatusCode, String)> {
+    while let Some(mut field) = multipart
         .next_field()
         .await
         .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
     {
         // FIXME: watch training on map_err()
-        // FIXME: make this a bit tidier
 
         // Parse the current upload
-        match field.name() {
-            Some(r) => {
-                let name = r.to_string();
-
-                let data;
-                match field.bytes().await {
-                    Ok(d) => {
-                        data = d;
-                    }
-                    Err(e) => {
-                        return Err((StatusCode::BAD_REQUEST, e.to_string()));
-                    }
-                }
+        let name = match field.name() {
+            Some(r) => r.to_string(),
+            None => {
+                return Err((
+                    StatusCode::EXPECTATION_FAILED,
+                    String::from("No field name in multipart data"),
+                ))
+            }
+        };
 
-                // Parse content type
-                if name == "content-type" {
-                    let content_type;
-                    match std::str::from_utf8(&data) {
-                        Ok(r) => {
-                            content_type = r;
-                            eprintln!("Content type is {}", content_type);
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to parse field data as UTF8 string: {}", e);
-                            return Err((StatusCode::BAD_REQUEST, e.to_string()));
-                        }
-                    }
-                    continue;
+        // Parse content type
+        if name == "content-type" {
+            let data = field
+                .bytes()
+                .await
+                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+            match std::str::from_utf8(&data) {
+                Ok(content_type) => {
+                    eprintln!("Content type is {}", content_type);
                 }
-                if !name.contains(".tar.gz") {
-                    eprintln!("Skipping file since it's not a .tar.gz archive");
-                    continue;
+                Err(e) => {
+                    eprintln!("Failed to parse field data as UTF8 string: {}", e);
+                    return Err((StatusCode::BAD_REQUEST, e.to_string()));
                 }
+            }
+            continue;
+        }
+        // The archive is never held in memory as a whole, only one chunk at a time
+        let first_chunk = read_archive_header(&mut field).await?;
+        // The format is told by the magic bytes, whatever the name of the file
+        let format = match archive_format::detect_format(&first_chunk) {
+            Some(r) => r,
+            None => {
+                eprintln!("Skipping {} since it's not an archive", name);
+                continue;
+            }
+        };
 
-                let human_readable_size = bytes_to_human_readable(data.len() as f64);
-                println!("Length of '{}' is {} bytes", &name, &human_readable_size);
-
-                // TODO: Keep track of versions of the same file
+        // TODO: Keep track of versions of the same file
 
-                // Spawn a different thread to do all of the data cleanup
-                // FIXME: tidy up error handling
-                tokio::spawn(async move {
-                    let (archive_path, archive_version) =
-                        save_archive(data).await.expect("Failed to save archive.");
+        let ingest_id = Uuid::new_v4().to_string();
+        ingest::create_ingest(&ingest_id, &name)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
-                    match extract_archive(&archive_path, &archive_version).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            eprintln!("Failed to extract archive. {}", e);
-                        }
-                    }
-                    match update_latest_version().await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            eprintln!("Failed to update latest version. {}", e);
-                        }
-                    }
-                    match clean_up_tmp().await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            eprintln!("Failed to clean up tmp dir. {}", e);
-                        }
-                    }
-                });
+        let saved_archive = match save_archive(first_chunk, &mut field, format).await {
+            Ok(r) => r,
+            Err(e) => {
+                ingest::fail_ingest(&ingest_id, &e.to_string()).await;
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
             }
-            None => {
-                return Err((
-                    StatusCode::EXPECTATION_FAILED,
-                    String::from("No field name in multipart data"),
-                ))
+        };
+        let record = match ingest::set_ingest_saved(&ingest_id, &saved_archive).await {
+            Ok(r) => r,
+            Err(e) => {
+                ingest::abandon_saved_archive(&ingest_id, &saved_archive, &e.to_string()).await;
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
             }
-        }
+        };
+
+        // Spawn a different thread to do all of the data cleanup
+        tokio::spawn(ingest::run_ingest(ingest_id, saved_archive));
+
+        return Ok(Json(record));
     }
 
-    Ok(())
+    Err((
+        StatusCode::BAD_REQUEST,
+        String::from("No archive (.tar.gz, .tar.zst, .tar or .zip) found in the upload"),
+    ))
 }
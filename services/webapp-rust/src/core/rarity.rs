@@ -0,0 +1,393 @@
+// Rarity of the variants and leaves of an archive.
+//
+// Every archive version can have a rarity manifest (RARITY_ROOT_DIR/<version>.json)
+// that defines the tiers and their weights, and assigns a tier to directories or files
+// (by their path relative to the entry point). When picking between siblings,
+// each one is chosen with a probability proportional to the weight of its tier.
+//
+// Nodes without an explicit assignment fall back to the tier named in their name
+// (EG: '02_uncommon_background' -> 'uncommon'), like generate_permutation.py used to do,
+// and then to the default tier.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use axum::{extract::Query, http::StatusCode, response::Json};
+use rand::Rng;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::{RARITY_ROOT_DIR, ZFILL_PADDING};
+use crate::core::repository::run_blocking;
+use crate::core::{find_entry_point, get_requested_version, read_json, write_json_atomically};
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RarityManifest {
+    // Tier name -> weight
+    pub tiers: BTreeMap<String, f64>,
+    // Tier of the nodes that aren't assigned one in any other way
+    pub default_tier: String,
+    // Path relative to the entry point (EG: '01_background/02_uncommon_background') -> tier name
+    #[serde(default)]
+    pub assignments: BTreeMap<String, String>,
+}
+
+impl Default for RarityManifest {
+    // Same values generate_permutation.py had hardcoded
+    fn default() -> Self {
+        let tiers = BTreeMap::from([
+            (String::from("common"), 75.0),
+            (String::from("uncommon"), 15.0),
+            (String::from("epic"), 7.0),
+            (String::from("legendary"), 3.0),
+        ]);
+        RarityManifest {
+            tiers,
+            default_tier: String::from("common"),
+            assignments: BTreeMap::new(),
+        }
+    }
+}
+
+impl RarityManifest {
+    // Name of the tier a node belongs to, given its path relative to the entry point
+    pub fn get_tier(&self, relative_path: &str) -> &str {
+        if let Some(tier) = self.assignments.get(relative_path) {
+            return tier;
+        }
+
+        let name = relative_path.rsplit('/').next().unwrap_or(relative_path);
+        let name = name.to_lowercase();
+        for token in name.split(['_', '.']) {
+            if let Some((tier, _)) = self.tiers.get_key_value(token) {
+                return tier;
+            }
+        }
+
+        &self.default_tier
+    }
+
+    pub fn get_weight(&self, relative_path: &str) -> f64 {
+        let tier = self.get_tier(relative_path);
+        self.tiers.get(tier).copied().unwrap_or(0.0)
+    }
+
+    // Pick one of the children of `parent` (relative to the entry point), based on their weights
+    pub fn pick<R: Rng>(
+        &self,
+        rng: &mut R,
+        parent: &str,
+        children: &[String],
+    ) -> anyhow::Result<String> {
+        if children.is_empty() {
+            let message = format!(
+                "Cannot pick between the children of '{}' - there are none!",
+                parent
+            );
+            anyhow::bail!(message);
+        }
+
+        let weights: Vec<f64> = children
+            .iter()
+            .map(|child| self.get_weight(&join_relative(parent, child)))
+            .collect();
+        let total_weight: f64 = weights.iter().sum();
+
+        // Nothing has a weight: every child is as likely as the others
+        if total_weight <= 0.0 {
+            let index = rng.gen_range(0..children.len());
+            return Ok(children[index].clone());
+        }
+
+        let mut dice = rng.gen_range(0.0..total_weight);
+        for (child, weight) in children.iter().zip(weights.iter()) {
+            if dice < *weight {
+                return Ok(child.clone());
+            }
+            dice -= weight;
+        }
+
+        // Only reachable because of rounding errors
+        Ok(children[children.len() - 1].clone())
+    }
+
+    // Returns one message per problem found
+    pub fn validate(&self, entry_point: &Path) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.tiers.is_empty() {
+            errors.push(String::from("At least one tier must be defined"));
+        }
+        for (tier, weight) in &self.tiers {
+            // NB: a weight of 0 would make the nodes of the tier impossible to pick
+            if !weight.is_finite() || *weight <= 0.0 {
+                errors.push(format!(
+                    "Tier '{}': the weight must be a positive number",
+                    tier
+                ));
+            }
+            if tier.contains('_') || tier.contains('.') || *tier != tier.to_lowercase() {
+                errors.push(format!(
+                    "Tier '{}': names must be lowercase and can't contain '_' or '.'",
+                    tier
+                ));
+            }
+        }
+        if !self.tiers.contains_key(&self.default_tier) {
+            errors.push(format!(
+                "The default tier '{}' is not one of the tiers",
+                self.default_tier
+            ));
+        }
+
+        for (relative_path, tier) in &self.assignments {
+            if !self.tiers.contains_key(tier) {
+                errors.push(format!("{}: tier '{}' doesn't exist", relative_path, tier));
+            }
+
+            let path = Path::new(relative_path);
+            if path.is_absolute()
+                || path
+                    .components()
+                    .any(|c| !matches!(c, std::path::Component::Normal(_)))
+            {
+                errors.push(format!(
+                    "{}: paths must be relative to the entry point",
+                    relative_path
+                ));
+            } else if !entry_point.join(path).exists() {
+                errors.push(format!("{}: doesn't exist in the archive", relative_path));
+            }
+        }
+
+        errors
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RarityQuery {
+    pub version: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RarityData {
+    version: i32,
+    // Whether the manifest was never saved and the defaults are being used
+    is_default: bool,
+    #[serde(flatten)]
+    manifest: RarityManifest,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+// Path of a child, relative to the entry point
+pub fn join_relative(parent: &str, child: &str) -> String {
+    if parent.is_empty() {
+        return String::from(child);
+    }
+    format!("{}/{}", parent, child)
+}
+
+fn get_manifest_path(version: i32) -> PathBuf {
+    Path::new(RARITY_ROOT_DIR).join(format!("{:0ZFILL_PADDING$}.json", version))
+}
+
+fn load_saved_manifest(version: i32) -> anyhow::Result<Option<RarityManifest>> {
+    let manifest_path = get_manifest_path(version);
+    if !manifest_path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(read_json(&manifest_path)?))
+}
+
+// The manifest of the version, or the default one if it was never saved
+pub fn load_manifest(version: i32) -> anyhow::Result<RarityManifest> {
+    match load_saved_manifest(version)? {
+        Some(manifest) => Ok(manifest),
+        None => Ok(RarityManifest::default()),
+    }
+}
+
+pub fn save_manifest(version: i32, manifest: &RarityManifest) -> anyhow::Result<()> {
+    match std::fs::create_dir_all(RARITY_ROOT_DIR) {
+        Ok(_) => {}
+        Err(e) => {
+            let message = format!("Failed to create {}. Error: {}", RARITY_ROOT_DIR, e);
+            anyhow::bail!(message);
+        }
+    }
+    write_json_atomically(&get_manifest_path(version), manifest)
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+pub async fn get_rarity(
+    query: Query<RarityQuery>,
+) -> Result<Json<RarityData>, (StatusCode, String)> {
+    let version = get_requested_version(query.version).await?;
+
+    let saved_manifest = run_blocking(move || load_saved_manifest(version))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(RarityData {
+        version,
+        is_default: saved_manifest.is_none(),
+        manifest: saved_manifest.unwrap_or_default(),
+    }))
+}
+
+// Replace the rarity manifest of a version
+pub async fn put_rarity(
+    query: Query<RarityQuery>,
+    Json(manifest): Json<RarityManifest>,
+) -> Result<Json<RarityData>, (StatusCode, String)> {
+    let version = get_requested_version(query.version).await?;
+
+    let entry_point = find_entry_point(version)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+
+    let validated_manifest = manifest.clone();
+    let errors = tokio::task::spawn_blocking(move || validated_manifest.validate(&entry_point))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !errors.is_empty() {
+        let message = format!(
+            "The rarity manifest is not valid for archive version {:0ZFILL_PADDING$}:\n{}",
+            version,
+            errors.join("\n")
+        );
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, message));
+    }
+
+    let saved_manifest = manifest.clone();
+    run_blocking(move || save_manifest(version, &saved_manifest))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(RarityData {
+        version,
+        is_default: false,
+        manifest,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::core::test_utils::{sample_archive, TestDir};
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| String::from(*n)).collect()
+    }
+
+    #[test]
+    fn takes_the_tier_from_the_name() {
+        let mut manifest = RarityManifest::default();
+        manifest.assignments.insert(
+            String::from("01_background/epic_background"),
+            String::from("legendary"),
+        );
+
+        assert_eq!(
+            manifest.get_tier("01_background/02_uncommon_background"),
+            "uncommon"
+        );
+        assert_eq!(
+            manifest.get_tier("03_eyes/Eye_Legendary_Gold.png"),
+            "legendary"
+        );
+        // Explicit assignments come first
+        assert_eq!(
+            manifest.get_tier("01_background/epic_background"),
+            "legendary"
+        );
+        // Only whole words count, then it's the default tier
+        assert_eq!(manifest.get_tier("01_background/uncommonly_blue"), "common");
+        assert_eq!(manifest.get_weight("01_background/uncommonly_blue"), 75.0);
+    }
+
+    #[test]
+    fn picks_according_to_the_weights() {
+        let manifest = RarityManifest {
+            tiers: BTreeMap::from([(String::from("common"), 3.0), (String::from("rare"), 1.0)]),
+            default_tier: String::from("common"),
+            assignments: BTreeMap::new(),
+        };
+        let children = names(&["Background_01.png", "Background_rare_02.png"]);
+        let mut rng = StdRng::seed_from_u64(42);
+
+        let picks = 4000;
+        let mut rare_picks = 0;
+        for _ in 0..picks {
+            let child = manifest.pick(&mut rng, "01_background", &children).unwrap();
+            if child == children[1] {
+                rare_picks += 1;
+            }
+        }
+
+        // 1 in 4 is expected
+        assert!((850..1150).contains(&rare_picks), "{rare_picks} of {picks}");
+        assert!(manifest.pick(&mut rng, "01_background", &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_weights_that_arent_positive() {
+        let dir = TestDir::new("rarity");
+        sample_archive(&dir);
+
+        assert!(RarityManifest::default().validate(&dir.path).is_empty());
+        for weight in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let mut manifest = RarityManifest::default();
+            manifest.tiers.insert(String::from("epic"), weight);
+            let errors = manifest.validate(&dir.path);
+            assert_eq!(
+                errors,
+                vec!["Tier 'epic': the weight must be a positive number"],
+                "{weight}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_assignments_outside_of_the_archive() {
+        let dir = TestDir::new("rarity");
+        sample_archive(&dir);
+
+        let mut manifest = RarityManifest::default();
+        for (path, tier) in [
+            ("01_background/common_background", "epic"),
+            ("01_background/missing", "epic"),
+            ("../001", "epic"),
+            ("02_body_skins", "mythic"),
+        ] {
+            manifest
+                .assignments
+                .insert(String::from(path), String::from(tier));
+        }
+
+        let errors = manifest.validate(&dir.path);
+        assert_eq!(
+            errors,
+            vec![
+                "../001: paths must be relative to the entry point",
+                "01_background/missing: doesn't exist in the archive",
+                "02_body_skins: tier 'mythic' doesn't exist",
+            ]
+        );
+    }
+}
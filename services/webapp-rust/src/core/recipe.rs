@@ -0,0 +1,385 @@
+// Generation of recipes: the list of images that, once overlaid in order,
+// make up a single cat.
+// This is a port of what scripts/generate_permutation.py used to do.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use rand::Rng;
+use regex::Regex;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::{IGNORED_FILE_NAMES, RECIPE_RULES_MAX_ATTEMPTS};
+use crate::core::rarity::{join_relative, RarityManifest};
+use crate::core::rules::RuleSet;
+
+// Directories starting with 2 digits are overlays: all of them are used, in order
+const OVERLAY_PATTERN: &str = r"^\d{2}";
+// Captures the 'stream' of a skin, EG: Body_Skin_Tiger_zebra.png -> Tiger_zebra
+const STREAM_PATTERN: &str = r"(?:^(?:Body_Skin_)|^(?:\w+_\d+_))([&]*[A-Za-z0-9&_]*)\.png";
+
+// Compiled once, since batches generate thousands of recipes
+static OVERLAY_REGEX: OnceLock<Regex> = OnceLock::new();
+static STREAM_REGEX: OnceLock<Regex> = OnceLock::new();
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recipe {
+    // The entry point of the archive the layers are relative to
+    pub root_dir: PathBuf,
+    // Relative paths of the images to overlay, from the bottom to the top
+    pub layers: Vec<String>,
+}
+
+impl Recipe {
+    // Same checksum generate_permutation.py used to write to trees.txt
+    pub fn checksum(&self) -> String {
+        let mut context = md5::Context::new();
+        for layer in &self.layers {
+            context.consume(layer.as_bytes());
+        }
+        format!("{:x}", context.compute())
+    }
+
+    pub fn layer_paths(&self) -> Vec<PathBuf> {
+        self.layers
+            .iter()
+            .map(|layer| self.root_dir.join(layer))
+            .collect()
+    }
+}
+
+// Same format that generate_permutation.py used to print to stdout
+impl fmt::Display for Recipe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "root_dir: {}", self.root_dir.display())?;
+        for layer in &self.layers {
+            writeln!(f, "{}", layer)?;
+        }
+        Ok(())
+    }
+}
+
+// JSON equivalent of the textual recipe format
+#[derive(Debug, Deserialize)]
+struct RecipeRequest {
+    #[serde(default)]
+    root_dir: Option<String>,
+    layers: Vec<String>,
+}
+
+// Keeps track of what has been chosen so far while walking the archive
+struct Traversal<'a> {
+    rarity: &'a RarityManifest,
+    rules: &'a RuleSet,
+    layers: Vec<String>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+pub fn is_overlay(dir_name: &str) -> bool {
+    OVERLAY_REGEX
+        .get_or_init(|| Regex::new(OVERLAY_PATTERN).unwrap())
+        .is_match(dir_name)
+}
+
+pub fn get_stream(file_name: &str) -> Option<String> {
+    STREAM_REGEX
+        .get_or_init(|| Regex::new(STREAM_PATTERN).unwrap())
+        .captures(file_name)
+        .and_then(|captures| captures.get(1))
+        .map(|m| String::from(m.as_str()))
+}
+
+// Split the content of a directory into (sorted) sub directories and files
+pub fn read_dir_sorted(path: &Path) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let entries = match fs::read_dir(path) {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!("Failed to read directory {}. Error: {}", path.display(), e);
+            anyhow::bail!(message);
+        }
+    };
+
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+
+    for entry in entries.flatten() {
+        let entry_path = entry.path();
+        let name = match entry_path.file_name().and_then(|n| n.to_str()) {
+            Some(n) => String::from(n),
+            None => continue,
+        };
+
+        if entry_path.is_dir() {
+            dirs.push(name);
+        } else if entry_path.is_file() && !IGNORED_FILE_NAMES.contains(&name.as_str()) {
+            files.push(name);
+        }
+    }
+
+    dirs.sort();
+    files.sort();
+
+    Ok((dirs, files))
+}
+
+fn traverse<R: Rng>(
+    rng: &mut R,
+    traversal: &mut Traversal<'_>,
+    branch: &[String],
+    dir: &Path,
+) -> anyhow::Result<()> {
+    let (dirs, files) = read_dir_sorted(dir)?;
+
+    // 1. Is this is a directory with overlays?
+    let overlays: Vec<&String> = dirs.iter().filter(|d| is_overlay(d)).collect();
+
+    if !overlays.is_empty() {
+        for overlay in overlays {
+            let mut overlay_branch = branch.to_vec();
+            overlay_branch.push(overlay.clone());
+            traverse(rng, traversal, &overlay_branch, &dir.join(overlay))?;
+        }
+        return Ok(());
+    }
+
+    // 2. Is this a directory with variants?
+    if !dirs.is_empty() {
+        let chosen_variant = traversal.rarity.pick(rng, &branch.join("/"), &dirs)?;
+        let mut variant_branch = branch.to_vec();
+        variant_branch.push(chosen_variant.clone());
+        return traverse(rng, traversal, &variant_branch, &dir.join(chosen_variant));
+    }
+
+    // 3. Is this a directory with the final leaves?
+    // Only the ones compatible with the layers picked so far can be used
+    let parent = branch.join("/");
+    let potential_leaves: Vec<String> = files
+        .into_iter()
+        .filter(|leaf| {
+            let layer = join_relative(&parent, leaf);
+            traversal
+                .rules
+                .find_conflict(&traversal.layers, &layer)
+                .is_none()
+        })
+        .collect();
+
+    // This shouldn't happen (every directory should contain something in the end) - but still
+    if potential_leaves.is_empty() {
+        eprintln!("No leaves found in {}, skipping it.", dir.display());
+        return Ok(());
+    }
+
+    let final_leaf = traversal.rarity.pick(rng, &parent, &potential_leaves)?;
+
+    let mut leaf_branch = branch.to_vec();
+    leaf_branch.push(final_leaf);
+    traversal.layers.push(leaf_branch.join("/"));
+
+    Ok(())
+}
+
+// Walk the archive starting from its entry point and randomly pick the images to overlay,
+// favouring the variants and leaves with a higher rarity weight and respecting the rules
+pub fn generate_random_recipe(
+    entry_point: &Path,
+    rarity: &RarityManifest,
+    rules: &RuleSet,
+) -> anyhow::Result<Recipe> {
+    if !entry_point.is_dir() {
+        let message = format!(
+            "Entry point {} doesn't exist on disk.",
+            entry_point.display()
+        );
+        anyhow::bail!(message);
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut violations = Vec::new();
+
+    // Leaves are picked so that they don't conflict with what came before them,
+    // but some rules (EG: 'requires') can only be checked once the recipe is complete
+    for _ in 0..RECIPE_RULES_MAX_ATTEMPTS {
+        let mut traversal = Traversal {
+            rarity,
+            rules,
+            layers: Vec::new(),
+        };
+        traverse(&mut rng, &mut traversal, &[], entry_point)?;
+
+        if traversal.layers.is_empty() {
+            let message = format!("No images found under {}", entry_point.display());
+            anyhow::bail!(message);
+        }
+
+        violations = rules.find_violations(&traversal.layers);
+        if violations.is_empty() {
+            return Ok(Recipe {
+                root_dir: entry_point.to_path_buf(),
+                layers: traversal.layers,
+            });
+        }
+    }
+
+    let message = format!(
+        "Failed to generate a recipe respecting the rules after {} attempts. Last violations:\n{}",
+        RECIPE_RULES_MAX_ATTEMPTS,
+        violations
+            .iter()
+            .map(|v| v.message.clone())
+            .collect::<Vec<String>>()
+            .join("\n")
+    );
+    anyhow::bail!(message);
+}
+
+// Parse a recipe provided by a user, either in the format printed by generate_permutation.py:
+//
+//   root_dir: ~/some/path/input_for_sphynx
+//   01_background/01_common_background/Background_C_20.png
+//   02_body_skins/Body_Skin_Standard_pink.png
+//
+// or as JSON: {"root_dir": "...", "layers": ["01_background/...", "02_body_skins/..."]}
+// NB: the root_dir is only informative, layers are always resolved against the entry point given.
+pub fn parse_recipe(body: &str, entry_point: &Path) -> anyhow::Result<Recipe> {
+    let body = body.trim();
+
+    let layers: Vec<String> = if body.starts_with('{') {
+        let request: RecipeRequest = match serde_json::from_str(body) {
+            Ok(r) => r,
+            Err(e) => {
+                let message = format!("Failed to parse JSON recipe. Error: {}", e);
+                anyhow::bail!(message);
+            }
+        };
+        if let Some(root_dir) = &request.root_dir {
+            eprintln!("Ignoring root_dir of recipe: {}", root_dir);
+        }
+        request.layers
+    } else {
+        body.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with("root_dir:"))
+            .map(String::from)
+            .collect()
+    };
+
+    let layers: Vec<String> = layers
+        .into_iter()
+        .map(|layer| layer.trim().trim_start_matches("./").to_string())
+        .collect();
+
+    if layers.is_empty() {
+        anyhow::bail!("The recipe doesn't contain any layer");
+    }
+
+    Ok(Recipe {
+        root_dir: entry_point.to_path_buf(),
+        layers,
+    })
+}
+
+// Check that every layer of the recipe is an image living inside its root_dir.
+// Returns one message per invalid layer.
+pub fn find_invalid_layers(recipe: &Recipe) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    let root_dir = match recipe.root_dir.canonicalize() {
+        Ok(r) => r,
+        Err(e) => {
+            errors.push(format!(
+                "Root dir {} is not available. Error: {}",
+                recipe.root_dir.display(),
+                e
+            ));
+            return errors;
+        }
+    };
+
+    for layer in &recipe.layers {
+        let layer_path = Path::new(layer);
+        if layer_path.is_absolute()
+            || layer_path
+                .components()
+                .any(|c| !matches!(c, std::path::Component::Normal(_)))
+        {
+            errors.push(format!("{}: layers must be relative paths", layer));
+            continue;
+        }
+
+        match root_dir.join(layer_path).canonicalize() {
+            Ok(full_path) => {
+                if !full_path.starts_with(&root_dir) {
+                    errors.push(format!("{}: points outside of the archive", layer));
+                } else if !full_path.is_file() {
+                    errors.push(format!("{}: is not a file", layer));
+                }
+            }
+            Err(_) => {
+                errors.push(format!("{}: doesn't exist in the archive", layer));
+            }
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::core::rules::{Rule, RuleKind, RulesManifest};
+    use crate::core::test_utils::{sample_archive, TestDir};
+
+    #[test]
+    fn generates_recipes_respecting_the_rules() {
+        let dir = TestDir::new("recipe");
+        sample_archive(&dir);
+        let rules = RuleSet::compile(&RulesManifest::default()).unwrap();
+
+        for _ in 0..20 {
+            let recipe =
+                generate_random_recipe(&dir.path, &RarityManifest::default(), &rules).unwrap();
+            assert_eq!(recipe.layers.len(), 4, "{recipe}");
+            assert!(rules.find_violations(&recipe.layers).is_empty(), "{recipe}");
+        }
+    }
+
+    #[test]
+    fn gives_up_when_the_rules_cant_be_respected() {
+        let dir = TestDir::new("recipe");
+        sample_archive(&dir);
+        // Every recipe has a background, and there's no tail
+        let rules = RuleSet::compile(&RulesManifest {
+            rules: vec![Rule {
+                description: None,
+                kind: RuleKind::Requires {
+                    layer: String::from("^01_background/"),
+                    requires: String::from("^04_tail/"),
+                },
+            }],
+        })
+        .unwrap();
+
+        let error = generate_random_recipe(&dir.path, &RarityManifest::default(), &rules)
+            .unwrap_err()
+            .to_string();
+        let expected = format!("after {} attempts", RECIPE_RULES_MAX_ATTEMPTS);
+        assert!(error.contains(&expected), "{error}");
+        assert!(
+            error.contains("requires a layer matching '^04_tail/'"),
+            "{error}"
+        );
+    }
+}
@@ -0,0 +1,465 @@
+// Reports over the recipes generated for an archive version (see uniqueness.rs):
+// how often every trait showed up, compared with the rarity it was configured with,
+// and how rare every generated image is.
+//
+// A trait is a layer of a recipe (EG: '02_body_skins/Body_Skin_Tiger_zebra.png'),
+// grouped by the top level layer it belongs to (EG: '02_body_skins').
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::ZFILL_PADDING;
+use crate::core::rarity::{self, RarityManifest};
+use crate::core::recipe::{is_overlay, read_dir_sorted};
+use crate::core::uniqueness::{load_registered_recipes, RegisteredRecipe};
+use crate::core::{get_entry_point_path, get_requested_version};
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TraitStats {
+    layer: String,
+    trait_path: String,
+    rarity: String,
+    // How many images use the trait
+    count: usize,
+    percentage: f64,
+    // What the rarity weights would give, without taking the compatibility rules into account
+    expected_percentage: Option<f64>,
+    // percentage - expected_percentage
+    deviation: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageRarity {
+    // Position of the recipe in the registry, starting from 1
+    index: usize,
+    checksum: String,
+    created_at: String,
+    // Sum of 1 / frequency of every trait: the higher, the rarer the image
+    rarity_score: f64,
+    // 1 is the rarest image
+    rank: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DistributionReport {
+    version: i32,
+    images: usize,
+    traits: Vec<TraitStats>,
+    image_rarities: Vec<ImageRarity>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DistributionQuery {
+    pub version: Option<i32>,
+    // 'json' (default) or 'csv'
+    pub format: Option<String>,
+    // Only for CSV, which has a single table: 'traits' (default) or 'images'
+    pub table: Option<String>,
+}
+
+// Caches the content of the directories, since most traits share their parents
+struct ExpectedProbabilities<'a> {
+    entry_point: &'a Path,
+    rarity: &'a RarityManifest,
+    listings: HashMap<PathBuf, (Vec<String>, Vec<String>)>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+impl<'a> ExpectedProbabilities<'a> {
+    fn get_listing(&mut self, dir: &Path) -> anyhow::Result<&(Vec<String>, Vec<String>)> {
+        if !self.listings.contains_key(dir) {
+            let listing = read_dir_sorted(dir)?;
+            self.listings.insert(dir.to_path_buf(), listing);
+        }
+        Ok(&self.listings[dir])
+    }
+
+    // Probability of picking the trait, following the same steps as the generation:
+    // every overlay is used, while variants and leaves are picked based on their weight
+    fn get(&mut self, trait_path: &str) -> anyhow::Result<f64> {
+        let rarity = self.rarity;
+        let mut probability = 1.0;
+        let mut parent = String::new();
+        let mut dir = self.entry_point.to_path_buf();
+
+        for component in trait_path.split('/') {
+            let (dirs, files) = self.get_listing(&dir)?;
+            let is_file = files.iter().any(|f| f == component);
+
+            let siblings: Vec<String> = if is_file {
+                files.clone()
+            } else if dirs.iter().any(|d| is_overlay(d)) {
+                // Overlays are always used
+                vec![]
+            } else {
+                dirs.clone()
+            };
+
+            if !siblings.is_empty() {
+                let weights: Vec<f64> = siblings
+                    .iter()
+                    .map(|s| rarity.get_weight(&rarity::join_relative(&parent, s)))
+                    .collect();
+                let total_weight: f64 = weights.iter().sum();
+                let index = match siblings.iter().position(|s| s == component) {
+                    Some(i) => i,
+                    None => {
+                        let message = format!("{} doesn't exist in the archive", trait_path);
+                        anyhow::bail!(message);
+                    }
+                };
+
+                probability *= if total_weight > 0.0 {
+                    weights[index] / total_weight
+                } else {
+                    1.0 / siblings.len() as f64
+                };
+            }
+
+            parent = rarity::join_relative(&parent, component);
+            dir = dir.join(component);
+        }
+
+        Ok(probability)
+    }
+}
+
+// The tier of a trait is the one of the deepest node of its path that isn't of the default tier
+// (EG: a common image inside an uncommon variant is uncommon)
+fn get_trait_tier(rarity: &RarityManifest, trait_path: &str) -> String {
+    let mut tier = rarity.default_tier.as_str();
+    let mut path = String::new();
+    for component in trait_path.split('/') {
+        path = rarity::join_relative(&path, component);
+        let node_tier = rarity.get_tier(&path);
+        if node_tier != rarity.default_tier {
+            tier = node_tier;
+        }
+    }
+    String::from(tier)
+}
+
+fn get_top_layer(trait_path: &str) -> String {
+    String::from(trait_path.split('/').next().unwrap_or(trait_path))
+}
+
+pub fn build_distribution_report(version: i32) -> anyhow::Result<DistributionReport> {
+    let entry_point = get_entry_point_path(version)?;
+    let rarity = rarity::load_manifest(version)?;
+    let recipes: Vec<RegisteredRecipe> = load_registered_recipes(version)?;
+    Ok(compute_distribution(
+        version,
+        &entry_point,
+        &rarity,
+        &recipes,
+    ))
+}
+
+fn compute_distribution(
+    version: i32,
+    entry_point: &Path,
+    rarity: &RarityManifest,
+    recipes: &[RegisteredRecipe],
+) -> DistributionReport {
+    let num_images = recipes.len();
+
+    // 1. Count the traits
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for recipe in recipes {
+        for layer in &recipe.layers {
+            *counts.entry(layer.clone()).or_insert(0) += 1;
+        }
+    }
+
+    let mut expected = ExpectedProbabilities {
+        entry_point,
+        rarity,
+        listings: HashMap::new(),
+    };
+
+    let mut traits = Vec::new();
+    for (trait_path, count) in &counts {
+        let percentage = *count as f64 * 100.0 / num_images as f64;
+        let expected_percentage = match expected.get(trait_path) {
+            Ok(p) => Some(p * 100.0),
+            Err(e) => {
+                eprintln!(
+                    "Can't compute the expected percentage of {}. {}",
+                    trait_path, e
+                );
+                None
+            }
+        };
+
+        traits.push(TraitStats {
+            layer: get_top_layer(trait_path),
+            trait_path: trait_path.clone(),
+            rarity: get_trait_tier(rarity, trait_path),
+            count: *count,
+            percentage,
+            expected_percentage,
+            deviation: expected_percentage.map(|e| percentage - e),
+        });
+    }
+
+    // 2. Score the images
+    let mut image_rarities: Vec<ImageRarity> = recipes
+        .iter()
+        .enumerate()
+        .map(|(position, recipe)| ImageRarity {
+            index: position + 1,
+            checksum: recipe.checksum.clone(),
+            created_at: recipe.created_at.clone(),
+            rarity_score: recipe
+                .layers
+                .iter()
+                .map(|layer| num_images as f64 / counts[layer] as f64)
+                .sum(),
+            rank: 0,
+        })
+        .collect();
+
+    let mut by_score: Vec<usize> = (0..image_rarities.len()).collect();
+    by_score.sort_by(|a, b| {
+        image_rarities[*b]
+            .rarity_score
+            .total_cmp(&image_rarities[*a].rarity_score)
+    });
+    for (position, index) in by_score.into_iter().enumerate() {
+        image_rarities[index].rank = position + 1;
+    }
+
+    DistributionReport {
+        version,
+        images: num_images,
+        traits,
+        image_rarities,
+    }
+}
+
+fn escape_csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    String::from(field)
+}
+
+fn format_optional(value: Option<f64>) -> String {
+    match value {
+        Some(v) => format!("{:.4}", v),
+        None => String::new(),
+    }
+}
+
+fn traits_to_csv(report: &DistributionReport) -> String {
+    let mut csv =
+        String::from("layer,trait_path,rarity,count,percentage,expected_percentage,deviation\n");
+    for t in &report.traits {
+        csv.push_str(&format!(
+            "{},{},{},{},{:.4},{},{}\n",
+            escape_csv_field(&t.layer),
+            escape_csv_field(&t.trait_path),
+            escape_csv_field(&t.rarity),
+            t.count,
+            t.percentage,
+            format_optional(t.expected_percentage),
+            format_optional(t.deviation)
+        ));
+    }
+    csv
+}
+
+fn images_to_csv(report: &DistributionReport) -> String {
+    let mut csv = String::from("index,checksum,created_at,rarity_score,rank\n");
+    for image in &report.image_rarities {
+        csv.push_str(&format!(
+            "{},{},{},{:.4},{}\n",
+            image.index,
+            image.checksum,
+            escape_csv_field(&image.created_at),
+            image.rarity_score,
+            image.rank
+        ));
+    }
+    csv
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+pub async fn get_distribution_report(
+    query: Query<DistributionQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let version = get_requested_version(query.version).await?;
+
+    let report = tokio::task::spawn_blocking(move || build_distribution_report(version))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    match query.format.as_deref() {
+        None | Some("json") => Ok(Json(report).into_response()),
+        Some("csv") => {
+            let (table, csv) = match query.table.as_deref() {
+                None | Some("traits") => ("traits", traits_to_csv(&report)),
+                Some("images") => ("images", images_to_csv(&report)),
+                Some(other) => {
+                    let message = format!("Unknown table '{}', use 'traits' or 'images'", other);
+                    return Err((StatusCode::BAD_REQUEST, message));
+                }
+            };
+            let file_name = format!("distribution_{:0ZFILL_PADDING$}_{}.csv", version, table);
+            Ok((
+                [
+                    (header::CONTENT_TYPE, String::from("text/csv")),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        format!("attachment; filename=\"{}\"", file_name),
+                    ),
+                ],
+                csv,
+            )
+                .into_response())
+        }
+        Some(other) => {
+            let message = format!("Unknown format '{}', use 'json' or 'csv'", other);
+            Err((StatusCode::BAD_REQUEST, message))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::core::test_utils::{sample_archive, TestDir};
+
+    const BACKGROUND_C_01: &str = "01_background/common_background/Background_C_01.png";
+    const BACKGROUND_U_01: &str = "01_background/uncommon_background/Background_U_01.png";
+    const BODY_PINK: &str = "02_body_skins/Body_Skin_Standard_pink.png";
+    const BODY_ZEBRA: &str = "02_body_skins/Body_Skin_Tiger_zebra.png";
+
+    fn recipe(checksum: &str, layers: &[&str]) -> RegisteredRecipe {
+        RegisteredRecipe {
+            checksum: String::from(checksum),
+            layers: layers.iter().map(|l| String::from(*l)).collect(),
+            created_at: String::from("2022-11-02T10:00:00+00:00"),
+        }
+    }
+
+    fn build_report() -> DistributionReport {
+        let dir = TestDir::new("reports");
+        sample_archive(&dir);
+        let recipes = vec![
+            recipe("aaa", &[BACKGROUND_C_01, BODY_PINK]),
+            recipe("bbb", &[BACKGROUND_C_01, BODY_ZEBRA]),
+            recipe("ccc", &[BACKGROUND_U_01, BODY_PINK]),
+            recipe("ddd", &[BACKGROUND_C_01, BODY_PINK]),
+        ];
+        compute_distribution(1, &dir.path, &RarityManifest::default(), &recipes)
+    }
+
+    fn find_trait<'a>(report: &'a DistributionReport, trait_path: &str) -> &'a TraitStats {
+        report
+            .traits
+            .iter()
+            .find(|t| t.trait_path == trait_path)
+            .unwrap()
+    }
+
+    #[test]
+    fn compares_traits_with_their_rarity() {
+        let report = build_report();
+        assert_eq!(report.images, 4);
+        assert_eq!(report.traits.len(), 4);
+
+        let common = find_trait(&report, BACKGROUND_C_01);
+        assert_eq!(common.layer, "01_background");
+        assert_eq!(common.rarity, "common");
+        assert_eq!(common.count, 3);
+        assert_eq!(common.percentage, 75.0);
+        // 75 / (75 + 15) for the common backgrounds, then 1 of the 2 of them
+        let expected = common.expected_percentage.unwrap();
+        assert!((expected - 41.6667).abs() < 0.001, "{expected}");
+        assert!((common.deviation.unwrap() - (75.0 - expected)).abs() < 1e-9);
+
+        // The tier of its directory
+        let uncommon = find_trait(&report, BACKGROUND_U_01);
+        assert_eq!(uncommon.rarity, "uncommon");
+        let expected = uncommon.expected_percentage.unwrap();
+        assert!((expected - 16.6667).abs() < 0.001, "{expected}");
+
+        assert_eq!(
+            find_trait(&report, BODY_ZEBRA).expected_percentage,
+            Some(50.0)
+        );
+    }
+
+    #[test]
+    fn ranks_the_images_with_the_rarest_traits_first() {
+        let report = build_report();
+
+        let ranks: Vec<(&str, usize)> = report
+            .image_rarities
+            .iter()
+            .map(|i| (i.checksum.as_str(), i.rank))
+            .collect();
+        // 'bbb' has the only zebra body and 'ccc' the only uncommon background,
+        // ties keep the order of the recipes
+        assert_eq!(ranks, vec![("aaa", 3), ("bbb", 1), ("ccc", 2), ("ddd", 4)]);
+        assert_eq!(report.image_rarities[0].rarity_score, 4.0 / 3.0 + 4.0 / 3.0);
+        assert_eq!(report.image_rarities[1].rarity_score, 4.0 / 3.0 + 4.0);
+    }
+
+    #[test]
+    fn skips_the_expected_percentage_of_traits_missing_from_the_archive() {
+        let dir = TestDir::new("reports");
+        sample_archive(&dir);
+        let recipes = vec![recipe(
+            "aaa",
+            &["01_background/removed/Background_R_01.png"],
+        )];
+
+        let report = compute_distribution(1, &dir.path, &RarityManifest::default(), &recipes);
+        assert_eq!(report.traits[0].expected_percentage, None);
+        assert!(traits_to_csv(&report).ends_with(",1,100.0000,,\n"));
+    }
+
+    #[test]
+    fn writes_csv_tables() {
+        let mut report = build_report();
+        report.image_rarities[0].created_at = String::from("Nov 2, 2022");
+
+        let traits_csv = traits_to_csv(&report);
+        let mut lines = traits_csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("layer,trait_path,rarity,count,percentage,expected_percentage,deviation")
+        );
+        assert_eq!(
+            lines.next(),
+            Some("01_background,01_background/common_background/Background_C_01.png,common,3,75.0000,41.6667,33.3333")
+        );
+
+        let images_csv = images_to_csv(&report);
+        let line = images_csv.lines().nth(1).unwrap();
+        assert!(line.starts_with("1,aaa,\"Nov 2, 2022\","), "{line}");
+    }
+}
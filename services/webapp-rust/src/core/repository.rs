@@ -0,0 +1,285 @@
+// Storage of the state of the application: archive versions, ingests, jobs, recipes
+// and generated assets. Everything goes through the Repository trait, so that the
+// rest of the code doesn't know (or care) which database sits behind it.
+//
+// The files themselves (archives, rendered images, metadata) stay on disk,
+// only their paths are stored.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use chrono::Utc;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::DATABASE_PATH;
+use crate::core::ingest::IngestRecord;
+use crate::core::jobs::JobRecord;
+use crate::core::sqlite::{LegacyJsonFiles, SqliteRepository};
+use crate::core::uniqueness::RegisteredRecipe;
+use crate::core::versions::VersionSwitch;
+use crate::core::{JobStatus, VersionsData};
+
+// Opened (and migrated) the first time it's needed
+static REPOSITORY: OnceLock<Box<dyn Repository>> = OnceLock::new();
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetKind {
+    Image,
+    Metadata,
+}
+
+// A file rendered by a job or by a batch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeneratedAsset {
+    // The job or the batch that generated it
+    pub owner_id: String,
+    // The item of a batch, starting from 1
+    pub item_index: Option<usize>,
+    pub kind: AssetKind,
+    pub archive_version: Option<i32>,
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    pub created_at: String,
+}
+
+pub trait Repository: Send + Sync {
+    // Archive versions. None if nothing was ever stored.
+    fn load_versions(&self) -> anyhow::Result<Option<VersionsData>>;
+    // The switch (if any) is added to the history, together with the new state
+    fn save_versions(
+        &self,
+        data: &VersionsData,
+        switch: Option<&VersionSwitch>,
+    ) -> anyhow::Result<()>;
+
+    // Ingests
+    fn save_ingest(&self, record: &IngestRecord) -> anyhow::Result<()>;
+    fn load_ingest(&self, ingest_id: &str) -> anyhow::Result<Option<IngestRecord>>;
+    fn list_ingests(&self) -> anyhow::Result<Vec<IngestRecord>>;
+
+    // Jobs
+    fn save_job(&self, record: &JobRecord) -> anyhow::Result<()>;
+    fn load_job(&self, job_id: &str) -> anyhow::Result<Option<JobRecord>>;
+    fn list_jobs_with_status(&self, status: JobStatus) -> anyhow::Result<Vec<JobRecord>>;
+
+    // Recipes. Returns false if the recipe had already been generated for the version.
+    fn insert_recipe(&self, version: i32, recipe: &RegisteredRecipe) -> anyhow::Result<bool>;
+    // Oldest first
+    fn load_recipes(&self, version: i32) -> anyhow::Result<Vec<RegisteredRecipe>>;
+    fn count_recipes(&self, version: i32) -> anyhow::Result<usize>;
+
+    // Generated assets. A file that was rendered again replaces the previous one.
+    fn save_asset(&self, asset: &GeneratedAsset) -> anyhow::Result<()>;
+    fn list_assets(&self, owner_id: &str) -> anyhow::Result<Vec<GeneratedAsset>>;
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+pub fn repository() -> anyhow::Result<&'static dyn Repository> {
+    if let Some(repository) = REPOSITORY.get() {
+        return Ok(repository.as_ref());
+    }
+
+    // NB: opening is idempotent (the migrations run in transactions),
+    // if two threads get here at the same time one of the two is simply dropped
+    let repository = SqliteRepository::open(Path::new(DATABASE_PATH), &LegacyJsonFiles::default())?;
+    Ok(REPOSITORY.get_or_init(|| Box::new(repository)).as_ref())
+}
+
+// The repository blocks (the connection sits behind a lock, and SQLite does its IO
+// synchronously): async code goes through here, so that it runs on the blocking threads
+pub async fn run_blocking<T, F>(f: F) -> anyhow::Result<T>
+where
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
+// Keep track of a file that was just rendered
+pub fn record_asset(
+    owner_id: &str,
+    item_index: Option<usize>,
+    kind: AssetKind,
+    archive_version: Option<i32>,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let size_bytes = match fs::metadata(path) {
+        Ok(r) => r.len(),
+        Err(e) => {
+            let message = format!("Failed to read {}. Error: {}", path.display(), e);
+            anyhow::bail!(message);
+        }
+    };
+    let asset = GeneratedAsset {
+        owner_id: String::from(owner_id),
+        item_index,
+        kind,
+        archive_version,
+        path: path.to_path_buf(),
+        size_bytes,
+        created_at: Utc::now().to_rfc3339(),
+    };
+    repository()?.save_asset(&asset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::core::test_utils::TestDir;
+    use crate::core::versions::SwitchReason;
+
+    fn open_repository(dir: &TestDir) -> Box<dyn Repository> {
+        let files = LegacyJsonFiles {
+            versions_path: dir.path.join("versions.json"),
+            jobs_dir: dir.path.join("jobs"),
+            ingests_dir: dir.path.join("ingests"),
+            recipes_dir: dir.path.join("recipes"),
+        };
+        let repository = SqliteRepository::open(&dir.path.join("webapp.sqlite"), &files).unwrap();
+        Box::new(repository)
+    }
+
+    fn job(job_id: &str, status: JobStatus, created_at: &str) -> JobRecord {
+        JobRecord {
+            job_id: String::from(job_id),
+            status,
+            created_at: String::from(created_at),
+            finished_at: None,
+            archive_version: Some(1),
+            recipe: None,
+            progress: None,
+            error: None,
+            output_path: None,
+        }
+    }
+
+    fn asset(owner_id: &str, item_index: usize, path: &str, size_bytes: u64) -> GeneratedAsset {
+        GeneratedAsset {
+            owner_id: String::from(owner_id),
+            item_index: Some(item_index),
+            kind: AssetKind::Image,
+            archive_version: Some(1),
+            path: PathBuf::from(path),
+            size_bytes,
+            created_at: String::from("2022-11-02T10:00:00+00:00"),
+        }
+    }
+
+    #[test]
+    fn keeps_the_history_of_the_versions() {
+        let dir = TestDir::new("repository");
+        let repository = open_repository(&dir);
+
+        let mut data = VersionsData {
+            last_version: 2,
+            last_modified: String::from("2022-11-02T10:00:00+00:00"),
+            current_version: None,
+            history: vec![],
+        };
+        repository.save_versions(&data, None).unwrap();
+
+        data.current_version = Some(1);
+        let switch = VersionSwitch {
+            version: 1,
+            previous_version: Some(2),
+            reason: SwitchReason::Rollback,
+            switched_at: String::from("2022-11-03T10:00:00+00:00"),
+        };
+        repository.save_versions(&data, Some(&switch)).unwrap();
+
+        let loaded = repository.load_versions().unwrap().unwrap();
+        assert_eq!(loaded.last_version, 2);
+        assert_eq!(loaded.current_version(), 1);
+        assert_eq!(loaded.history.len(), 1);
+        assert_eq!(loaded.history[0].reason, SwitchReason::Rollback);
+        assert_eq!(loaded.history[0].previous_version, Some(2));
+    }
+
+    #[test]
+    fn lists_the_jobs_by_status() {
+        let dir = TestDir::new("repository");
+        let repository = open_repository(&dir);
+
+        let mut first = job("job-1", JobStatus::STARTED, "2022-11-02T10:00:00+00:00");
+        repository.save_job(&first).unwrap();
+        let second = job("job-2", JobStatus::STARTED, "2022-11-02T11:00:00+00:00");
+        repository.save_job(&second).unwrap();
+        first.status = JobStatus::FAILED;
+        first.error = Some(String::from("Missing layer"));
+        repository.save_job(&first).unwrap();
+
+        let started = repository
+            .list_jobs_with_status(JobStatus::STARTED)
+            .unwrap();
+        let started: Vec<&str> = started.iter().map(|j| j.job_id.as_str()).collect();
+        assert_eq!(started, vec!["job-2"]);
+
+        let failed = repository.load_job("job-1").unwrap().unwrap();
+        assert_eq!(failed.status, JobStatus::FAILED);
+        assert_eq!(failed.error.as_deref(), Some("Missing layer"));
+        assert!(repository.load_job("job-3").unwrap().is_none());
+    }
+
+    #[test]
+    fn keeps_the_recipes_of_each_version_oldest_first() {
+        let dir = TestDir::new("repository");
+        let repository = open_repository(&dir);
+
+        for (version, checksum, created_at) in [
+            (1, "bbb", "2022-11-02T10:00:00+00:00"),
+            (2, "bbb", "2022-11-02T11:00:00+00:00"),
+            (1, "aaa", "2022-11-02T12:00:00+00:00"),
+        ] {
+            let recipe = RegisteredRecipe {
+                checksum: String::from(checksum),
+                layers: vec![String::from("01_background/Background_C_01.png")],
+                created_at: String::from(created_at),
+            };
+            assert!(repository.insert_recipe(version, &recipe).unwrap());
+        }
+
+        let recipes = repository.load_recipes(1).unwrap();
+        let checksums: Vec<&str> = recipes.iter().map(|r| r.checksum.as_str()).collect();
+        assert_eq!(checksums, vec!["bbb", "aaa"]);
+        assert_eq!(repository.count_recipes(2).unwrap(), 1);
+        assert_eq!(repository.count_recipes(3).unwrap(), 0);
+    }
+
+    #[test]
+    fn replaces_the_assets_rendered_again() {
+        let dir = TestDir::new("repository");
+        let repository = open_repository(&dir);
+
+        repository
+            .save_asset(&asset("batch-1", 2, "/batches/batch-1/00002.png", 10))
+            .unwrap();
+        repository
+            .save_asset(&asset("batch-1", 1, "/batches/batch-1/00001.png", 10))
+            .unwrap();
+        repository
+            .save_asset(&asset("batch-2", 1, "/batches/batch-2/00001.png", 10))
+            .unwrap();
+        repository
+            .save_asset(&asset("batch-1", 2, "/batches/batch-1/00002.png", 20))
+            .unwrap();
+
+        let assets = repository.list_assets("batch-1").unwrap();
+        let assets: Vec<(Option<usize>, u64)> = assets
+            .iter()
+            .map(|a| (a.item_index, a.size_bytes))
+            .collect();
+        assert_eq!(assets, vec![(Some(1), 10), (Some(2), 20)]);
+    }
+}
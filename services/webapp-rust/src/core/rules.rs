@@ -0,0 +1,521 @@
+// Compatibility rules between the layers of a recipe.
+//
+// Every archive version can have its own rules (RULES_ROOT_DIR/<version>.json).
+// Rules refer to layers through regular expressions matched against their path,
+// relative to the entry point (EG: '03_ears/ear_1/01_ear_1_skins/Ear_1_Standard_pink.png'):
+//   requires      -> if a layer matching `layer` is used, a layer matching `requires` must be used too
+//   excludes      -> if a layer matching `layer` is used, no layer matching `excludes` can be used
+//   same_stream_as -> layers matching `layer` must be of the same stream as the layer matching
+//                    `same_stream_as` (EG: the skin of the ears must match the one of the body)
+//
+// Without a saved file, the default rules reproduce what generate_permutation.py did with its
+// CURRENT_STREAM: every skin must be of the same stream as the body skin.
+
+use std::path::{Path, PathBuf};
+
+use axum::{extract::Query, http::StatusCode, response::Json};
+use regex::Regex;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::{RULES_ROOT_DIR, SKINS_DIR_NAME, ZFILL_PADDING};
+use crate::core::recipe::{find_invalid_layers, get_stream, parse_recipe};
+use crate::core::repository::run_blocking;
+use crate::core::{find_entry_point, get_requested_version, read_json, write_json_atomically};
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleKind {
+    Requires {
+        layer: String,
+        requires: String,
+    },
+    Excludes {
+        layer: String,
+        excludes: String,
+    },
+    SameStreamAs {
+        layer: String,
+        same_stream_as: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    // Free text, to remember why the rule is there
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(flatten)]
+    pub kind: RuleKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RulesManifest {
+    pub rules: Vec<Rule>,
+}
+
+impl Default for RulesManifest {
+    fn default() -> Self {
+        RulesManifest {
+            rules: vec![Rule {
+                description: Some(String::from(
+                    "Every skin must be of the same stream as the body skin",
+                )),
+                kind: RuleKind::SameStreamAs {
+                    // Images directly inside a directory with 'skins' in its name
+                    layer: String::from(r"skins[^/]*/[^/]+$"),
+                    same_stream_as: format!(r"^{}/[^/]+$", SKINS_DIR_NAME),
+                },
+            }],
+        }
+    }
+}
+
+impl RulesManifest {
+    // Whether these are the default rules (the descriptions don't matter)
+    pub fn is_default(&self) -> bool {
+        let default_manifest = RulesManifest::default();
+        let kinds = self.rules.iter().map(|r| &r.kind);
+        kinds.eq(default_manifest.rules.iter().map(|r| &r.kind))
+    }
+}
+
+// A rule with its regular expressions compiled
+struct CompiledRule {
+    index: usize,
+    rule: Rule,
+    layer: Regex,
+    other: Regex,
+}
+
+// The rules of an archive version, ready to be checked against recipes
+pub struct RuleSet {
+    rules: Vec<CompiledRule>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleViolation {
+    // Position of the rule in the manifest
+    pub rule_index: usize,
+    pub rule: Rule,
+    pub message: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RulesQuery {
+    pub version: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RulesData {
+    version: i32,
+    // Whether the rules were never saved and the defaults are being used
+    is_default: bool,
+    #[serde(flatten)]
+    manifest: RulesManifest,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RulesCheckData {
+    version: i32,
+    valid: bool,
+    violations: Vec<RuleViolation>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+fn get_layer_stream(layer: &str) -> Option<String> {
+    let file_name = layer.rsplit('/').next().unwrap_or(layer);
+    get_stream(file_name)
+}
+
+impl RuleSet {
+    // Fails listing every rule that can't be compiled
+    pub fn compile(manifest: &RulesManifest) -> anyhow::Result<RuleSet> {
+        let mut rules = Vec::new();
+        let mut errors = Vec::new();
+
+        for (index, rule) in manifest.rules.iter().enumerate() {
+            let (layer_pattern, other_pattern) = match &rule.kind {
+                RuleKind::Requires { layer, requires } => (layer, requires),
+                RuleKind::Excludes { layer, excludes } => (layer, excludes),
+                RuleKind::SameStreamAs {
+                    layer,
+                    same_stream_as,
+                } => (layer, same_stream_as),
+            };
+
+            let mut compile = |pattern: &str| match Regex::new(pattern) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    errors.push(format!(
+                        "Rule {}: invalid pattern '{}'. {}",
+                        index, pattern, e
+                    ));
+                    None
+                }
+            };
+            let layer = compile(layer_pattern);
+            let other = compile(other_pattern);
+
+            if let (Some(layer), Some(other)) = (layer, other) {
+                rules.push(CompiledRule {
+                    index,
+                    rule: rule.clone(),
+                    layer,
+                    other,
+                });
+            }
+        }
+
+        if !errors.is_empty() {
+            anyhow::bail!(errors.join("\n"));
+        }
+
+        Ok(RuleSet { rules })
+    }
+
+    // Check a single pair of layers against a rule.
+    // `requires` rules can't be broken by a pair, only by what is missing from a whole recipe.
+    fn check_pair(compiled: &CompiledRule, layer: &str, other: &str) -> Option<String> {
+        if layer == other || !compiled.layer.is_match(layer) || !compiled.other.is_match(other) {
+            return None;
+        }
+
+        match &compiled.rule.kind {
+            RuleKind::Requires { .. } => None,
+            RuleKind::Excludes { .. } => {
+                Some(format!("{} can't be used together with {}", layer, other))
+            }
+            RuleKind::SameStreamAs { .. } => {
+                let expected_stream = match get_layer_stream(other) {
+                    Some(s) if !s.is_empty() => s,
+                    // The other layer doesn't define a stream, so anything goes
+                    _ => return None,
+                };
+                match get_layer_stream(layer) {
+                    Some(stream) if stream == expected_stream => None,
+                    Some(stream) => Some(format!(
+                        "{} is of stream '{}', but it must be of the same stream as {} ('{}')",
+                        layer, stream, other, expected_stream
+                    )),
+                    None => Some(format!(
+                        "{} has no stream, but it must be of the same stream as {} ('{}')",
+                        layer, other, expected_stream
+                    )),
+                }
+            }
+        }
+    }
+
+    // Why `candidate` can't be added to a recipe that already contains `layers`, if it can't.
+    // Used while generating, so that only compatible layers are picked.
+    pub fn find_conflict(&self, layers: &[String], candidate: &str) -> Option<String> {
+        for compiled in &self.rules {
+            for layer in layers {
+                let conflict = Self::check_pair(compiled, candidate, layer)
+                    .or_else(|| Self::check_pair(compiled, layer, candidate));
+                if conflict.is_some() {
+                    return conflict;
+                }
+            }
+        }
+        None
+    }
+
+    // Every rule broken by a complete recipe
+    pub fn find_violations(&self, layers: &[String]) -> Vec<RuleViolation> {
+        let mut violations = Vec::new();
+
+        for compiled in &self.rules {
+            let mut add_violation = |message: String| {
+                violations.push(RuleViolation {
+                    rule_index: compiled.index,
+                    rule: compiled.rule.clone(),
+                    message,
+                })
+            };
+
+            match &compiled.rule.kind {
+                RuleKind::Requires { requires, .. } => {
+                    for layer in layers.iter().filter(|l| compiled.layer.is_match(l)) {
+                        let is_satisfied = layers
+                            .iter()
+                            .any(|other| other != layer && compiled.other.is_match(other));
+                        if !is_satisfied {
+                            add_violation(format!(
+                                "{} requires a layer matching '{}', but there is none",
+                                layer, requires
+                            ));
+                        }
+                    }
+                }
+                RuleKind::Excludes { .. } | RuleKind::SameStreamAs { .. } => {
+                    for layer in layers {
+                        for other in layers {
+                            if let Some(message) = Self::check_pair(compiled, layer, other) {
+                                add_violation(message);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+fn get_rules_path(version: i32) -> PathBuf {
+    Path::new(RULES_ROOT_DIR).join(format!("{:0ZFILL_PADDING$}.json", version))
+}
+
+fn load_saved_rules(version: i32) -> anyhow::Result<Option<RulesManifest>> {
+    let rules_path = get_rules_path(version);
+    if !rules_path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(read_json(&rules_path)?))
+}
+
+// The rules of the version, or the default ones if they were never saved
+pub fn load_rules(version: i32) -> anyhow::Result<RuleSet> {
+    let manifest = load_saved_rules(version)?.unwrap_or_default();
+    RuleSet::compile(&manifest)
+}
+
+// Whether the version follows the default rules, saved or not
+pub fn uses_default_rules(version: i32) -> anyhow::Result<bool> {
+    let manifest = load_saved_rules(version)?.unwrap_or_default();
+    Ok(manifest.is_default())
+}
+
+pub fn save_rules(version: i32, manifest: &RulesManifest) -> anyhow::Result<()> {
+    match std::fs::create_dir_all(RULES_ROOT_DIR) {
+        Ok(_) => {}
+        Err(e) => {
+            let message = format!("Failed to create {}. Error: {}", RULES_ROOT_DIR, e);
+            anyhow::bail!(message);
+        }
+    }
+    write_json_atomically(&get_rules_path(version), manifest)
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+pub async fn get_rules(query: Query<RulesQuery>) -> Result<Json<RulesData>, (StatusCode, String)> {
+    let version = get_requested_version(query.version).await?;
+
+    let saved_rules = run_blocking(move || load_saved_rules(version))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(RulesData {
+        version,
+        is_default: saved_rules.is_none(),
+        manifest: saved_rules.unwrap_or_default(),
+    }))
+}
+
+// Replace the rules of a version
+pub async fn put_rules(
+    query: Query<RulesQuery>,
+    Json(manifest): Json<RulesManifest>,
+) -> Result<Json<RulesData>, (StatusCode, String)> {
+    let version = get_requested_version(query.version).await?;
+
+    // Make sure the archive exists
+    find_entry_point(version)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+
+    match RuleSet::compile(&manifest) {
+        Ok(_) => {}
+        Err(e) => {
+            let message = format!("The rules are not valid:\n{}", e);
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, message));
+        }
+    }
+
+    let saved_manifest = manifest.clone();
+    run_blocking(move || save_rules(version, &saved_manifest))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(RulesData {
+        version,
+        is_default: false,
+        manifest,
+    }))
+}
+
+// Explain why a recipe (in the same formats accepted by /api/generate) breaks the rules, if it does
+pub async fn check_recipe(
+    query: Query<RulesQuery>,
+    body: String,
+) -> Result<Json<RulesCheckData>, (StatusCode, String)> {
+    let version = get_requested_version(query.version).await?;
+
+    let entry_point = find_entry_point(version)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+
+    let recipe =
+        parse_recipe(&body, &entry_point).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let checked_recipe = recipe.clone();
+    let invalid_layers = tokio::task::spawn_blocking(move || find_invalid_layers(&checked_recipe))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !invalid_layers.is_empty() {
+        let message = format!(
+            "The recipe doesn't match archive version {:0ZFILL_PADDING$}:\n{}",
+            version,
+            invalid_layers.join("\n")
+        );
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, message));
+    }
+
+    let rules = run_blocking(move || load_rules(version))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let violations = rules.find_violations(&recipe.layers);
+
+    Ok(Json(RulesCheckData {
+        version,
+        valid: violations.is_empty(),
+        violations,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY_PINK: &str = "02_body_skins/Body_Skin_Standard_pink.png";
+    const EAR_PINK: &str = "03_ears/ear_1/01_ear_1_skins/Ear_1_Standard_pink.png";
+    const EAR_ZEBRA: &str = "03_ears/ear_1/01_ear_1_skins/Ear_1_Tiger_zebra.png";
+    const EAR_LINE: &str = "03_ears/ear_1/02_ear_1_lines/Ear_1_Line.png";
+    const BACKGROUND: &str = "01_background/common_background/Background_C_01.png";
+
+    fn layers(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|p| String::from(*p)).collect()
+    }
+
+    fn rule(kind: RuleKind) -> Rule {
+        Rule {
+            description: None,
+            kind,
+        }
+    }
+
+    fn compile(rules: Vec<Rule>) -> RuleSet {
+        RuleSet::compile(&RulesManifest { rules }).unwrap()
+    }
+
+    #[test]
+    fn conflicts_with_skins_of_another_stream() {
+        let rules = RuleSet::compile(&RulesManifest::default()).unwrap();
+        let chosen = layers(&[BACKGROUND, BODY_PINK]);
+
+        assert!(rules.find_conflict(&chosen, EAR_PINK).is_none());
+        assert!(rules.find_conflict(&chosen, EAR_LINE).is_none());
+        let conflict = rules.find_conflict(&chosen, EAR_ZEBRA).unwrap();
+        assert!(conflict.contains("'Tiger_zebra'"), "{conflict}");
+
+        // The order in which the layers are picked doesn't matter
+        let conflict = rules.find_conflict(&layers(&[EAR_ZEBRA]), BODY_PINK);
+        assert!(conflict.is_some());
+    }
+
+    #[test]
+    fn conflicts_with_excluded_layers() {
+        let rules = compile(vec![rule(RuleKind::Excludes {
+            layer: String::from("^01_background/"),
+            excludes: String::from("Line"),
+        })]);
+
+        assert!(rules
+            .find_conflict(&layers(&[BACKGROUND]), EAR_LINE)
+            .is_some());
+        assert!(rules
+            .find_conflict(&layers(&[EAR_LINE]), BACKGROUND)
+            .is_some());
+        assert!(rules
+            .find_conflict(&layers(&[BACKGROUND]), EAR_PINK)
+            .is_none());
+    }
+
+    #[test]
+    fn finds_every_violation() {
+        let rules = compile(vec![
+            rule(RuleKind::Requires {
+                layer: String::from("^03_ears/"),
+                requires: String::from("^04_tail/"),
+            }),
+            rule(RuleKind::Excludes {
+                layer: String::from("^01_background/"),
+                excludes: String::from("Line"),
+            }),
+            RulesManifest::default().rules.remove(0),
+        ]);
+
+        let violations = rules.find_violations(&layers(&[BACKGROUND, BODY_PINK, EAR_ZEBRA]));
+        let indexes: Vec<usize> = violations.iter().map(|v| v.rule_index).collect();
+        assert_eq!(indexes, vec![0, 2]);
+
+        let violations = rules.find_violations(&layers(&[BACKGROUND, EAR_LINE]));
+        let indexes: Vec<usize> = violations.iter().map(|v| v.rule_index).collect();
+        assert_eq!(indexes, vec![0, 1]);
+
+        let tail = "04_tail/Tail_1.png";
+        assert!(rules
+            .find_violations(&layers(&[BACKGROUND, BODY_PINK, EAR_PINK, tail]))
+            .is_empty());
+    }
+
+    #[test]
+    fn lists_every_invalid_pattern() {
+        let manifest = RulesManifest {
+            rules: vec![
+                rule(RuleKind::Requires {
+                    layer: String::from("("),
+                    requires: String::from("["),
+                }),
+                rule(RuleKind::Excludes {
+                    layer: String::from("^01_background/"),
+                    excludes: String::from("Line"),
+                }),
+            ],
+        };
+
+        let error = RuleSet::compile(&manifest).err().unwrap().to_string();
+        // Both patterns of the first rule, nothing about the second one
+        assert!(error.contains("Rule 0: invalid pattern '('"), "{error}");
+        assert!(error.contains("Rule 0: invalid pattern '['"), "{error}");
+        assert!(!error.contains("Rule 1"), "{error}");
+    }
+
+    #[test]
+    fn ignores_descriptions_when_comparing_with_the_defaults() {
+        let mut manifest = RulesManifest::default();
+        manifest.rules[0].description = None;
+        assert!(manifest.is_default());
+
+        manifest.rules.push(rule(RuleKind::Excludes {
+            layer: String::from("^01_background/"),
+            excludes: String::from("Line"),
+        }));
+        assert!(!manifest.is_default());
+        assert!(!RulesManifest { rules: vec![] }.is_default());
+    }
+}
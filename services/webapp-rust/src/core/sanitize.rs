@@ -0,0 +1,587 @@
+// Sanitization of the names of the directories and files of an uploaded archive.
+// This is a port of what scripts/sanitize_directories.py used to do, without going
+// through a generated shell script.
+//
+// The archive is walked bottom-up: every directory whose name isn't clean is renamed and,
+// inside of it, hidden and .ini files are removed and the other files are renamed too.
+// NB: like in the python script, the files of directories that already have a clean name
+// are left as they are (the stream patterns depend on names like 'Body_Skin_Tiger_zebra.png').
+//
+// Every operation is recorded in a manifest (SANITIZE_ROOT_DIR/<version>.json).
+// The same plan can be previewed before uploading, from an archive or from a listing of its paths.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use axum::{body::Bytes, http::StatusCode, response::Json};
+use chrono::Utc;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::{
+    ENTRY_POINT_DIR_NAME, SANITIZED_ENTRY_POINT_DIR_NAME, SANITIZE_NUM_PADDING, SANITIZE_ROOT_DIR,
+};
+use crate::core::{archive_format, write_json_atomically};
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+// A directory or file of an archive, relative to its root (EG: 'sphynx/programm/01_background')
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    pub path: String,
+    pub is_dir: bool,
+}
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SanitizeAction {
+    RENAME,
+    REMOVE,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SanitizeOperation {
+    pub action: SanitizeAction,
+    pub from: String,
+    // Only for renames
+    pub to: Option<String>,
+}
+
+// Different entries of the same directory that would end up with the same name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NameCollision {
+    pub parent: String,
+    pub name: String,
+    pub sources: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SanitizePlan {
+    // In the order they must be applied
+    pub operations: Vec<SanitizeOperation>,
+    pub collisions: Vec<NameCollision>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RenamePreview {
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SanitizePreview {
+    // How many directories and files were looked at
+    entries: usize,
+    renames: Vec<RenamePreview>,
+    removals: Vec<String>,
+    collisions: Vec<NameCollision>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SanitizeManifest {
+    pub version: String,
+    pub sanitized_at: String,
+    pub operations: Vec<SanitizeOperation>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+// EG: 'Body Skin-Tiger&zebra 3.png' -> 'body_skin_tiger_and_zebra_03.png'
+//  1. names of archive versions ('001') are left untouched
+//  2. the entry point 'programm' becomes 'program'
+//  3. spaces and hyphens become underscores, and repeated underscores are collapsed
+//  4. '+' becomes 'plus' and '&' becomes '_and_'
+//  5. a trailing number is zero-padded, and split from the word it's attached to
+//     (EG: 'Ear 1' and 'Ear1' -> 'ear_01')
+//  6. anything but letters, digits, '_' and '.' is dropped, and everything is lowercased
+pub fn sanitize_name(file_name: &str) -> String {
+    if file_name.len() >= 3 && file_name.chars().take(3).all(|c| c.is_ascii_digit()) {
+        return String::from(file_name);
+    }
+
+    if file_name == ENTRY_POINT_DIR_NAME {
+        return String::from(SANITIZED_ENTRY_POINT_DIR_NAME);
+    }
+
+    // Same as python's os.path.splitext: leading dots don't start an extension
+    let leading_dots = file_name.len() - file_name.trim_start_matches('.').len();
+    let (name, ext) = match file_name[leading_dots..].rfind('.') {
+        Some(i) => file_name.split_at(leading_dots + i),
+        None => (file_name, ""),
+    };
+
+    let mut name = name.replace([' ', '-'], "_");
+    while name.contains("__") {
+        name = name.replace("__", "_");
+    }
+
+    let name = name.replace('+', "plus").replace('&', "_and_");
+
+    let mut tokens: Vec<String> = name.split('_').map(String::from).collect();
+    if let Some(last_token) = tokens.pop() {
+        // The number can be attached to a word (EG: 'Ear1' -> 'Ear', '1')
+        let word = last_token.trim_end_matches(|c: char| c.is_ascii_digit());
+        let digits = &last_token[word.len()..];
+        match digits.parse::<u64>() {
+            Ok(number) => {
+                if !word.is_empty() {
+                    tokens.push(String::from(word));
+                }
+                tokens.push(format!("{:0width$}", number, width = SANITIZE_NUM_PADDING));
+            }
+            Err(_) => tokens.push(last_token),
+        }
+    }
+    let name = tokens.join("_");
+
+    let name: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.')
+        .collect();
+
+    format!("{}{}", name, ext).to_lowercase()
+}
+
+fn get_parent(path: &str) -> &str {
+    match path.rsplit_once('/') {
+        Some((parent, _)) => parent,
+        None => "",
+    }
+}
+
+fn get_name(path: &str) -> &str {
+    match path.rsplit_once('/') {
+        Some((_, name)) => name,
+        None => path,
+    }
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        return String::from(name);
+    }
+    format!("{}/{}", parent, name)
+}
+
+fn is_removable_file(file_name: &str) -> bool {
+    file_name.starts_with('.') || file_name.ends_with(".ini")
+}
+
+// Work out what needs to be renamed or removed, without touching anything
+pub fn plan_sanitization(entries: &[ArchiveEntry]) -> SanitizePlan {
+    // Parent -> files in it
+    let mut files_by_dir: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    let mut dirs: Vec<&str> = Vec::new();
+    for entry in entries {
+        let path = entry.path.trim_matches('/');
+        if path.is_empty() {
+            continue;
+        }
+        if entry.is_dir {
+            dirs.push(path);
+        } else {
+            files_by_dir.entry(get_parent(path)).or_default().push(path);
+        }
+    }
+
+    // Bottom-up, so that the path of everything is still the original one when it's touched
+    dirs.sort_by(|a, b| {
+        let depth = |p: &str| p.matches('/').count();
+        depth(b).cmp(&depth(a)).then(a.cmp(b))
+    });
+
+    let mut operations = Vec::new();
+    // Parent -> final name -> original paths, to find collisions
+    let mut final_names: BTreeMap<String, BTreeMap<String, Vec<String>>> = BTreeMap::new();
+    let mut add_final_name = |path: &str, name: String| {
+        final_names
+            .entry(String::from(get_parent(path)))
+            .or_default()
+            .entry(name)
+            .or_default()
+            .push(String::from(path));
+    };
+
+    for dir in &dirs {
+        let dir_name = get_name(dir);
+        let sanitized_dir_name = sanitize_name(dir_name);
+        let files = files_by_dir.remove(dir).unwrap_or_default();
+
+        if sanitized_dir_name == dir_name {
+            for file in files {
+                add_final_name(file, String::from(get_name(file)));
+            }
+            add_final_name(dir, sanitized_dir_name);
+            continue;
+        }
+
+        for file in files {
+            let file_name = get_name(file);
+            if is_removable_file(file_name) {
+                operations.push(SanitizeOperation {
+                    action: SanitizeAction::REMOVE,
+                    from: String::from(file),
+                    to: None,
+                });
+                continue;
+            }
+
+            let sanitized_file_name = sanitize_name(file_name);
+            if sanitized_file_name != file_name {
+                operations.push(SanitizeOperation {
+                    action: SanitizeAction::RENAME,
+                    from: String::from(file),
+                    to: Some(join_path(dir, &sanitized_file_name)),
+                });
+            }
+            add_final_name(file, sanitized_file_name);
+        }
+
+        operations.push(SanitizeOperation {
+            action: SanitizeAction::RENAME,
+            from: String::from(*dir),
+            to: Some(join_path(get_parent(dir), &sanitized_dir_name)),
+        });
+        add_final_name(dir, sanitized_dir_name);
+    }
+
+    // Files at the root, or in directories that weren't listed, are left as they are
+    for files in files_by_dir.values() {
+        for file in files {
+            add_final_name(file, String::from(get_name(file)));
+        }
+    }
+
+    let mut collisions = Vec::new();
+    for (parent, names) in final_names {
+        for (name, sources) in names {
+            if sources.len() > 1 {
+                collisions.push(NameCollision {
+                    parent: parent.clone(),
+                    name,
+                    sources,
+                });
+            }
+        }
+    }
+
+    SanitizePlan {
+        operations,
+        collisions,
+    }
+}
+
+fn collect_entries(root: &Path, dir: &Path, entries: &mut Vec<ArchiveEntry>) -> anyhow::Result<()> {
+    let dir_entries = match fs::read_dir(dir) {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!("Failed to read directory {}. Error: {}", dir.display(), e);
+            anyhow::bail!(message);
+        }
+    };
+
+    for entry in dir_entries.flatten() {
+        let entry_path = entry.path();
+        let relative_path = match entry_path.strip_prefix(root) {
+            Ok(p) => p.to_string_lossy().replace('\\', "/"),
+            Err(_) => continue,
+        };
+        let file_type = match entry.file_type() {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+
+        if file_type.is_dir() {
+            entries.push(ArchiveEntry {
+                path: relative_path,
+                is_dir: true,
+            });
+            collect_entries(root, &entry_path, entries)?;
+        } else {
+            entries.push(ArchiveEntry {
+                path: relative_path,
+                is_dir: false,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Every directory and file under the root, relative to it
+pub fn list_entries(root: &Path) -> anyhow::Result<Vec<ArchiveEntry>> {
+    let mut entries = Vec::new();
+    collect_entries(root, root, &mut entries)?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+// Paths ending with '/' are directories, and so are the parents of every path
+// (archives don't necessarily have an entry for every directory)
+fn entries_from_paths<'a>(paths: impl Iterator<Item = (&'a str, bool)>) -> Vec<ArchiveEntry> {
+    let mut dirs = BTreeSet::new();
+    let mut files = BTreeSet::new();
+
+    for (path, is_dir) in paths {
+        let is_dir = is_dir || path.ends_with('/');
+        let path = path.trim_start_matches("./").trim_matches('/');
+        if path.is_empty() || path == "." {
+            continue;
+        }
+
+        let mut parent = get_parent(path);
+        while !parent.is_empty() {
+            dirs.insert(String::from(parent));
+            parent = get_parent(parent);
+        }
+        if is_dir {
+            dirs.insert(String::from(path));
+        } else {
+            files.insert(String::from(path));
+        }
+    }
+
+    dirs.iter()
+        .map(|path| ArchiveEntry {
+            path: path.clone(),
+            is_dir: true,
+        })
+        .chain(
+            files
+                .iter()
+                .filter(|f| !dirs.contains(*f))
+                .map(|path| ArchiveEntry {
+                    path: path.clone(),
+                    is_dir: false,
+                }),
+        )
+        .collect()
+}
+
+// A JSON array of paths, or one path per line
+fn list_listing_entries(data: &[u8]) -> anyhow::Result<Vec<ArchiveEntry>> {
+    let text = match std::str::from_utf8(data) {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!("The listing is not valid UTF8: {}", e);
+            anyhow::bail!(message);
+        }
+    };
+
+    if text.trim_start().starts_with('[') {
+        let paths: Vec<String> = match serde_json::from_str(text) {
+            Ok(r) => r,
+            Err(e) => {
+                let message = format!("The listing is not a valid JSON array of paths: {}", e);
+                anyhow::bail!(message);
+            }
+        };
+        return Ok(entries_from_paths(
+            paths.iter().map(|path| (path.as_str(), false)),
+        ));
+    }
+
+    Ok(entries_from_paths(
+        text.lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| (line, false)),
+    ))
+}
+
+pub fn preview_sanitization(data: &[u8]) -> anyhow::Result<SanitizePreview> {
+    let entries = match archive_format::list_entries(data) {
+        Some(paths) => {
+            let paths = paths?;
+            entries_from_paths(paths.iter().map(|(path, is_dir)| (path.as_str(), *is_dir)))
+        }
+        None => list_listing_entries(data)?,
+    };
+    let plan = plan_sanitization(&entries);
+
+    let mut renames = Vec::new();
+    let mut removals = Vec::new();
+    for operation in plan.operations {
+        match (operation.action, operation.to) {
+            (SanitizeAction::RENAME, Some(to)) => renames.push(RenamePreview {
+                from: operation.from,
+                to,
+            }),
+            (SanitizeAction::REMOVE, _) => removals.push(operation.from),
+            (SanitizeAction::RENAME, None) => {}
+        }
+    }
+
+    Ok(SanitizePreview {
+        entries: entries.len(),
+        renames,
+        removals,
+        collisions: plan.collisions,
+    })
+}
+
+fn apply_operation(root: &Path, operation: &SanitizeOperation) -> anyhow::Result<()> {
+    let from = root.join(&operation.from);
+
+    match operation.action {
+        SanitizeAction::REMOVE => match fs::remove_file(&from) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let message = format!("Failed to remove {}. Error: {}", from.display(), e);
+                anyhow::bail!(message);
+            }
+        },
+        SanitizeAction::RENAME => {
+            let to: PathBuf = match &operation.to {
+                Some(to) => root.join(to),
+                None => {
+                    let message = format!("Missing destination to rename {}", from.display());
+                    anyhow::bail!(message);
+                }
+            };
+            // Never overwrite anything (or move a directory inside another one)
+            if to.exists() {
+                let message = format!(
+                    "Can't rename {} to {}: the destination exists already",
+                    from.display(),
+                    to.display()
+                );
+                anyhow::bail!(message);
+            }
+            match fs::rename(&from, &to) {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    let message = format!(
+                        "Failed to rename {} to {}. Error: {}",
+                        from.display(),
+                        to.display(),
+                        e
+                    );
+                    anyhow::bail!(message);
+                }
+            }
+        }
+    }
+}
+
+// Sanitize everything under `root` (the extracted archive of `version`) and record what was done
+pub fn sanitize_directory(root: &Path, version: &str) -> anyhow::Result<SanitizeManifest> {
+    let entries = list_entries(root)?;
+    let plan = plan_sanitization(&entries);
+
+    if !plan.collisions.is_empty() {
+        let collisions: Vec<String> = plan
+            .collisions
+            .iter()
+            .map(|c| {
+                format!(
+                    "{} <- {}",
+                    join_path(&c.parent, &c.name),
+                    c.sources.join(", ")
+                )
+            })
+            .collect();
+        let message = format!(
+            "Sanitizing the archive would give the same name to different files:\n{}",
+            collisions.join("\n")
+        );
+        anyhow::bail!(message);
+    }
+
+    for operation in &plan.operations {
+        apply_operation(root, operation)?;
+    }
+
+    let manifest = SanitizeManifest {
+        version: String::from(version),
+        sanitized_at: Utc::now().to_rfc3339(),
+        operations: plan.operations,
+    };
+
+    match fs::create_dir_all(SANITIZE_ROOT_DIR) {
+        Ok(_) => {}
+        Err(e) => {
+            let message = format!("Failed to create {}. Error: {}", SANITIZE_ROOT_DIR, e);
+            anyhow::bail!(message);
+        }
+    }
+    let manifest_path = Path::new(SANITIZE_ROOT_DIR).join(format!("{}.json", version));
+    write_json_atomically(&manifest_path, &manifest)?;
+
+    Ok(manifest)
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+// What the sanitization would do to an archive, without touching anything.
+// The body is either an archive (.tar.gz, .tar.zst, .tar or .zip) or a listing of its paths (directories end with '/')
+pub async fn preview(body: Bytes) -> Result<Json<SanitizePreview>, (StatusCode, String)> {
+    if body.is_empty() {
+        let message = String::from("Send an archive or a listing of its paths");
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+
+    let preview = tokio::task::spawn_blocking(move || preview_sanitization(&body))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    Ok(Json(preview))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_trailing_numbers() {
+        assert_eq!(sanitize_name("Ear 1"), "ear_01");
+        assert_eq!(sanitize_name("Ear_12.png"), "ear_12.png");
+        assert_eq!(
+            sanitize_name("Body Skin-Tiger&zebra 3.png"),
+            "body_skin_tiger_and_zebra_03.png"
+        );
+    }
+
+    #[test]
+    fn splits_numbers_attached_to_words() {
+        assert_eq!(sanitize_name("Ear1"), "ear_01");
+        assert_eq!(sanitize_name("Ear1.png"), "ear_01.png");
+        assert_eq!(sanitize_name("Left_Ear01"), "left_ear_01");
+    }
+
+    #[test]
+    fn replaces_special_characters() {
+        assert_eq!(sanitize_name("&"), "_and_");
+        assert_eq!(sanitize_name("Hat+Scarf"), "hatplusscarf");
+        assert_eq!(sanitize_name("Big  (hat)"), "big_hat");
+    }
+
+    #[test]
+    fn keeps_version_names() {
+        assert_eq!(sanitize_name("001"), "001");
+        assert_eq!(sanitize_name("000_Background"), "000_Background");
+    }
+
+    #[test]
+    fn renames_the_entry_point() {
+        assert_eq!(
+            sanitize_name(ENTRY_POINT_DIR_NAME),
+            SANITIZED_ENTRY_POINT_DIR_NAME
+        );
+    }
+
+    #[test]
+    fn leading_dots_dont_start_an_extension() {
+        assert_eq!(sanitize_name(".DS_Store"), ".ds_store");
+        assert_eq!(sanitize_name(".hidden.png"), ".hidden.png");
+    }
+}
@@ -0,0 +1,1058 @@
+// The Repository backed by an embedded SQLite database (DATABASE_PATH).
+//
+// The schema is changed only through the migrations below, applied in order when the
+// database is opened. The number of migrations already applied is kept in the
+// `user_version` of the database.
+//
+// The first time, what was stored in JSON files before the database existed
+// (versions.json, the jobs, ingests and recipes directories) is imported. The files are left
+// where they are, but they are not read any more.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OptionalExtension, Row, TransactionBehavior};
+
+// JSON
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::core::constants::{INGESTS_ROOT_DIR, JOBS_ROOT_DIR, RECIPES_ROOT_DIR, VERSIONS_PATH};
+use crate::core::ingest::IngestRecord;
+use crate::core::jobs::JobRecord;
+use crate::core::repository::{AssetKind, GeneratedAsset, Repository};
+use crate::core::uniqueness::RegisteredRecipe;
+use crate::core::versions::VersionSwitch;
+use crate::core::{read_json, JobStatus, VersionsData};
+
+type Migration = fn(&Connection, &LegacyJsonFiles) -> anyhow::Result<()>;
+
+// NB: only ever append to this list, the applied migrations are counted
+const MIGRATIONS: [Migration; 2] = [create_tables, import_json_files];
+
+// How long to wait for another connection to release the database
+const BUSY_TIMEOUT_MS: u64 = 5_000;
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+pub struct SqliteRepository {
+    // A single connection: every query is short, and SQLite writes one at a time anyway
+    connection: Mutex<Connection>,
+}
+
+// Where the state was stored before the database existed
+pub struct LegacyJsonFiles {
+    pub versions_path: PathBuf,
+    pub jobs_dir: PathBuf,
+    pub ingests_dir: PathBuf,
+    pub recipes_dir: PathBuf,
+}
+
+impl Default for LegacyJsonFiles {
+    fn default() -> Self {
+        LegacyJsonFiles {
+            versions_path: PathBuf::from(VERSIONS_PATH),
+            jobs_dir: PathBuf::from(JOBS_ROOT_DIR),
+            ingests_dir: PathBuf::from(INGESTS_ROOT_DIR),
+            recipes_dir: PathBuf::from(RECIPES_ROOT_DIR),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Migrations
+// -----------------------------------------------------------------------------
+
+fn create_tables(connection: &Connection, _: &LegacyJsonFiles) -> anyhow::Result<()> {
+    check(
+        connection.execute_batch(include_str!("migrations/001_initial.sql")),
+        "create the tables",
+    )
+}
+
+// The JSON files of a directory, one record each
+fn read_json_records<T: DeserializeOwned>(dir: &Path, extension: &str) -> Vec<T> {
+    let entries = match fs::read_dir(dir) {
+        Ok(r) => r,
+        Err(_) => return vec![],
+    };
+
+    let mut records = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(extension) {
+            continue;
+        }
+        // Skips the other files sitting next to the records (EG: '<job_id>.metadata.json')
+        match path.file_stem().and_then(|s| s.to_str()) {
+            Some(s) if !s.contains('.') => {}
+            _ => continue,
+        };
+        match read_json(&path) {
+            Ok(record) => records.push(record),
+            Err(e) => {
+                eprintln!("Skipping {}. {}", path.display(), e);
+            }
+        }
+    }
+    records
+}
+
+// Lines that can't be read are skipped, like the registry did
+fn read_recipes_file(path: &Path) -> Vec<RegisteredRecipe> {
+    let file_contents = match fs::read_to_string(path) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Skipping {}. Error: {}", path.display(), e);
+            return vec![];
+        }
+    };
+    file_contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+fn import_json_files(connection: &Connection, files: &LegacyJsonFiles) -> anyhow::Result<()> {
+    let versions_path = &files.versions_path;
+    if versions_path.exists() {
+        let data: VersionsData = read_json(versions_path)?;
+        write_version_state(connection, &data)?;
+        for switch in &data.history {
+            insert_version_switch(connection, switch)?;
+        }
+        eprintln!("Imported {}", versions_path.display());
+    }
+
+    let job_records: Vec<JobRecord> = read_json_records(&files.jobs_dir, "json");
+    for record in &job_records {
+        write_job(connection, record)?;
+        // The rendered files were named after the job
+        if let Some(output_path) = &record.output_path {
+            import_job_asset(connection, record, AssetKind::Image, output_path)?;
+        }
+        // Named like jobs::get_job_metadata_path does
+        let metadata_path = files
+            .jobs_dir
+            .join(format!("{}.metadata.json", record.job_id));
+        import_job_asset(connection, record, AssetKind::Metadata, &metadata_path)?;
+    }
+    eprintln!(
+        "Imported {} jobs from {}",
+        job_records.len(),
+        files.jobs_dir.display()
+    );
+
+    let ingest_records: Vec<IngestRecord> = read_json_records(&files.ingests_dir, "json");
+    for record in &ingest_records {
+        write_ingest(connection, record)?;
+    }
+    eprintln!(
+        "Imported {} ingests from {}",
+        ingest_records.len(),
+        files.ingests_dir.display()
+    );
+
+    if let Ok(entries) = fs::read_dir(&files.recipes_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("jsonl") {
+                continue;
+            }
+            let version = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(s) => match s.parse::<i32>() {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            let recipes = read_recipes_file(&path);
+            for recipe in &recipes {
+                write_recipe(connection, version, recipe)?;
+            }
+            eprintln!("Imported {} recipes from {}", recipes.len(), path.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn import_job_asset(
+    connection: &Connection,
+    record: &JobRecord,
+    kind: AssetKind,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let metadata = match fs::metadata(path) {
+        Ok(r) => r,
+        Err(_) => return Ok(()),
+    };
+    let asset = GeneratedAsset {
+        owner_id: record.job_id.clone(),
+        item_index: None,
+        kind,
+        archive_version: record.archive_version,
+        path: path.to_path_buf(),
+        size_bytes: metadata.len(),
+        created_at: record
+            .finished_at
+            .clone()
+            .unwrap_or_else(|| record.created_at.clone()),
+    };
+    write_asset(connection, &asset)
+}
+
+fn apply_migrations(connection: &mut Connection, files: &LegacyJsonFiles) -> anyhow::Result<()> {
+    for (index, migration) in MIGRATIONS.iter().enumerate() {
+        let migration_number = index as i32 + 1;
+
+        // NB: the number is read again in the transaction, somebody else might have applied it
+        let transaction = check(
+            connection.transaction_with_behavior(TransactionBehavior::Immediate),
+            "start a migration",
+        )?;
+        let applied: i32 = check(
+            transaction.query_row("PRAGMA user_version", [], |row| row.get(0)),
+            "read the version of the database",
+        )?;
+        if applied >= migration_number {
+            continue;
+        }
+
+        eprintln!("Applying database migration {}", migration_number);
+        migration(&transaction, files)?;
+        check(
+            transaction.execute_batch(&format!("PRAGMA user_version = {}", migration_number)),
+            "update the version of the database",
+        )?;
+        check(transaction.commit(), "commit a migration")?;
+    }
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+fn check<T>(result: rusqlite::Result<T>, action: &str) -> anyhow::Result<T> {
+    match result {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            let message = format!("Failed to {} in the database. Error: {}", action, e);
+            anyhow::bail!(message);
+        }
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> anyhow::Result<String> {
+    match serde_json::to_string(value) {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            let message = format!(
+                "Failed to serialize {}. Error: {}",
+                std::any::type_name::<T>(),
+                e
+            );
+            anyhow::bail!(message);
+        }
+    }
+}
+
+fn from_json<T: DeserializeOwned>(value: &str) -> anyhow::Result<T> {
+    match serde_json::from_str(value) {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            let message = format!("Failed to deserialize {:?}. Error: {}", value, e);
+            anyhow::bail!(message);
+        }
+    }
+}
+
+// Enums are stored by name (EG: 'STARTED'), without the quotes of their JSON
+fn enum_to_sql<T: Serialize>(value: &T) -> anyhow::Result<String> {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => Ok(s),
+        _ => {
+            let message = format!("Failed to store {}", std::any::type_name::<T>());
+            anyhow::bail!(message);
+        }
+    }
+}
+
+fn enum_from_sql<T: DeserializeOwned>(value: &str) -> anyhow::Result<T> {
+    match serde_json::from_value(serde_json::Value::String(String::from(value))) {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            let message = format!(
+                "Unknown {} {}. Error: {}",
+                std::any::type_name::<T>(),
+                value,
+                e
+            );
+            anyhow::bail!(message);
+        }
+    }
+}
+
+fn path_to_sql(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+// Run a query and convert every row. The conversion happens outside of rusqlite,
+// so that it can fail with our own errors (EG: a broken JSON column).
+fn query_rows<R, T>(
+    connection: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+    read_row: fn(&Row) -> rusqlite::Result<R>,
+    convert: fn(R) -> anyhow::Result<T>,
+) -> anyhow::Result<Vec<T>> {
+    let mut statement = check(connection.prepare(sql), "prepare a query")?;
+    let rows = check(statement.query_map(params, read_row), "run a query")?;
+    let mut results = Vec::new();
+    for row in rows {
+        let row = check(row, "read a row")?;
+        results.push(convert(row)?);
+    }
+    Ok(results)
+}
+
+// Versions
+// -----------------------------------------------------------------------------
+
+fn write_version_state(connection: &Connection, data: &VersionsData) -> anyhow::Result<()> {
+    check(
+        connection.execute(
+            "INSERT INTO version_state (id, last_version, current_version, last_modified)
+            VALUES (1, ?1, ?2, ?3)
+            ON CONFLICT (id) DO UPDATE SET
+                last_version = excluded.last_version,
+                current_version = excluded.current_version,
+                last_modified = excluded.last_modified",
+            params![data.last_version, data.current_version, data.last_modified],
+        ),
+        "save the versions",
+    )?;
+    Ok(())
+}
+
+fn insert_version_switch(connection: &Connection, switch: &VersionSwitch) -> anyhow::Result<()> {
+    check(
+        connection.execute(
+            "INSERT INTO version_switches (version, previous_version, reason, switched_at)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![
+                switch.version,
+                switch.previous_version,
+                enum_to_sql(&switch.reason)?,
+                switch.switched_at
+            ],
+        ),
+        "save a switch of version",
+    )?;
+    Ok(())
+}
+
+type VersionSwitchRow = (i32, Option<i32>, String, String);
+
+fn read_version_switch(row: &Row) -> rusqlite::Result<VersionSwitchRow> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+}
+
+fn convert_version_switch(row: VersionSwitchRow) -> anyhow::Result<VersionSwitch> {
+    let (version, previous_version, reason, switched_at) = row;
+    Ok(VersionSwitch {
+        version,
+        previous_version,
+        reason: enum_from_sql(&reason)?,
+        switched_at,
+    })
+}
+
+// Ingests
+// -----------------------------------------------------------------------------
+
+const INGEST_COLUMNS: &str = "ingest_id, file_name, size_bytes, checksum, stage, created_at, \
+    updated_at, finished_at, archive_version, sanitize_operations, validation, error";
+
+fn write_ingest(connection: &Connection, record: &IngestRecord) -> anyhow::Result<()> {
+    let validation = match &record.validation {
+        Some(v) => Some(to_json(v)?),
+        None => None,
+    };
+    check(
+        connection.execute(
+            &format!(
+                "INSERT OR REPLACE INTO ingests ({})
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                INGEST_COLUMNS
+            ),
+            params![
+                record.ingest_id,
+                record.file_name,
+                record.size_bytes,
+                record.checksum,
+                enum_to_sql(&record.stage)?,
+                record.created_at,
+                record.updated_at,
+                record.finished_at,
+                record.archive_version,
+                record.sanitize_operations,
+                validation,
+                record.error
+            ],
+        ),
+        "save an ingest",
+    )?;
+    Ok(())
+}
+
+type IngestRow = (
+    String,
+    String,
+    Option<usize>,
+    Option<String>,
+    String,
+    String,
+    String,
+    Option<String>,
+    Option<i32>,
+    Option<usize>,
+    Option<String>,
+    Option<String>,
+);
+
+fn read_ingest(row: &Row) -> rusqlite::Result<IngestRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+        row.get(6)?,
+        row.get(7)?,
+        row.get(8)?,
+        row.get(9)?,
+        row.get(10)?,
+        row.get(11)?,
+    ))
+}
+
+fn convert_ingest(row: IngestRow) -> anyhow::Result<IngestRecord> {
+    let (
+        ingest_id,
+        file_name,
+        size_bytes,
+        checksum,
+        stage,
+        created_at,
+        updated_at,
+        finished_at,
+        archive_version,
+        sanitize_operations,
+        validation,
+        error,
+    ) = row;
+    let validation = match validation {
+        Some(v) => Some(from_json(&v)?),
+        None => None,
+    };
+    Ok(IngestRecord {
+        ingest_id,
+        file_name,
+        size_bytes,
+        checksum,
+        stage: enum_from_sql(&stage)?,
+        created_at,
+        updated_at,
+        finished_at,
+        archive_version,
+        sanitize_operations,
+        validation,
+        error,
+    })
+}
+
+// Jobs
+// -----------------------------------------------------------------------------
+
+const JOB_COLUMNS: &str =
+    "job_id, status, created_at, finished_at, archive_version, recipe, progress, error, output_path";
+
+fn write_job(connection: &Connection, record: &JobRecord) -> anyhow::Result<()> {
+    let recipe = match &record.recipe {
+        Some(r) => Some(to_json(r)?),
+        None => None,
+    };
+    check(
+        connection.execute(
+            &format!(
+                "INSERT OR REPLACE INTO jobs ({})
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                JOB_COLUMNS
+            ),
+            params![
+                record.job_id,
+                enum_to_sql(&record.status)?,
+                record.created_at,
+                record.finished_at,
+                record.archive_version,
+                recipe,
+                record.progress,
+                record.error,
+                record.output_path.as_deref().map(path_to_sql)
+            ],
+        ),
+        "save a job",
+    )?;
+    Ok(())
+}
+
+type JobRow = (
+    String,
+    String,
+    String,
+    Option<String>,
+    Option<i32>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+fn read_job(row: &Row) -> rusqlite::Result<JobRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+        row.get(6)?,
+        row.get(7)?,
+        row.get(8)?,
+    ))
+}
+
+fn convert_job(row: JobRow) -> anyhow::Result<JobRecord> {
+    let (
+        job_id,
+        status,
+        created_at,
+        finished_at,
+        archive_version,
+        recipe,
+        progress,
+        error,
+        output_path,
+    ) = row;
+    let recipe = match recipe {
+        Some(r) => Some(from_json(&r)?),
+        None => None,
+    };
+    Ok(JobRecord {
+        job_id,
+        status: enum_from_sql(&status)?,
+        created_at,
+        finished_at,
+        archive_version,
+        recipe,
+        progress,
+        error,
+        output_path: output_path.map(PathBuf::from),
+    })
+}
+
+// Recipes
+// -----------------------------------------------------------------------------
+
+// Returns false if the recipe was already there
+fn write_recipe(
+    connection: &Connection,
+    version: i32,
+    recipe: &RegisteredRecipe,
+) -> anyhow::Result<bool> {
+    let inserted = check(
+        connection.execute(
+            "INSERT OR IGNORE INTO recipes (archive_version, checksum, layers, created_at)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![
+                version,
+                recipe.checksum,
+                to_json(&recipe.layers)?,
+                recipe.created_at
+            ],
+        ),
+        "save a recipe",
+    )?;
+    Ok(inserted > 0)
+}
+
+type RecipeRow = (String, String, String);
+
+fn read_recipe(row: &Row) -> rusqlite::Result<RecipeRow> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+}
+
+fn convert_recipe(row: RecipeRow) -> anyhow::Result<RegisteredRecipe> {
+    let (checksum, layers, created_at) = row;
+    Ok(RegisteredRecipe {
+        checksum,
+        layers: from_json(&layers)?,
+        created_at,
+    })
+}
+
+// Assets
+// -----------------------------------------------------------------------------
+
+fn write_asset(connection: &Connection, asset: &GeneratedAsset) -> anyhow::Result<()> {
+    check(
+        connection.execute(
+            "INSERT OR REPLACE INTO assets
+                (owner_id, item_index, kind, archive_version, path, size_bytes, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                asset.owner_id,
+                asset.item_index,
+                enum_to_sql(&asset.kind)?,
+                asset.archive_version,
+                path_to_sql(&asset.path),
+                asset.size_bytes,
+                asset.created_at
+            ],
+        ),
+        "save an asset",
+    )?;
+    Ok(())
+}
+
+type AssetRow = (
+    String,
+    Option<usize>,
+    String,
+    Option<i32>,
+    String,
+    u64,
+    String,
+);
+
+fn read_asset(row: &Row) -> rusqlite::Result<AssetRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+        row.get(6)?,
+    ))
+}
+
+fn convert_asset(row: AssetRow) -> anyhow::Result<GeneratedAsset> {
+    let (owner_id, item_index, kind, archive_version, path, size_bytes, created_at) = row;
+    Ok(GeneratedAsset {
+        owner_id,
+        item_index,
+        kind: enum_from_sql(&kind)?,
+        archive_version,
+        path: PathBuf::from(path),
+        size_bytes,
+        created_at,
+    })
+}
+
+// -----------------------------------------------------------------------------
+// Repository
+// -----------------------------------------------------------------------------
+
+impl SqliteRepository {
+    // Create the database if needed, and bring its schema up to date.
+    // What was stored in `legacy_files` is imported the first time.
+    pub fn open(path: &Path, legacy_files: &LegacyJsonFiles) -> anyhow::Result<SqliteRepository> {
+        if let Some(parent) = path.parent() {
+            match fs::create_dir_all(parent) {
+                Ok(_) => {}
+                Err(e) => {
+                    let message = format!("Failed to create {}. Error: {}", parent.display(), e);
+                    anyhow::bail!(message);
+                }
+            }
+        }
+
+        let mut connection = check(Connection::open(path), "open the database")?;
+        check(
+            connection.busy_timeout(std::time::Duration::from_millis(BUSY_TIMEOUT_MS)),
+            "configure the database",
+        )?;
+        // The readers don't block the writer (and the other way around)
+        check(
+            connection.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(())),
+            "configure the database",
+        )?;
+        apply_migrations(&mut connection, legacy_files)?;
+
+        Ok(SqliteRepository {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn with_connection<T>(
+        &self,
+        action: impl FnOnce(&mut Connection) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        action(&mut connection)
+    }
+}
+
+impl Repository for SqliteRepository {
+    fn load_versions(&self) -> anyhow::Result<Option<VersionsData>> {
+        self.with_connection(|connection| {
+            let state = check(
+                connection
+                    .query_row(
+                        "SELECT last_version, current_version, last_modified
+                        FROM version_state WHERE id = 1",
+                        [],
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                    )
+                    .optional(),
+                "load the versions",
+            )?;
+            let (last_version, current_version, last_modified) = match state {
+                Some(r) => r,
+                None => return Ok(None),
+            };
+            let history = query_rows(
+                connection,
+                "SELECT version, previous_version, reason, switched_at
+                FROM version_switches ORDER BY id",
+                [],
+                read_version_switch,
+                convert_version_switch,
+            )?;
+            Ok(Some(VersionsData {
+                last_version,
+                last_modified,
+                current_version,
+                history,
+            }))
+        })
+    }
+
+    fn save_versions(
+        &self,
+        data: &VersionsData,
+        switch: Option<&VersionSwitch>,
+    ) -> anyhow::Result<()> {
+        self.with_connection(|connection| {
+            let transaction = check(connection.transaction(), "start a transaction")?;
+            write_version_state(&transaction, data)?;
+            if let Some(switch) = switch {
+                insert_version_switch(&transaction, switch)?;
+            }
+            check(transaction.commit(), "save the versions")
+        })
+    }
+
+    fn save_ingest(&self, record: &IngestRecord) -> anyhow::Result<()> {
+        self.with_connection(|connection| write_ingest(connection, record))
+    }
+
+    fn load_ingest(&self, ingest_id: &str) -> anyhow::Result<Option<IngestRecord>> {
+        self.with_connection(|connection| {
+            let records = query_rows(
+                connection,
+                &format!(
+                    "SELECT {} FROM ingests WHERE ingest_id = ?1",
+                    INGEST_COLUMNS
+                ),
+                [ingest_id],
+                read_ingest,
+                convert_ingest,
+            )?;
+            Ok(records.into_iter().next())
+        })
+    }
+
+    fn list_ingests(&self) -> anyhow::Result<Vec<IngestRecord>> {
+        self.with_connection(|connection| {
+            query_rows(
+                connection,
+                &format!("SELECT {} FROM ingests ORDER BY created_at", INGEST_COLUMNS),
+                [],
+                read_ingest,
+                convert_ingest,
+            )
+        })
+    }
+
+    fn save_job(&self, record: &JobRecord) -> anyhow::Result<()> {
+        self.with_connection(|connection| write_job(connection, record))
+    }
+
+    fn load_job(&self, job_id: &str) -> anyhow::Result<Option<JobRecord>> {
+        self.with_connection(|connection| {
+            let records = query_rows(
+                connection,
+                &format!("SELECT {} FROM jobs WHERE job_id = ?1", JOB_COLUMNS),
+                [job_id],
+                read_job,
+                convert_job,
+            )?;
+            Ok(records.into_iter().next())
+        })
+    }
+
+    fn list_jobs_with_status(&self, status: JobStatus) -> anyhow::Result<Vec<JobRecord>> {
+        let status = enum_to_sql(&status)?;
+        self.with_connection(|connection| {
+            query_rows(
+                connection,
+                &format!(
+                    "SELECT {} FROM jobs WHERE status = ?1 ORDER BY created_at",
+                    JOB_COLUMNS
+                ),
+                [status],
+                read_job,
+                convert_job,
+            )
+        })
+    }
+
+    fn insert_recipe(&self, version: i32, recipe: &RegisteredRecipe) -> anyhow::Result<bool> {
+        self.with_connection(|connection| write_recipe(connection, version, recipe))
+    }
+
+    fn load_recipes(&self, version: i32) -> anyhow::Result<Vec<RegisteredRecipe>> {
+        self.with_connection(|connection| {
+            query_rows(
+                connection,
+                "SELECT checksum, layers, created_at
+                FROM recipes WHERE archive_version = ?1 ORDER BY id",
+                [version],
+                read_recipe,
+                convert_recipe,
+            )
+        })
+    }
+
+    fn count_recipes(&self, version: i32) -> anyhow::Result<usize> {
+        self.with_connection(|connection| {
+            check(
+                connection.query_row(
+                    "SELECT COUNT(*) FROM recipes WHERE archive_version = ?1",
+                    [version],
+                    |row| row.get(0),
+                ),
+                "count the recipes",
+            )
+        })
+    }
+
+    fn save_asset(&self, asset: &GeneratedAsset) -> anyhow::Result<()> {
+        self.with_connection(|connection| write_asset(connection, asset))
+    }
+
+    fn list_assets(&self, owner_id: &str) -> anyhow::Result<Vec<GeneratedAsset>> {
+        self.with_connection(|connection| {
+            query_rows(
+                connection,
+                "SELECT owner_id, item_index, kind, archive_version, path, size_bytes, created_at
+                FROM assets WHERE owner_id = ?1 ORDER BY item_index, id",
+                [owner_id],
+                read_asset,
+                convert_asset,
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::core::test_utils::TestDir;
+
+    fn legacy_files(dir: &TestDir) -> LegacyJsonFiles {
+        LegacyJsonFiles {
+            versions_path: dir.path.join("versions.json"),
+            jobs_dir: dir.path.join("jobs"),
+            ingests_dir: dir.path.join("ingests"),
+            recipes_dir: dir.path.join("recipes"),
+        }
+    }
+
+    fn write_file(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn recipe_line(checksum: &str) -> String {
+        format!(
+            "{{\"checksum\":\"{}\",\"layers\":[\"01_background/Background_C_01.png\"],\"created_at\":\"2022-11-02T10:00:00+00:00\"}}\n",
+            checksum
+        )
+    }
+
+    fn user_version(repository: &SqliteRepository) -> usize {
+        repository
+            .with_connection(|connection| {
+                check(
+                    connection.query_row("PRAGMA user_version", [], |row| row.get(0)),
+                    "read the version of the database",
+                )
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn opens_a_new_database() {
+        let dir = TestDir::new("sqlite");
+        let repository =
+            SqliteRepository::open(&dir.path.join("webapp.sqlite"), &legacy_files(&dir)).unwrap();
+
+        assert_eq!(user_version(&repository), MIGRATIONS.len());
+        assert!(repository.load_versions().unwrap().is_none());
+        assert_eq!(repository.count_recipes(1).unwrap(), 0);
+    }
+
+    #[test]
+    fn applies_only_the_missing_migrations() {
+        let dir = TestDir::new("sqlite");
+        let files = legacy_files(&dir);
+        let database_path = dir.path.join("webapp.sqlite");
+        fs::create_dir_all(&dir.path).unwrap();
+        // A database that only got the first migration, with a recipe generated since then
+        {
+            let connection = Connection::open(&database_path).unwrap();
+            create_tables(&connection, &files).unwrap();
+            connection.execute_batch("PRAGMA user_version = 1").unwrap();
+            connection
+                .execute_batch(
+                    "INSERT INTO recipes (archive_version, checksum, layers, created_at)
+                    VALUES (1, 'aaa', '[]', '2022-11-02T10:00:00+00:00')",
+                )
+                .unwrap();
+        }
+        write_file(&files.recipes_dir.join("001.jsonl"), &recipe_line("bbb"));
+
+        // The tables aren't created again (it would fail), the import happens
+        let repository = SqliteRepository::open(&database_path, &files).unwrap();
+        assert_eq!(user_version(&repository), MIGRATIONS.len());
+        assert_eq!(repository.count_recipes(1).unwrap(), 2);
+    }
+
+    #[test]
+    fn imports_the_json_files_once() {
+        let dir = TestDir::new("sqlite");
+        let files = legacy_files(&dir);
+        let database_path = dir.path.join("webapp.sqlite");
+        write_file(
+            &files.versions_path,
+            r#"{"last_version": 2, "last_modified": "2022-11-02T10:00:00+00:00"}"#,
+        );
+        write_file(
+            &files.jobs_dir.join("job-1.json"),
+            r#"{"job_id": "job-1", "status": "COMPLETED", "created_at": "2022-11-02T10:00:00+00:00"}"#,
+        );
+        write_file(&files.jobs_dir.join("job-1.metadata.json"), "{}");
+        let recipes_path = files.recipes_dir.join("001.jsonl");
+        write_file(
+            &recipes_path,
+            &format!("{}{}", recipe_line("aaa"), recipe_line("bbb")),
+        );
+
+        let repository = SqliteRepository::open(&database_path, &files).unwrap();
+        assert_eq!(user_version(&repository), MIGRATIONS.len());
+        let versions = repository.load_versions().unwrap().unwrap();
+        assert_eq!(versions.last_version, 2);
+        assert_eq!(versions.current_version, None);
+        assert_eq!(repository.count_recipes(1).unwrap(), 2);
+        assert!(repository.load_job("job-1").unwrap().is_some());
+        let assets = repository.list_assets("job-1").unwrap();
+        assert_eq!(assets.len(), 1);
+        assert_eq!(assets[0].kind, AssetKind::Metadata);
+        drop(repository);
+
+        // The files are still there (and keep changing, EG: an old version of the server),
+        // but the database is what counts from now on
+        write_file(
+            &files.versions_path,
+            r#"{"last_version": 5, "last_modified": "2022-11-03T10:00:00+00:00"}"#,
+        );
+        write_file(
+            &recipes_path,
+            &format!(
+                "{}{}{}",
+                recipe_line("aaa"),
+                recipe_line("bbb"),
+                recipe_line("ccc")
+            ),
+        );
+
+        let repository = SqliteRepository::open(&database_path, &files).unwrap();
+        assert_eq!(user_version(&repository), MIGRATIONS.len());
+        assert_eq!(repository.load_versions().unwrap().unwrap().last_version, 2);
+        assert_eq!(repository.count_recipes(1).unwrap(), 2);
+    }
+
+    fn recipe(checksum: &str) -> RegisteredRecipe {
+        RegisteredRecipe {
+            checksum: String::from(checksum),
+            layers: vec![String::from("01_background/Background_C_01.png")],
+            created_at: String::from("2022-11-02T10:00:00+00:00"),
+        }
+    }
+
+    #[test]
+    fn refuses_recipes_generated_already() {
+        let dir = TestDir::new("sqlite");
+        let repository =
+            SqliteRepository::open(&dir.path.join("webapp.sqlite"), &legacy_files(&dir)).unwrap();
+
+        assert!(repository.insert_recipe(1, &recipe("aaa")).unwrap());
+        assert!(!repository.insert_recipe(1, &recipe("aaa")).unwrap());
+        // Every version has its own recipes
+        assert!(repository.insert_recipe(2, &recipe("aaa")).unwrap());
+        assert!(repository.insert_recipe(1, &recipe("bbb")).unwrap());
+
+        let checksums: Vec<String> = repository
+            .load_recipes(1)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.checksum)
+            .collect();
+        assert_eq!(checksums, vec!["aaa", "bbb"]);
+        assert_eq!(repository.count_recipes(2).unwrap(), 1);
+    }
+
+    #[test]
+    fn lets_a_single_job_claim_a_recipe() {
+        let dir = TestDir::new("sqlite");
+        let database_path = dir.path.join("webapp.sqlite");
+        // Two connections, as if two servers shared the database
+        let repositories: Vec<std::sync::Arc<SqliteRepository>> = (0..2)
+            .map(|_| {
+                let repository = SqliteRepository::open(&database_path, &legacy_files(&dir));
+                std::sync::Arc::new(repository.unwrap())
+            })
+            .collect();
+
+        let handles: Vec<std::thread::JoinHandle<bool>> = (0..8)
+            .map(|i| {
+                let repository = repositories[i % 2].clone();
+                std::thread::spawn(move || repository.insert_recipe(1, &recipe("aaa")).unwrap())
+            })
+            .collect();
+        let claims: Vec<bool> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(claims.iter().filter(|c| **c).count(), 1);
+        assert_eq!(repositories[0].count_recipes(1).unwrap(), 1);
+    }
+}
@@ -0,0 +1,49 @@
+// Helpers shared by the tests of the core modules
+
+use std::fs;
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+// A directory that is removed at the end of the test
+pub struct TestDir {
+    pub path: PathBuf,
+}
+
+impl TestDir {
+    // NB: the directory itself isn't created, only its path is chosen
+    pub fn new(prefix: &str) -> TestDir {
+        let path = std::env::temp_dir().join(format!("{}-{}", prefix, Uuid::new_v4()));
+        TestDir { path }
+    }
+
+    // Write an empty file (and its parents) at a path relative to the directory
+    pub fn add_file(&self, relative: &str) -> PathBuf {
+        let path = self.path.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, b"").unwrap();
+        path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// A small archive laid out like archive 001: 3 backgrounds, 2 body skins and ears of both streams
+pub fn sample_archive(dir: &TestDir) {
+    for file in [
+        "01_background/common_background/Background_C_01.png",
+        "01_background/common_background/Background_C_02.png",
+        "01_background/uncommon_background/Background_U_01.png",
+        "02_body_skins/Body_Skin_Standard_pink.png",
+        "02_body_skins/Body_Skin_Tiger_zebra.png",
+        "03_ears/ear_1/01_ear_1_skins/Ear_1_Standard_pink.png",
+        "03_ears/ear_1/01_ear_1_skins/Ear_1_Tiger_zebra.png",
+        "03_ears/ear_1/02_ear_1_lines/Ear_1_Line.png",
+    ] {
+        dir.add_file(file);
+    }
+}
@@ -0,0 +1,129 @@
+// Registry of the recipes that have been generated for every archive version,
+// so that the same cat is never generated twice.
+//
+// The recipes are stored in the repository, which refuses a checksum that was already
+// generated for the version: the check and the insert are a single operation, so that
+// concurrent jobs can't claim the same recipe.
+
+use std::path::Path;
+
+use axum::{extract::Query, http::StatusCode, response::Json};
+use chrono::Utc;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::combinatorics::count_combinations;
+use crate::core::constants::{UNIQUE_RECIPE_MAX_ATTEMPTS, ZFILL_PADDING};
+use crate::core::rarity::load_manifest as load_rarity_manifest;
+use crate::core::recipe::{generate_random_recipe, Recipe};
+use crate::core::repository::repository;
+use crate::core::rules::{load_rules, uses_default_rules};
+use crate::core::{find_entry_point, get_requested_version};
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisteredRecipe {
+    pub checksum: String,
+    pub layers: Vec<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UniquenessQuery {
+    pub version: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UniquenessData {
+    version: i32,
+    total_combinations: String,
+    generated: usize,
+    remaining: String,
+    // The version has custom rules, so the combinations (and what's left of them) are estimates
+    approximate: bool,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+// All of the recipes generated so far for the given version, oldest first
+pub fn load_registered_recipes(version: i32) -> anyhow::Result<Vec<RegisteredRecipe>> {
+    repository()?.load_recipes(version)
+}
+
+// Record the recipe as generated. Returns false if it had already been generated before.
+pub fn claim_recipe(version: i32, recipe: &Recipe) -> anyhow::Result<bool> {
+    let registered = RegisteredRecipe {
+        checksum: recipe.checksum(),
+        layers: recipe.layers.clone(),
+        created_at: Utc::now().to_rfc3339(),
+    };
+    repository()?.insert_recipe(version, &registered)
+}
+
+pub fn count_generated_recipes(version: i32) -> anyhow::Result<usize> {
+    repository()?.count_recipes(version)
+}
+
+// Generate a random recipe that has never been generated before for this version
+pub fn generate_unique_recipe(version: i32, entry_point: &Path) -> anyhow::Result<Recipe> {
+    let rarity = load_rarity_manifest(version)?;
+    let rules = load_rules(version)?;
+    for _ in 0..UNIQUE_RECIPE_MAX_ATTEMPTS {
+        let recipe = generate_random_recipe(entry_point, &rarity, &rules)?;
+        if claim_recipe(version, &recipe)? {
+            return Ok(recipe);
+        }
+    }
+
+    let message = format!(
+        "Failed to find a recipe that wasn't generated already after {} attempts. \
+        Version {:0ZFILL_PADDING$} might be running out of combinations.",
+        UNIQUE_RECIPE_MAX_ATTEMPTS, version
+    );
+    anyhow::bail!(message);
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+// How many unique recipes are left for an archive version
+pub async fn get_uniqueness(
+    query: Query<UniquenessQuery>,
+) -> Result<Json<UniquenessData>, (StatusCode, String)> {
+    let version = get_requested_version(query.version).await?;
+
+    let entry_point = find_entry_point(version)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+
+    let counts = tokio::task::spawn_blocking(move || -> anyhow::Result<(u128, usize, bool)> {
+        Ok((
+            count_combinations(&entry_point)?,
+            count_generated_recipes(version)?,
+            !uses_default_rules(version)?,
+        ))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let (total_combinations, generated, approximate) =
+        counts.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    // NB: big numbers are sent as strings, since javascript can't represent them precisely
+    Ok(Json(UniquenessData {
+        version,
+        total_combinations: total_combinations.to_string(),
+        generated,
+        remaining: total_combinations
+            .saturating_sub(generated as u128)
+            .to_string(),
+        approximate,
+    }))
+}
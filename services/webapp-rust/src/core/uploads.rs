@@ -0,0 +1,799 @@
+// Resumable uploads of archives, sent in chunks so that a dropped connection only costs
+// the chunk that was being sent:
+//
+//   POST   /api/uploads                     -> start an upload, returns its id and the chunk size
+//   PUT    /api/uploads/:id/chunks/:index   -> send a chunk (in any order, as many times as needed)
+//   GET    /api/uploads/:id                 -> which chunks were received so far
+//   POST   /api/uploads/:id/finalize        -> once every chunk is there, ingest the archive
+//   DELETE /api/uploads/:id                 -> give up
+//
+// Every upload is a JSON file (UPLOADS_ROOT_DIR/<upload_id>.json) next to the archive being
+// received (UPLOADS_ROOT_DIR/<upload_id>.part), so that uploads survive restarts of the server.
+// Once finalized, the archive goes through the same steps as the ones of /api/upload-archive.
+// An upload can't be finalized while some of its chunks are still being written.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use axum::{
+    extract::{BodyStream, Path as UrlPath},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::Utc;
+use futures::StreamExt;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::{UPLOADS_ROOT_DIR, UPLOAD_CHUNK_SIZE, UPLOAD_MAX_SIZE};
+use crate::core::ingest::{self, IngestRecord};
+use crate::core::repository::run_blocking;
+use crate::core::{
+    archive_format, get_archive_save_path, read_json, release_saved_version, write_json_atomically,
+    SavedArchive,
+};
+
+// Chunks of the same upload can be received at the same time:
+// their records are loaded, changed and saved back one at a time
+static UPLOADS_LOCK: Mutex<()> = Mutex::new(());
+// How many chunks of every upload are being written right now.
+// NB: when both are needed, UPLOADS_LOCK is taken first
+static CHUNK_WRITERS: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+// NB: the names follow the ones used by JobStatus
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum UploadStatus {
+    UPLOADING,
+    FINALIZING,
+    FINALIZED,
+    // The archive was lost while being finalized, the upload has to start over
+    FAILED,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadRecord {
+    pub upload_id: String,
+    pub file_name: String,
+    pub size_bytes: usize,
+    pub chunk_size: usize,
+    pub num_chunks: usize,
+    // md5 of the whole archive, checked when finalizing if given
+    pub checksum: Option<String>,
+    pub status: UploadStatus,
+    pub created_at: String,
+    pub updated_at: String,
+    pub received_chunks: BTreeSet<usize>,
+    // Set once finalized
+    pub ingest_id: Option<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+// A chunk being written, the upload can't be finalized until it's dropped
+struct ChunkWriter {
+    upload_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadRequest {
+    pub file_name: String,
+    pub size_bytes: usize,
+    pub checksum: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadData {
+    #[serde(flatten)]
+    record: UploadRecord,
+    // Byte ranges received so far, as [start, end)
+    received_ranges: Vec<[usize; 2]>,
+    missing_chunks: Vec<usize>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+fn now_rfc3339() -> String {
+    Utc::now().to_rfc3339()
+}
+
+// Upload ids are uuids, don't let anybody read arbitrary paths
+fn is_valid_upload_id(upload_id: &str) -> bool {
+    !upload_id.is_empty()
+        && upload_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn get_upload_record_path(upload_id: &str) -> PathBuf {
+    Path::new(UPLOADS_ROOT_DIR).join(format!("{}.json", upload_id))
+}
+
+fn get_upload_data_path(upload_id: &str) -> PathBuf {
+    Path::new(UPLOADS_ROOT_DIR).join(format!("{}.part", upload_id))
+}
+
+fn save_upload(record: &UploadRecord) -> anyhow::Result<()> {
+    write_json_atomically(&get_upload_record_path(&record.upload_id), record)
+}
+
+// Returns None if no upload with the given id was ever started
+pub fn load_upload(upload_id: &str) -> anyhow::Result<Option<UploadRecord>> {
+    if !is_valid_upload_id(upload_id) {
+        return Ok(None);
+    }
+    let record_path = get_upload_record_path(upload_id);
+    if !record_path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(read_json(&record_path)?))
+}
+
+// Same as run_blocking, for the steps of the routes that already fail with a status code.
+// NB: the locks above are only ever taken on the blocking threads
+async fn run_blocking_step<T, F>(f: F) -> Result<T, (StatusCode, String)>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, (StatusCode, String)> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(r) => r,
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// Load the upload, apply the changes and save it back.
+// The changes can fail, in which case nothing is saved.
+fn update_upload<F>(upload_id: &str, update: F) -> Result<UploadRecord, (StatusCode, String)>
+where
+    F: FnOnce(&mut UploadRecord) -> Result<(), (StatusCode, String)>,
+{
+    let _guard = UPLOADS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut record = get_upload_or_404(upload_id)?;
+    update(&mut record)?;
+    record.updated_at = now_rfc3339();
+    save_upload(&record).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(record)
+}
+
+// Register a chunk that is about to be written, as long as the upload is still receiving chunks
+fn start_chunk_writer(
+    upload_id: &str,
+) -> Result<(UploadRecord, ChunkWriter), (StatusCode, String)> {
+    let _guard = UPLOADS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let record = get_upload_or_404(upload_id)?;
+    if record.status != UploadStatus::UPLOADING {
+        let message = format!("Upload {} is {:?}", upload_id, record.status);
+        return Err((StatusCode::CONFLICT, message));
+    }
+
+    Ok((record, register_chunk_writer(upload_id)))
+}
+
+fn register_chunk_writer(upload_id: &str) -> ChunkWriter {
+    let mut writers = CHUNK_WRITERS.lock().unwrap_or_else(|e| e.into_inner());
+    *writers.entry(String::from(upload_id)).or_insert(0) += 1;
+    ChunkWriter {
+        upload_id: String::from(upload_id),
+    }
+}
+
+// NB: only meaningful while holding UPLOADS_LOCK
+fn count_chunk_writers(upload_id: &str) -> usize {
+    let writers = CHUNK_WRITERS.lock().unwrap_or_else(|e| e.into_inner());
+    writers.get(upload_id).copied().unwrap_or(0)
+}
+
+// Also when the client goes away in the middle of a chunk
+impl Drop for ChunkWriter {
+    fn drop(&mut self) {
+        let mut writers = CHUNK_WRITERS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = writers.get_mut(&self.upload_id) {
+            *count -= 1;
+            if *count == 0 {
+                writers.remove(&self.upload_id);
+            }
+        }
+    }
+}
+
+fn get_upload_or_404(upload_id: &str) -> Result<UploadRecord, (StatusCode, String)> {
+    match load_upload(upload_id) {
+        Ok(Some(r)) => Ok(r),
+        Ok(None) => {
+            let message = format!("Upload {} doesn't exist.", upload_id);
+            Err((StatusCode::NOT_FOUND, message))
+        }
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+// The last chunk is usually smaller than the others
+fn get_chunk_size(record: &UploadRecord, index: usize) -> usize {
+    let start = index * record.chunk_size;
+    std::cmp::min(record.chunk_size, record.size_bytes - start)
+}
+
+fn get_upload_data(record: UploadRecord) -> UploadData {
+    let mut received_ranges: Vec<[usize; 2]> = Vec::new();
+    for index in &record.received_chunks {
+        let start = index * record.chunk_size;
+        let end = start + get_chunk_size(&record, *index);
+        match received_ranges.last_mut() {
+            Some(last) if last[1] == start => last[1] = end,
+            _ => received_ranges.push([start, end]),
+        }
+    }
+    let missing_chunks = (0..record.num_chunks)
+        .filter(|i| !record.received_chunks.contains(i))
+        .collect();
+
+    UploadData {
+        record,
+        received_ranges,
+        missing_chunks,
+    }
+}
+
+// Write the chunk where it belongs in the archive, as it's being received.
+// Fails if the chunk isn't exactly `expected_size` bytes.
+async fn write_chunk(
+    data_path: &Path,
+    offset: usize,
+    expected_size: usize,
+    mut body: BodyStream,
+) -> Result<(), (StatusCode, String)> {
+    let internal_error = |e: std::io::Error| {
+        let message = format!("Failed to write {}. Error: {}", data_path.display(), e);
+        (StatusCode::INTERNAL_SERVER_ERROR, message)
+    };
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(data_path)
+        .await
+        .map_err(internal_error)?;
+    file.seek(SeekFrom::Start(offset as u64))
+        .await
+        .map_err(internal_error)?;
+
+    let mut size = 0;
+    while let Some(data) = body.next().await {
+        let data = data.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        size += data.len();
+        if size > expected_size {
+            break;
+        }
+        file.write_all(&data).await.map_err(internal_error)?;
+    }
+    file.flush().await.map_err(internal_error)?;
+
+    if size != expected_size {
+        let message = format!(
+            "The chunk must be {} bytes, but {} bytes were received",
+            expected_size, size
+        );
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+    Ok(())
+}
+
+// The md5 of the archive and its first bytes (to check its type)
+fn read_archive_summary(data_path: &Path) -> anyhow::Result<(String, Vec<u8>)> {
+    let mut file = match fs::File::open(data_path) {
+        Ok(f) => f,
+        Err(e) => {
+            let message = format!("Failed to open {}. Error: {}", data_path.display(), e);
+            anyhow::bail!(message);
+        }
+    };
+
+    let mut checksum = md5::Context::new();
+    let mut header = Vec::new();
+    let mut buffer = vec![0; UPLOAD_CHUNK_SIZE];
+    loop {
+        let num_bytes = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                let message = format!("Failed to read {}. Error: {}", data_path.display(), e);
+                anyhow::bail!(message);
+            }
+        };
+        if header.len() < archive_format::HEADER_LEN {
+            let missing = archive_format::HEADER_LEN - header.len();
+            header.extend_from_slice(&buffer[..std::cmp::min(num_bytes, missing)]);
+        }
+        checksum.consume(&buffer[..num_bytes]);
+    }
+
+    Ok((format!("{:x}", checksum.compute()), header))
+}
+
+// Check that the archive is complete and sound, and hand it over to the ingest
+async fn ingest_upload(record: &UploadRecord) -> Result<IngestRecord, (StatusCode, String)> {
+    let missing_chunks = get_upload_data(record.clone()).missing_chunks;
+    if !missing_chunks.is_empty() {
+        let message = format!(
+            "Upload {} is missing {} chunks: {:?}",
+            record.upload_id,
+            missing_chunks.len(),
+            missing_chunks
+        );
+        return Err((StatusCode::CONFLICT, message));
+    }
+
+    let data_path = get_upload_data_path(&record.upload_id);
+    let summary_path = data_path.clone();
+    let (checksum, header) =
+        tokio::task::spawn_blocking(move || read_archive_summary(&summary_path))
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    if let Some(expected_checksum) = &record.checksum {
+        if *expected_checksum != checksum {
+            let message = format!(
+                "The checksum of the archive is {}, but {} was expected: some chunks are corrupted",
+                checksum, expected_checksum
+            );
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, message));
+        }
+    }
+    let format = match archive_format::detect_format(&header) {
+        Some(r) => r,
+        None => {
+            let message = archive_format::unsupported_format_message(&record.file_name);
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, message));
+        }
+    };
+
+    let ingest_id = Uuid::new_v4().to_string();
+    ingest::create_ingest(&ingest_id, &record.file_name)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let (save_path, version) = match get_archive_save_path(format).await {
+        Ok(r) => r,
+        Err(e) => {
+            ingest::fail_ingest(&ingest_id, &e.to_string()).await;
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+        }
+    };
+    match tokio::fs::rename(&data_path, &save_path).await {
+        Ok(_) => {}
+        Err(e) => {
+            let message = format!(
+                "Failed to move {} to {}. Error: {}",
+                data_path.display(),
+                save_path.display(),
+                e
+            );
+            release_saved_version(&version);
+            ingest::fail_ingest(&ingest_id, &message).await;
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, message));
+        }
+    }
+
+    let saved_archive = SavedArchive {
+        path: save_path,
+        version,
+        size_bytes: record.size_bytes,
+        checksum,
+    };
+    let ingest_record = match ingest::set_ingest_saved(&ingest_id, &saved_archive).await {
+        Ok(r) => r,
+        Err(e) => {
+            ingest::abandon_saved_archive(&ingest_id, &saved_archive, &e.to_string()).await;
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+        }
+    };
+    tokio::spawn(ingest::run_ingest(ingest_id, saved_archive));
+
+    Ok(ingest_record)
+}
+
+// Only an upload that is receiving chunks, and isn't writing any, can start being finalized
+fn start_finalization(record: &mut UploadRecord) -> Result<(), (StatusCode, String)> {
+    match record.status {
+        UploadStatus::UPLOADING => {
+            if count_chunk_writers(&record.upload_id) > 0 {
+                let message = format!(
+                    "Some chunks of upload {} are still being written",
+                    record.upload_id
+                );
+                return Err((StatusCode::CONFLICT, message));
+            }
+            record.status = UploadStatus::FINALIZING;
+            Ok(())
+        }
+        // Finalizing twice is fine, EG: if the response got lost
+        UploadStatus::FINALIZED => Ok(()),
+        UploadStatus::FINALIZING => {
+            let message = format!("Upload {} is already being finalized", record.upload_id);
+            Err((StatusCode::CONFLICT, message))
+        }
+        UploadStatus::FAILED => {
+            let message = format!(
+                "Upload {} has failed. {}",
+                record.upload_id,
+                record.error.clone().unwrap_or_default()
+            );
+            Err((StatusCode::CONFLICT, message))
+        }
+    }
+}
+
+// An upload that couldn't be finalized goes back to receiving chunks, so that the client
+// can fix what's wrong (EG: send the corrupted chunks again). Unless its archive is gone.
+fn reset_finalization(record: &mut UploadRecord, has_archive: bool, error: &str) {
+    if has_archive {
+        record.status = UploadStatus::UPLOADING;
+    } else {
+        record.status = UploadStatus::FAILED;
+        record.error = Some(String::from(error));
+    }
+}
+
+fn abort_finalization(upload_id: &str, error: &str) -> Result<UploadRecord, (StatusCode, String)> {
+    update_upload(upload_id, |record| {
+        let has_archive = get_upload_data_path(&record.upload_id).exists();
+        reset_finalization(record, has_archive, error);
+        Ok(())
+    })
+}
+
+// Uploads that were being finalized when the server went down
+pub fn recover_interrupted_uploads() -> anyhow::Result<()> {
+    let uploads_root_dir = Path::new(UPLOADS_ROOT_DIR);
+    if !uploads_root_dir.exists() {
+        return Ok(());
+    }
+
+    let entries = match fs::read_dir(uploads_root_dir) {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!(
+                "Failed to read directory {}. Error: {}",
+                uploads_root_dir.display(),
+                e
+            );
+            anyhow::bail!(message);
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let upload_id = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(r) => r,
+            None => continue,
+        };
+        let record = match load_upload(upload_id)? {
+            Some(r) => r,
+            None => continue,
+        };
+        if record.status != UploadStatus::FINALIZING {
+            continue;
+        }
+
+        eprintln!(
+            "Upload {} was interrupted while being finalized.",
+            upload_id
+        );
+        match abort_finalization(upload_id, "Interrupted by a restart of the server") {
+            Ok(_) => {}
+            Err((_, message)) => anyhow::bail!(message),
+        }
+    }
+
+    Ok(())
+}
+
+// Remove the archive and the record of an upload, unless it's being finalized
+fn remove_upload(upload_id: &str) -> Result<UploadRecord, (StatusCode, String)> {
+    let _guard = UPLOADS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let record = get_upload_or_404(upload_id)?;
+    if record.status == UploadStatus::FINALIZING {
+        let message = format!("Upload {} is being finalized", upload_id);
+        return Err((StatusCode::CONFLICT, message));
+    }
+
+    for path in [
+        get_upload_data_path(upload_id),
+        get_upload_record_path(upload_id),
+    ] {
+        if !path.exists() {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(_) => {}
+            Err(e) => {
+                let message = format!("Failed to remove {}. Error: {}", path.display(), e);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, message));
+            }
+        }
+    }
+
+    Ok(record)
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+pub async fn create_upload(
+    Json(request): Json<UploadRequest>,
+) -> Result<Json<UploadData>, (StatusCode, String)> {
+    if request.size_bytes == 0 || request.size_bytes > UPLOAD_MAX_SIZE {
+        let message = format!(
+            "The size of the archive must be between 1 and {} bytes",
+            UPLOAD_MAX_SIZE
+        );
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+    let checksum = request.checksum.map(|c| c.to_lowercase());
+    if let Some(checksum) = &checksum {
+        if checksum.len() != 32 || !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+            let message = format!("{} is not an md5 checksum", checksum);
+            return Err((StatusCode::BAD_REQUEST, message));
+        }
+    }
+
+    let upload_id = Uuid::new_v4().to_string();
+    let now = now_rfc3339();
+    let record = UploadRecord {
+        upload_id: upload_id.clone(),
+        file_name: request.file_name,
+        size_bytes: request.size_bytes,
+        chunk_size: UPLOAD_CHUNK_SIZE,
+        // NB: can't overflow, the size is at most UPLOAD_MAX_SIZE
+        num_chunks: (request.size_bytes + UPLOAD_CHUNK_SIZE - 1) / UPLOAD_CHUNK_SIZE,
+        checksum,
+        status: UploadStatus::UPLOADING,
+        created_at: now.clone(),
+        updated_at: now,
+        received_chunks: BTreeSet::new(),
+        ingest_id: None,
+        error: None,
+    };
+
+    // The file is allocated upfront, so that chunks can be written in any order
+    let saved_record = record.clone();
+    let result = run_blocking(move || {
+        fs::create_dir_all(UPLOADS_ROOT_DIR)?;
+        let file = fs::File::create(get_upload_data_path(&saved_record.upload_id))?;
+        file.set_len(saved_record.size_bytes as u64)?;
+        save_upload(&saved_record)
+    })
+    .await;
+    match result {
+        Ok(_) => {}
+        Err(e) => {
+            let message = format!("Failed to start the upload. Error: {}", e);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, message));
+        }
+    }
+    eprintln!(
+        "Started upload {} of {} ({} chunks)",
+        upload_id, record.file_name, record.num_chunks
+    );
+
+    Ok(Json(get_upload_data(record)))
+}
+
+pub async fn get_upload(
+    UrlPath(upload_id): UrlPath<String>,
+) -> Result<Json<UploadData>, (StatusCode, String)> {
+    let record = run_blocking_step(move || get_upload_or_404(&upload_id)).await?;
+    Ok(Json(get_upload_data(record)))
+}
+
+pub async fn put_upload_chunk(
+    UrlPath((upload_id, index)): UrlPath<(String, usize)>,
+    body: BodyStream,
+) -> Result<Json<UploadData>, (StatusCode, String)> {
+    // Held until the chunk is recorded as received
+    let writer_upload_id = upload_id.clone();
+    let (record, _writer) =
+        run_blocking_step(move || start_chunk_writer(&writer_upload_id)).await?;
+    if index >= record.num_chunks {
+        let message = format!(
+            "Chunk {} doesn't exist, upload {} has {} chunks",
+            index, upload_id, record.num_chunks
+        );
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+    let expected_size = get_chunk_size(&record, index);
+    let offset = index * record.chunk_size;
+    let result = write_chunk(
+        &get_upload_data_path(&upload_id),
+        offset,
+        expected_size,
+        body,
+    )
+    .await;
+    // What was written might have overwritten a chunk that was received before
+    if let Err(e) = result {
+        run_blocking_step(move || {
+            update_upload(&upload_id, |record| {
+                record.received_chunks.remove(&index);
+                Ok(())
+            })
+        })
+        .await?;
+        return Err(e);
+    }
+
+    let record = run_blocking_step(move || {
+        update_upload(&upload_id, |record| {
+            if record.status != UploadStatus::UPLOADING {
+                let message = format!("Upload {} is {:?}", record.upload_id, record.status);
+                return Err((StatusCode::CONFLICT, message));
+            }
+            record.received_chunks.insert(index);
+            Ok(())
+        })
+    })
+    .await?;
+
+    Ok(Json(get_upload_data(record)))
+}
+
+// Returns the ingest of the archive (see ingest.rs)
+pub async fn finalize_upload(
+    UrlPath(upload_id): UrlPath<String>,
+) -> Result<Json<IngestRecord>, (StatusCode, String)> {
+    let finalizing_upload_id = upload_id.clone();
+    let record =
+        run_blocking_step(move || update_upload(&finalizing_upload_id, start_finalization)).await?;
+
+    if record.status == UploadStatus::FINALIZED {
+        let ingest_id = record.ingest_id.unwrap_or_default();
+        let load_ingest_id = ingest_id.clone();
+        return match run_blocking(move || ingest::load_ingest(&load_ingest_id)).await {
+            Ok(Some(r)) => Ok(Json(r)),
+            Ok(None) => {
+                let message = format!("Ingest {} doesn't exist.", ingest_id);
+                Err((StatusCode::NOT_FOUND, message))
+            }
+            Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        };
+    }
+
+    match ingest_upload(&record).await {
+        Ok(ingest_record) => {
+            let ingest_id = ingest_record.ingest_id.clone();
+            run_blocking_step(move || {
+                update_upload(&upload_id, |record| {
+                    record.status = UploadStatus::FINALIZED;
+                    record.ingest_id = Some(ingest_id);
+                    Ok(())
+                })
+            })
+            .await?;
+            Ok(Json(ingest_record))
+        }
+        Err(e) => {
+            let error = e.1.clone();
+            run_blocking_step(move || abort_finalization(&upload_id, &error)).await?;
+            Err(e)
+        }
+    }
+}
+
+pub async fn delete_upload(
+    UrlPath(upload_id): UrlPath<String>,
+) -> Result<Json<UploadData>, (StatusCode, String)> {
+    let record = run_blocking_step(move || remove_upload(&upload_id)).await?;
+    Ok(Json(get_upload_data(record)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(received_chunks: &[usize]) -> UploadRecord {
+        let chunk_size = 10;
+        UploadRecord {
+            upload_id: Uuid::new_v4().to_string(),
+            file_name: String::from("archive.tar.gz"),
+            size_bytes: 3 * chunk_size - 5,
+            chunk_size,
+            num_chunks: 3,
+            checksum: None,
+            status: UploadStatus::UPLOADING,
+            created_at: now_rfc3339(),
+            updated_at: now_rfc3339(),
+            received_chunks: received_chunks.iter().copied().collect(),
+            ingest_id: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn lists_received_ranges_and_missing_chunks() {
+        let data = get_upload_data(upload(&[0, 2]));
+        assert_eq!(data.received_ranges, vec![[0, 10], [20, 25]]);
+        assert_eq!(data.missing_chunks, vec![1]);
+
+        let data = get_upload_data(upload(&[0, 1, 2]));
+        assert_eq!(data.received_ranges, vec![[0, 25]]);
+        assert!(data.missing_chunks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_to_ingest_with_missing_chunks() {
+        let record = upload(&[0, 2]);
+
+        let (status, message) = ingest_upload(&record).await.unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(message.contains("missing 1 chunks: [1]"), "{message}");
+    }
+
+    #[test]
+    fn waits_for_the_chunks_being_written() {
+        let mut record = upload(&[0, 1, 2]);
+
+        let writer = register_chunk_writer(&record.upload_id);
+        let other_writer = register_chunk_writer(&record.upload_id);
+        let (status, _) = start_finalization(&mut record).unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(record.status, UploadStatus::UPLOADING);
+
+        drop(writer);
+        assert!(start_finalization(&mut record).is_err());
+
+        // Once every chunk is written, even when the client went away halfway through one
+        drop(other_writer);
+        assert_eq!(count_chunk_writers(&record.upload_id), 0);
+        start_finalization(&mut record).unwrap();
+        assert_eq!(record.status, UploadStatus::FINALIZING);
+
+        // Nor can it be finalized again while that is going on
+        let (status, _) = start_finalization(&mut record).unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn resumes_interrupted_finalizations() {
+        let mut record = upload(&[0, 1, 2]);
+        record.status = UploadStatus::FINALIZING;
+
+        reset_finalization(&mut record, true, "Interrupted");
+        assert_eq!(record.status, UploadStatus::UPLOADING);
+        assert_eq!(record.error, None);
+        // The chunks received so far don't have to be sent again
+        assert_eq!(record.received_chunks.len(), 3);
+        start_finalization(&mut record).unwrap();
+    }
+
+    #[test]
+    fn fails_interrupted_finalizations_without_the_archive() {
+        let mut record = upload(&[0, 1, 2]);
+        record.status = UploadStatus::FINALIZING;
+
+        reset_finalization(&mut record, false, "Interrupted");
+        assert_eq!(record.status, UploadStatus::FAILED);
+        assert_eq!(record.error.as_deref(), Some("Interrupted"));
+        let (_, message) = start_finalization(&mut record).unwrap_err();
+        assert!(message.contains("has failed. Interrupted"), "{message}");
+    }
+}
@@ -0,0 +1,447 @@
+// Validation of the layout of an archive, before it becomes a version:
+//
+//   <anything>/programm/        -> the entry point must exist
+//     01_background/            -> its directories must be numbered overlays ('NN_')
+//       01_common_background/   -> variants, one is picked
+//         Background_C_01.png   -> leaves: only PNGs, all with the same dimensions
+//
+// Errors make the archive unusable, warnings point at things that will be ignored
+// when generating images.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use axum::{extract::Query, http::StatusCode, response::Json};
+use regex::Regex;
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::{ENTRY_POINT_DIR_NAME, ZFILL_PADDING};
+use crate::core::recipe::{is_overlay, read_dir_sorted};
+use crate::core::{find_entry_point_dir, get_archive_path, get_requested_version};
+
+// Top level overlays of the entry point, EG: '01_background'
+const NUMBERED_OVERLAY_PATTERN: &str = r"^\d{2}_";
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationCode {
+    MissingEntryPoint,
+    MissingOverlays,
+    UnnumberedOverlay,
+    EmptyLeaf,
+    NotPng,
+    UnreadableImage,
+    InconsistentDimensions,
+    IgnoredDirectory,
+    IgnoredFile,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidationIssue {
+    pub code: ValidationCode,
+    // Relative to the root of the archive
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidationReport {
+    pub valid: bool,
+    pub entry_point: Option<String>,
+    // How many images were found in the leaves
+    pub images: usize,
+    // The dimensions shared by (most of) the images
+    pub dimensions: Option<Dimensions>,
+    pub errors: Vec<ValidationIssue>,
+    pub warnings: Vec<ValidationIssue>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ValidationQuery {
+    pub version: Option<i32>,
+}
+
+struct Validation<'a> {
+    root: &'a Path,
+    errors: Vec<ValidationIssue>,
+    warnings: Vec<ValidationIssue>,
+    // Image path -> dimensions
+    images: Vec<(String, Dimensions)>,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+impl<'a> Validation<'a> {
+    fn relative(&self, path: &Path) -> String {
+        match path.strip_prefix(self.root) {
+            Ok(p) => p.to_string_lossy().into_owned(),
+            Err(_) => path.to_string_lossy().into_owned(),
+        }
+    }
+
+    fn error(&mut self, code: ValidationCode, path: &Path, message: String) {
+        let path = self.relative(path);
+        self.errors.push(ValidationIssue {
+            code,
+            path,
+            message,
+        });
+    }
+
+    fn warning(&mut self, code: ValidationCode, path: &Path, message: String) {
+        let path = self.relative(path);
+        self.warnings.push(ValidationIssue {
+            code,
+            path,
+            message,
+        });
+    }
+
+    fn check_leaf(&mut self, dir: &Path, files: &[String]) {
+        if files.is_empty() {
+            let message = String::from("The directory is empty, there's nothing to pick from it");
+            self.error(ValidationCode::EmptyLeaf, dir, message);
+            return;
+        }
+
+        for file in files {
+            let file_path = dir.join(file);
+            if !file.to_lowercase().ends_with(".png") {
+                let message = format!("{} is not a PNG, only PNGs can be overlaid", file);
+                self.error(ValidationCode::NotPng, &file_path, message);
+                continue;
+            }
+
+            // Only the header is read
+            match image::image_dimensions(&file_path) {
+                Ok((width, height)) => {
+                    let path = self.relative(&file_path);
+                    self.images.push((path, Dimensions { width, height }));
+                }
+                Err(e) => {
+                    let message = format!("Failed to read {}. Error: {}", file, e);
+                    self.error(ValidationCode::UnreadableImage, &file_path, message);
+                }
+            }
+        }
+    }
+
+    // Same walk as the generation of recipes (see recipe.rs)
+    fn check_dir(&mut self, dir: &Path) -> anyhow::Result<()> {
+        let (dirs, files) = read_dir_sorted(dir)?;
+
+        if dirs.is_empty() {
+            self.check_leaf(dir, &files);
+            return Ok(());
+        }
+
+        for file in &files {
+            let message = format!(
+                "{} is next to directories, so it will never be picked",
+                file
+            );
+            self.warning(ValidationCode::IgnoredFile, &dir.join(file), message);
+        }
+
+        let has_overlays = dirs.iter().any(|d| is_overlay(d));
+        for sub_dir in &dirs {
+            if has_overlays && !is_overlay(sub_dir) {
+                let message = format!(
+                    "{} is next to overlays but isn't numbered, so it will never be used",
+                    sub_dir
+                );
+                self.warning(
+                    ValidationCode::IgnoredDirectory,
+                    &dir.join(sub_dir),
+                    message,
+                );
+                continue;
+            }
+            self.check_dir(&dir.join(sub_dir))?;
+        }
+
+        Ok(())
+    }
+
+    // The images that don't have the same dimensions as most of the others
+    fn check_dimensions(&mut self) -> Option<Dimensions> {
+        let mut counts: BTreeMap<(u32, u32), usize> = BTreeMap::new();
+        for (_, d) in &self.images {
+            *counts.entry((d.width, d.height)).or_insert(0) += 1;
+        }
+        let ((width, height), _) = counts.iter().max_by_key(|(_, count)| **count)?;
+        let expected = Dimensions {
+            width: *width,
+            height: *height,
+        };
+
+        let mismatches: Vec<(String, Dimensions)> = self
+            .images
+            .iter()
+            .filter(|(_, d)| *d != expected)
+            .cloned()
+            .collect();
+        for (path, d) in mismatches {
+            let message = format!(
+                "The image is {}x{}, but the others are {}x{}",
+                d.width, d.height, expected.width, expected.height
+            );
+            self.error(
+                ValidationCode::InconsistentDimensions,
+                &self.root.join(&path),
+                message,
+            );
+        }
+
+        Some(expected)
+    }
+}
+
+// Check the layout of an extracted archive
+pub fn validate_archive(root: &Path) -> anyhow::Result<ValidationReport> {
+    let mut validation = Validation {
+        root,
+        errors: Vec::new(),
+        warnings: Vec::new(),
+        images: Vec::new(),
+    };
+
+    let entry_point = match find_entry_point_dir(&root.to_path_buf()) {
+        Some(r) => r,
+        None => {
+            let message = format!(
+                "There's no '{}' directory, the images must be inside of it",
+                ENTRY_POINT_DIR_NAME
+            );
+            validation.error(ValidationCode::MissingEntryPoint, root, message);
+            return Ok(ValidationReport {
+                valid: false,
+                entry_point: None,
+                images: 0,
+                dimensions: None,
+                errors: validation.errors,
+                warnings: validation.warnings,
+            });
+        }
+    };
+    // find_entry_point_dir canonicalizes the path
+    let root_path = root.canonicalize().unwrap_or_else(|_| PathBuf::from(root));
+    validation.root = &root_path;
+
+    let numbered_overlay_regex = Regex::new(NUMBERED_OVERLAY_PATTERN).unwrap();
+    let (dirs, files) = read_dir_sorted(&entry_point)?;
+    if dirs.is_empty() {
+        let message = String::from("The entry point has no overlays (EG: '01_background')");
+        validation.error(ValidationCode::MissingOverlays, &entry_point, message);
+    }
+    for file in &files {
+        let message = format!(
+            "{} is not inside an overlay, so it will never be used",
+            file
+        );
+        validation.warning(
+            ValidationCode::IgnoredFile,
+            &entry_point.join(file),
+            message,
+        );
+    }
+    for dir in &dirs {
+        if !numbered_overlay_regex.is_match(dir) {
+            let message = format!(
+                "{} must start with its number in the stack of overlays (EG: '01_background')",
+                dir
+            );
+            validation.error(
+                ValidationCode::UnnumberedOverlay,
+                &entry_point.join(dir),
+                message,
+            );
+            continue;
+        }
+        validation.check_dir(&entry_point.join(dir))?;
+    }
+
+    let dimensions = validation.check_dimensions();
+    let entry_point = validation.relative(&entry_point);
+
+    Ok(ValidationReport {
+        valid: validation.errors.is_empty(),
+        entry_point: Some(entry_point),
+        images: validation.images.len(),
+        dimensions,
+        errors: validation.errors,
+        warnings: validation.warnings,
+    })
+}
+
+// One line per issue, for the logs
+pub fn format_issues(issues: &[ValidationIssue]) -> String {
+    issues
+        .iter()
+        .map(|i| format!("[{:?}] {}: {}", i.code, i.path, i.message))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+// Validate an archive that was already uploaded
+pub async fn get_validation(
+    query: Query<ValidationQuery>,
+) -> Result<Json<ValidationReport>, (StatusCode, String)> {
+    let version = get_requested_version(query.version).await?;
+
+    let archive_path = get_archive_path(version);
+    if !archive_path.is_dir() {
+        let message = format!("Archive version {:0ZFILL_PADDING$} doesn't exist", version);
+        return Err((StatusCode::NOT_FOUND, message));
+    }
+
+    let report = tokio::task::spawn_blocking(move || validate_archive(&archive_path))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::core::test_utils::TestDir;
+
+    // A transparent PNG, only its dimensions matter
+    fn add_image(dir: &TestDir, relative: &str, size: u32) {
+        let path = dir.add_file(relative);
+        image::RgbaImage::new(size, size).save(path).unwrap();
+    }
+
+    fn codes(issues: &[ValidationIssue]) -> Vec<(ValidationCode, &str)> {
+        issues.iter().map(|i| (i.code, i.path.as_str())).collect()
+    }
+
+    #[test]
+    fn accepts_a_valid_archive() {
+        let dir = TestDir::new("validation");
+        add_image(
+            &dir,
+            "001/programm/01_background/01_common/Background_C_01.png",
+            4,
+        );
+        add_image(
+            &dir,
+            "001/programm/01_background/02_rare/Background_R_01.png",
+            4,
+        );
+        add_image(
+            &dir,
+            "001/programm/02_body_skins/Body_Skin_Standard_pink.png",
+            4,
+        );
+
+        let report = validate_archive(&dir.path).unwrap();
+        assert!(report.valid, "{}", format_issues(&report.errors));
+        assert_eq!(report.entry_point.as_deref(), Some("001/programm"));
+        assert_eq!(report.images, 3);
+        assert_eq!(
+            report.dimensions,
+            Some(Dimensions {
+                width: 4,
+                height: 4
+            })
+        );
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn reports_a_bad_layout() {
+        let dir = TestDir::new("validation");
+        add_image(
+            &dir,
+            "001/programm/01_background/01_common/Background_C_01.png",
+            4,
+        );
+        add_image(
+            &dir,
+            "001/programm/01_background/01_common/Background_C_02.png",
+            4,
+        );
+        add_image(
+            &dir,
+            "001/programm/01_background/02_rare/Background_R_01.png",
+            8,
+        );
+        dir.add_file("001/programm/01_background/02_rare/Background_R_02.jpg");
+        dir.add_file("001/programm/01_background/notes.txt");
+        add_image(
+            &dir,
+            "001/programm/01_background/old/Background_O_01.png",
+            4,
+        );
+        fs::create_dir_all(dir.path.join("001/programm/02_body_skins")).unwrap();
+        add_image(&dir, "001/programm/eyes/Eyes_blue.png", 4);
+
+        let report = validate_archive(&dir.path).unwrap();
+        assert!(!report.valid);
+        assert_eq!(report.images, 3);
+        assert_eq!(
+            codes(&report.errors),
+            vec![
+                (
+                    ValidationCode::NotPng,
+                    "001/programm/01_background/02_rare/Background_R_02.jpg"
+                ),
+                (ValidationCode::EmptyLeaf, "001/programm/02_body_skins"),
+                (ValidationCode::UnnumberedOverlay, "001/programm/eyes"),
+                (
+                    ValidationCode::InconsistentDimensions,
+                    "001/programm/01_background/02_rare/Background_R_01.png"
+                ),
+            ]
+        );
+        assert_eq!(
+            codes(&report.warnings),
+            vec![
+                (
+                    ValidationCode::IgnoredFile,
+                    "001/programm/01_background/notes.txt"
+                ),
+                (
+                    ValidationCode::IgnoredDirectory,
+                    "001/programm/01_background/old"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_a_missing_entry_point() {
+        let dir = TestDir::new("validation");
+        add_image(&dir, "001/images/01_background/Background_C_01.png", 4);
+
+        let report = validate_archive(&dir.path).unwrap();
+        assert!(!report.valid);
+        assert_eq!(report.entry_point, None);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].code, ValidationCode::MissingEntryPoint);
+    }
+}
@@ -0,0 +1,531 @@
+// The versions of the archive that were published, one directory each under ARCHIVES_ROOT_DIR.
+// Every endpoint that reads an archive takes an optional `version` (the current one by default),
+// so that older archives stay usable.
+//
+// An archive is extracted and validated in ARCHIVES_STAGING_DIR, and only then moved in place
+// and made the current version: the current version is never a broken or half extracted one.
+// The current version can be switched back to any earlier one (EG: if the new images are wrong),
+// nothing is deleted so it can also be switched forward again.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use axum::{extract::Path as UrlPath, http::StatusCode, response::Json};
+use chrono::{DateTime, Utc};
+
+// JSON
+use serde::{Deserialize, Serialize};
+
+use crate::core::constants::{ARCHIVES_ROOT_DIR, ZFILL_PADDING};
+use crate::core::repository::{repository, run_blocking};
+use crate::core::validation::{self, validate_archive};
+use crate::core::{
+    bytes_to_human_readable, get_archive_path, get_staging_path, ingest, load_versions_data,
+    VersionsData,
+};
+
+// The versions are read, changed and stored back by the ingests and by the rollbacks
+static VERSIONS_LOCK: Mutex<()> = Mutex::new(());
+// The versions given to the uploads that are still being saved or ingested.
+// NB: an upload interrupted by a restart fails, so they don't need to be kept on disk.
+static RESERVED_VERSIONS: Mutex<BTreeSet<i32>> = Mutex::new(BTreeSet::new());
+
+// -----------------------------------------------------------------------------
+// Data structures
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionInfo {
+    pub version: i32,
+    // Zero-padded (EG: '002'), like the directory
+    pub name: String,
+    // The name of the uploaded archive, unknown for the ones uploaded before ingests existed
+    pub file_name: Option<String>,
+    pub uploaded_at: Option<String>,
+    // Of the extracted files
+    pub size_bytes: u64,
+    pub file_count: usize,
+    pub is_current: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionsListData {
+    // The one used when no version is asked for
+    pub current_version: i32,
+    // The highest one, the same as the current one unless there was a rollback
+    pub last_version: i32,
+    pub versions: Vec<VersionInfo>,
+    pub history: Vec<VersionSwitch>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SwitchReason {
+    Publish,
+    // Back to an older version
+    Rollback,
+    // Forward again, to a newer version that had been rolled back
+    RollForward,
+}
+
+// Every change of the current version, kept with the versions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionSwitch {
+    pub version: i32,
+    pub previous_version: Option<i32>,
+    pub reason: SwitchReason,
+    pub switched_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RollbackRequest {
+    pub version: i32,
+}
+
+// -----------------------------------------------------------------------------
+// Functions
+// -----------------------------------------------------------------------------
+
+impl VersionInfo {
+    // For the templates
+    pub fn size_human_readable(&self) -> String {
+        bytes_to_human_readable(self.size_bytes as f64)
+    }
+}
+
+// Size and number of the files of a directory, links are not followed
+fn measure_directory(path: &Path) -> (u64, usize) {
+    let entries = match fs::read_dir(path) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Failed to read directory {}. Error: {}", path.display(), e);
+            return (0, 0);
+        }
+    };
+
+    let mut size_bytes = 0;
+    let mut file_count = 0;
+    for entry in entries.flatten() {
+        let metadata = match fs::symlink_metadata(entry.path()) {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            let (dir_size, dir_count) = measure_directory(&entry.path());
+            size_bytes += dir_size;
+            file_count += dir_count;
+        } else if metadata.is_file() {
+            size_bytes += metadata.len();
+            file_count += 1;
+        }
+    }
+    (size_bytes, file_count)
+}
+
+fn get_modified_time(path: &Path) -> Option<String> {
+    let modified: SystemTime = fs::metadata(path).ok()?.modified().ok()?;
+    let modified: DateTime<Utc> = modified.into();
+    Some(modified.to_rfc3339())
+}
+
+fn get_version_info(
+    version: i32,
+    current_version: i32,
+    ingest: Option<&ingest::IngestRecord>,
+) -> VersionInfo {
+    let archive_path = get_archive_path(version);
+    let (size_bytes, file_count) = measure_directory(&archive_path);
+    let uploaded_at = match ingest.and_then(|i| i.finished_at.clone()) {
+        Some(r) => Some(r),
+        None => get_modified_time(&archive_path),
+    };
+
+    VersionInfo {
+        version,
+        name: format!("{:0ZFILL_PADDING$}", version),
+        file_name: ingest.map(|i| i.file_name.clone()),
+        uploaded_at,
+        size_bytes,
+        file_count,
+        is_current: version == current_version,
+    }
+}
+
+// Published versions only, the ones being ingested are still in the staging directory
+fn get_published_versions(last_version: i32) -> anyhow::Result<Vec<i32>> {
+    let archives_root_dir = Path::new(ARCHIVES_ROOT_DIR);
+    if !archives_root_dir.exists() {
+        return Ok(vec![]);
+    }
+    let entries = match fs::read_dir(archives_root_dir) {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!(
+                "Failed to read directory {}. Error: {}",
+                archives_root_dir.display(),
+                e
+            );
+            anyhow::bail!(message);
+        }
+    };
+
+    let mut versions: Vec<i32> = entries
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|version| *version >= 1 && *version <= last_version)
+        .collect();
+    versions.sort_unstable();
+    Ok(versions)
+}
+
+// Blocking: every version is walked to measure it
+pub fn list_versions() -> anyhow::Result<VersionsListData> {
+    let data = load_versions_data()?;
+    let current_version = data.current_version();
+    let ingests = ingest::load_published_ingests()?;
+    let versions = get_published_versions(data.last_version)?
+        .into_iter()
+        .map(|version| get_version_info(version, current_version, ingests.get(&version)))
+        .collect();
+    Ok(VersionsListData {
+        current_version,
+        last_version: data.last_version,
+        versions,
+        history: data.history,
+    })
+}
+
+// Whether the version can be read from (EG: by the inventory)
+pub fn is_published(version: i32, last_version: i32) -> bool {
+    version >= 1 && version <= last_version && get_archive_path(version).is_dir()
+}
+
+// The version the next upload becomes, never given to two uploads at the same time.
+// It must be released once the upload is published or has failed.
+pub fn reserve_version() -> anyhow::Result<i32> {
+    let _lock = VERSIONS_LOCK.lock().unwrap();
+    let data = load_versions_data()?;
+    let mut reserved_versions = RESERVED_VERSIONS.lock().unwrap();
+    let highest_version = match reserved_versions.iter().next_back() {
+        Some(reserved) => data.last_version.max(*reserved),
+        None => data.last_version,
+    };
+    let version = highest_version + 1;
+    reserved_versions.insert(version);
+    Ok(version)
+}
+
+pub fn release_version(version: i32) {
+    RESERVED_VERSIONS.lock().unwrap().remove(&version);
+}
+
+fn new_version_switch(version: i32, data: &VersionsData, reason: SwitchReason) -> VersionSwitch {
+    let now: DateTime<Utc> = SystemTime::now().into();
+    VersionSwitch {
+        version,
+        previous_version: Some(data.current_version()),
+        reason,
+        switched_at: now.to_rfc3339(),
+    }
+}
+
+// Make an already published `version` the current one, None if it already is
+fn switch_to_version(data: &mut VersionsData, version: i32) -> Option<VersionSwitch> {
+    let current_version = data.current_version();
+    if version == current_version {
+        return None;
+    }
+    let reason = if version < current_version {
+        SwitchReason::Rollback
+    } else {
+        SwitchReason::RollForward
+    };
+    let switch = new_version_switch(version, data, reason);
+    data.current_version = Some(version);
+    data.last_modified = switch.switched_at.clone();
+    Some(switch)
+}
+
+// Make a newly published `version` the current one, None if a newer one was published already
+fn switch_to_published_version(data: &mut VersionsData, version: i32) -> Option<VersionSwitch> {
+    if version < data.last_version {
+        return None;
+    }
+    let switch = new_version_switch(version, data, SwitchReason::Publish);
+    data.last_version = version;
+    data.current_version = Some(version);
+    data.last_modified = switch.switched_at.clone();
+    Some(switch)
+}
+
+// Read, change and store back the versions, together with the switch of version (if any)
+fn update_versions_data(
+    update: impl FnOnce(&mut VersionsData) -> Option<VersionSwitch>,
+) -> anyhow::Result<Option<VersionSwitch>> {
+    let _lock = VERSIONS_LOCK.lock().unwrap();
+    let mut data = load_versions_data()?;
+    let switch = update(&mut data);
+    repository()?.save_versions(&data, switch.as_ref())?;
+    Ok(switch)
+}
+
+// Make an already published `version` the current one.
+// None if it already is (checked under the lock, the current version might have just changed).
+fn switch_current_version(version: i32) -> anyhow::Result<Option<VersionSwitch>> {
+    let switch = update_versions_data(|data| switch_to_version(data, version))?;
+    if let Some(switch) = &switch {
+        eprintln!(
+            "Current version is now {} (was {:?})",
+            version, switch.previous_version
+        );
+    }
+    Ok(switch)
+}
+
+// A newly published version becomes the current one, unless a newer one was published
+// while it was being ingested (EG: two uploads at the same time)
+fn record_published_version(version: i32) -> anyhow::Result<()> {
+    let switch = update_versions_data(|data| switch_to_published_version(data, version))?;
+    match switch {
+        Some(switch) => eprintln!(
+            "Current version is now {} (was {:?})",
+            version, switch.previous_version
+        ),
+        None => eprintln!(
+            "Version {} was published, but a newer one is already the current one",
+            version
+        ),
+    }
+    Ok(())
+}
+
+// The published version right before `version`, versions that failed to be ingested leave gaps
+pub fn get_previous_version(version: i32) -> anyhow::Result<Option<i32>> {
+    let data = load_versions_data()?;
+    let previous = get_published_versions(data.last_version)?
+        .into_iter()
+        .filter(|v| *v < version)
+        .max();
+    Ok(previous)
+}
+
+// Move a validated archive out of the staging directory and make it the current version.
+// Blocking, the archive is moved with a single rename.
+pub fn publish_staged_version(version: &str) -> anyhow::Result<()> {
+    let version_number = match version.parse::<i32>() {
+        Ok(r) => r,
+        Err(e) => {
+            let message = format!("Invalid archive version {}. Error: {}", version, e);
+            anyhow::bail!(message);
+        }
+    };
+    let staging_path = get_staging_path(version);
+    let archive_path = get_archive_path(version_number);
+    // Never replace a version, the recipes generated with it must stay reproducible
+    if archive_path.exists() {
+        let message = format!(
+            "Archive version {} already exists in {}",
+            version,
+            archive_path.display()
+        );
+        anyhow::bail!(message);
+    }
+
+    match fs::rename(&staging_path, &archive_path) {
+        Ok(_) => {}
+        Err(e) => {
+            let message = format!(
+                "Failed to move {} to {}. Error: {}",
+                staging_path.display(),
+                archive_path.display(),
+                e
+            );
+            anyhow::bail!(message);
+        }
+    }
+
+    match record_published_version(version_number) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            // Back to staging, so that it's removed like any other failed ingest
+            match fs::rename(&archive_path, &staging_path) {
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!(
+                        "Failed to move {} back to {}. Error: {}",
+                        archive_path.display(),
+                        staging_path.display(),
+                        e
+                    );
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// API Routes
+// -----------------------------------------------------------------------------
+
+pub async fn get_versions() -> Result<Json<VersionsListData>, (StatusCode, String)> {
+    let versions = tokio::task::spawn_blocking(list_versions)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(versions))
+}
+
+pub async fn get_version(
+    UrlPath(version): UrlPath<i32>,
+) -> Result<Json<VersionInfo>, (StatusCode, String)> {
+    let data = run_blocking(load_versions_data)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let current_version = data.current_version();
+    if !is_published(version, data.last_version) {
+        let message = format!("Archive version {:0ZFILL_PADDING$} doesn't exist", version);
+        return Err((StatusCode::NOT_FOUND, message));
+    }
+
+    let info = tokio::task::spawn_blocking(move || -> anyhow::Result<VersionInfo> {
+        let ingests = ingest::load_published_ingests()?;
+        Ok(get_version_info(
+            version,
+            current_version,
+            ingests.get(&version),
+        ))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(info))
+}
+
+// Admin: make another published version the current one (EG: to undo a bad upload,
+// or to go forward again afterwards). The version is validated again first, nothing is deleted.
+pub async fn rollback_version(
+    Json(request): Json<RollbackRequest>,
+) -> Result<Json<VersionSwitch>, (StatusCode, String)> {
+    let version = request.version;
+    let data = run_blocking(load_versions_data)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !is_published(version, data.last_version) {
+        let message = format!("Archive version {:0ZFILL_PADDING$} doesn't exist", version);
+        return Err((StatusCode::NOT_FOUND, message));
+    }
+    let already_current = format!(
+        "Archive version {:0ZFILL_PADDING$} is already the current one",
+        version
+    );
+    if version == data.current_version() {
+        return Err((StatusCode::CONFLICT, already_current));
+    }
+
+    let archive_path = get_archive_path(version);
+    let report = tokio::task::spawn_blocking(move || validate_archive(&archive_path))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !report.valid {
+        let message = format!(
+            "Archive version {:0ZFILL_PADDING$} is not valid:\n{}",
+            version,
+            validation::format_issues(&report.errors)
+        );
+        return Err((StatusCode::CONFLICT, message));
+    }
+
+    let switch = tokio::task::spawn_blocking(move || switch_current_version(version))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    match switch {
+        Some(switch) => Ok(Json(switch)),
+        // Somebody else got there first
+        None => Err((StatusCode::CONFLICT, already_current)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::core::test_utils::TestDir;
+
+    // Written before rollbacks existed: no current version nor history
+    fn legacy_versions_data(last_version: i32) -> VersionsData {
+        let json = format!(
+            r#"{{"last_version": {}, "last_modified": "2022-11-02T10:00:00+00:00"}}"#,
+            last_version
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn defaults_to_the_last_version() {
+        let data = legacy_versions_data(3);
+        assert_eq!(data.current_version(), 3);
+        assert!(data.history.is_empty());
+    }
+
+    #[test]
+    fn switches_back_and_forward() {
+        let mut data = legacy_versions_data(3);
+
+        let switch = switch_to_version(&mut data, 1).unwrap();
+        assert_eq!(switch.reason, SwitchReason::Rollback);
+        assert_eq!(switch.previous_version, Some(3));
+        assert_eq!(data.current_version(), 1);
+        assert_eq!(data.last_modified, switch.switched_at);
+        // Still the next upload's base
+        assert_eq!(data.last_version, 3);
+
+        assert!(switch_to_version(&mut data, 1).is_none());
+
+        let switch = switch_to_version(&mut data, 2).unwrap();
+        assert_eq!(switch.reason, SwitchReason::RollForward);
+        assert_eq!(switch.previous_version, Some(1));
+        assert_eq!(data.current_version(), 2);
+    }
+
+    #[test]
+    fn publishes_only_the_newest_version() {
+        let mut data = legacy_versions_data(3);
+        switch_to_version(&mut data, 1).unwrap();
+
+        let switch = switch_to_published_version(&mut data, 4).unwrap();
+        assert_eq!(switch.reason, SwitchReason::Publish);
+        assert_eq!(switch.previous_version, Some(1));
+        assert_eq!(data.current_version(), 4);
+        assert_eq!(data.last_version, 4);
+
+        // Ingested together with 4, but finished after it
+        assert!(switch_to_published_version(&mut data, 3).is_none());
+        assert_eq!(data.current_version(), 4);
+        assert_eq!(data.last_version, 4);
+    }
+
+    #[test]
+    fn measures_a_directory() {
+        let dir = TestDir::new("versions");
+        fs::create_dir_all(dir.path.join("programm/01_background")).unwrap();
+        fs::write(
+            dir.path.join("programm/01_background/Background_C_01.png"),
+            b"1234",
+        )
+        .unwrap();
+        fs::write(dir.path.join("programm/Readme.txt"), b"12").unwrap();
+
+        assert_eq!(measure_directory(&dir.path), (6, 2));
+        assert_eq!(measure_directory(&dir.path.join("missing")), (0, 0));
+    }
+}
@@ -1,12 +1,10 @@
 // Templates and web server
 use askama::Template;
 use axum::{
-    body::Body,
     extract,
-    extract::{DefaultBodyLimit, Multipart},
     http::StatusCode,
     response::{Html, IntoResponse, Json, Response},
-    routing::{get, post},
+    routing::{get, post, put},
     Router,
 };
 
@@ -14,10 +12,10 @@ use axum::{
 use serde_json::{json, Value};
 // Filesystem operations
 use tokio::runtime::Handle;
-use tokio::{self, io::AsyncReadExt}; // trait needed for write_all()
 
 use crate::core::constants::{APP_VERSION, PORT_NUM};
-use crate::core::{ArchiveInfo, Page};
+use crate::core::versions::VersionInfo;
+use crate::core::{InventoryQuery, Page};
 
 mod core;
 
@@ -34,6 +32,43 @@ async fn main() {
         address, num_workers
     );
 
+    // Create the database (or bring it up to date) before anybody needs it
+    match core::repository::repository() {
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to open the database. {}", e);
+        }
+    }
+
+    // Jobs that were running before a restart will never finish
+    match core::jobs::fail_interrupted_jobs() {
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to check for interrupted jobs. {}", e);
+        }
+    }
+    match core::ingest::fail_interrupted_ingests() {
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to check for interrupted ingests. {}", e);
+        }
+    }
+
+    match core::uploads::recover_interrupted_uploads() {
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to check for interrupted uploads. {}", e);
+        }
+    }
+
+    // Batches that were running before a restart are picked up again
+    match core::batches::resume_interrupted_batches() {
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to resume interrupted batches. {}", e);
+        }
+    }
+
     // Create the routes
     let app = Router::new()
         .route("/", get(upload))
@@ -44,10 +79,90 @@ async fn main() {
         .route("/api/json", get(hello_json))
         .route("/api/status", get(core::status))
         .route("/api/upload-archive", post(core::upload_archive))
+        .route("/api/ingests/:ingest_id", get(core::ingest::get_ingest))
+        .route("/api/uploads", post(core::uploads::create_upload))
+        .route(
+            "/api/uploads/:upload_id",
+            get(core::uploads::get_upload).delete(core::uploads::delete_upload),
+        )
+        .route(
+            "/api/uploads/:upload_id/chunks/:index",
+            put(core::uploads::put_upload_chunk),
+        )
+        .route(
+            "/api/uploads/:upload_id/finalize",
+            post(core::uploads::finalize_upload),
+        )
+        .route("/api/sanitize/preview", post(core::sanitize::preview))
+        .route(
+            "/api/archives/validation",
+            get(core::validation::get_validation),
+        )
+        .route("/api/versions", get(core::versions::get_versions))
+        .route("/api/versions/:version", get(core::versions::get_version))
+        .route(
+            "/api/admin/versions/rollback",
+            post(core::versions::rollback_version),
+        )
+        .route(
+            "/api/versions/:version/diff",
+            get(core::diff::get_version_diff),
+        )
         .route("/api/inventory", get(core::list_inventory))
         .route("/api/image", get(core::image_preview))
         .route("/api/jobs", get(core::get_job))
-        .route("/api/random", post(core::queue_generation_of_random_image));
+        .route("/api/random", post(core::queue_generation_of_random_image))
+        .route("/api/generate", post(core::generate_image_from_recipe))
+        .route(
+            "/api/combinatorics",
+            get(core::combinatorics::get_combinations),
+        )
+        .route(
+            "/api/rarity",
+            get(core::rarity::get_rarity).put(core::rarity::put_rarity),
+        )
+        .route(
+            "/api/rules",
+            get(core::rules::get_rules).put(core::rules::put_rules),
+        )
+        .route("/api/rules/check", post(core::rules::check_recipe))
+        .route(
+            "/api/reports/distribution",
+            get(core::reports::get_distribution_report),
+        )
+        .route(
+            "/api/recipes/uniqueness",
+            get(core::uniqueness::get_uniqueness),
+        )
+        .route(
+            "/api/batches",
+            get(core::batches::get_batches).post(core::batches::create_batch),
+        )
+        .route("/api/batches/:batch_id", get(core::batches::get_batch))
+        .route(
+            "/api/batches/:batch_id/export",
+            get(core::export::export_batch),
+        )
+        .route(
+            "/api/batches/:batch_id/metadata",
+            put(core::batches::set_batch_metadata_base_uri),
+        )
+        .route(
+            "/api/batches/:batch_id/metadata/:index",
+            get(core::batches::get_batch_item_metadata),
+        )
+        .route(
+            "/api/batches/:batch_id/pause",
+            post(core::batches::pause_batch),
+        )
+        .route(
+            "/api/batches/:batch_id/resume",
+            post(core::batches::resume_batch),
+        )
+        .route(
+            "/api/batches/:batch_id/cancel",
+            post(core::batches::cancel_batch),
+        );
 
     // Run the app via hyper
     // axum::Server is a re-export of hyper::Server
@@ -86,29 +201,23 @@ async fn upload() -> impl IntoResponse {
     HtmlTemplate(template)
 }
 
-async fn inventory() -> impl IntoResponse {
-    // TODO: Read all of the images that we have
-    // TODO: Create a list of all the archives that were uploaded
-    // from there, the user should be able to navigate the uploaded images
-    // Then, for every uploaded image, he should be able to upload a new version
-
-    // FIXME: This is fake data, it should come from a DB
-    let archives = vec![
-        ArchiveInfo {
-            name: String::from("Sphinx"),
-            version: 1,
-        },
-        ArchiveInfo {
-            name: String::from("Sphinx"),
-            version: 2,
-        },
-    ];
+// The images of a version of the archive (the current one by default), and the list of versions
+async fn inventory(query: extract::Query<InventoryQuery>) -> impl IntoResponse {
+    let (current_version, archives) = match core::versions::get_versions().await {
+        Ok(r) => (r.current_version, r.0.versions),
+        Err((_, e)) => {
+            eprintln!("Failed to list the versions of the archive. {}", e);
+            (0, vec![])
+        }
+    };
+    let version = query.version.unwrap_or(current_version);
 
     let title = String::from("Inventory");
     let pages = core::get_pages_lists_for_current_page(&title);
     let template = InventoryTemplate {
         title,
         pages,
+        version,
         archives,
     };
     HtmlTemplate(template)
@@ -139,6 +248,7 @@ struct HelloTemplate {
 #[derive(Template)]
 #[template(path = "upload.html")]
 struct UploadTemplate {
+    #[allow(dead_code)]
     app_version: &'static str,
     title: String,
     pages: Vec<Page>,
@@ -149,7 +259,9 @@ struct UploadTemplate {
 struct InventoryTemplate {
     title: String,
     pages: Vec<Page>,
-    archives: Vec<ArchiveInfo>,
+    // The one being browsed
+    version: i32,
+    archives: Vec<VersionInfo>,
 }
 
 #[derive(Template)]